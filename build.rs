@@ -1,3 +1,33 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Passes the git revision harmonia is built from to the compiler as
+/// `HARMONIA_GIT_REV`, for `/version`. Builds outside of a git checkout, like
+/// the nix build, can set it in the environment instead.
+fn git_revision() {
+    println!("cargo:rerun-if-env-changed=HARMONIA_GIT_REV");
+    if std::env::var_os("HARMONIA_GIT_REV").is_some() {
+        return;
+    }
+    // rebuild when a commit is made or checked out
+    let head = Path::new(".git/HEAD");
+    if !head.exists() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(head) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Some(output) = output.ok().filter(|o| o.status.success()) {
+        let rev = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=HARMONIA_GIT_REV={}", rev.trim());
+    }
+}
+
 fn main() {
     pkg_config::probe_library("libsodium").unwrap();
+    git_revision();
 }
@@ -13,6 +13,8 @@
   boost ? pkgs.boost,
   openssl ? pkgs.openssl,
   enableClippy ? false,
+  # reported by /version, the source has no .git to read it from
+  gitRevision ? null,
 }:
 
 rustPlatform.buildRustPackage (
@@ -26,6 +28,8 @@ rustPlatform.buildRustPackage (
         ".cpp"
         ".h"
         ".md"
+        ".css"
+        ".drv"
       ]
     );
     cargoLock.lockFile = ./Cargo.lock;
@@ -40,6 +44,8 @@ rustPlatform.buildRustPackage (
     ];
     doCheck = false;
 
+    env = lib.optionalAttrs (gitRevision != null) { HARMONIA_GIT_REV = gitRevision; };
+
     meta = with lib; {
       description = "Nix binary cache implemented in rust using libnix-store";
       homepage = "https://github.com/nix-community/harmonia";
@@ -28,7 +28,9 @@
           ...
         }:
         {
-          packages.harmonia = pkgs.callPackage ./. { };
+          packages.harmonia = pkgs.callPackage ./. {
+            gitRevision = inputs.self.rev or inputs.self.dirtyRev or null;
+          };
           packages.default = config.packages.harmonia;
           checks =
             let
@@ -0,0 +1,192 @@
+use std::net::IpAddr;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::{http, HttpRequest};
+use ipnet::IpNet;
+
+/// Paths that are exempt from `allowed_networks` with `allowed_networks_exempt_health`.
+const HEALTH_PATHS: &[&str] = &["/health", "/livez", "/readyz"];
+
+/// Restricts access to clients from a set of networks.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NetworkFilter {
+    pub(crate) allowed: Vec<IpNet>,
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    pub(crate) exempt_health: bool,
+    /// `path_prefix`, which the health paths are below.
+    pub(crate) path_prefix: String,
+}
+
+fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+/// The scheme and host clients reach harmonia at, for absolute URLs.
+///
+/// `Forwarded`, `X-Forwarded-Proto` and `X-Forwarded-Host` are only taken from
+/// trusted proxies and unix sockets, so that other clients can't make the
+/// URLs point elsewhere.
+pub(crate) fn origin(req: &HttpRequest, trusted_proxies: &[IpNet]) -> String {
+    let trusted = req
+        .peer_addr()
+        .is_none_or(|peer| contains(trusted_proxies, peer.ip().to_canonical()));
+    if trusted {
+        let conn = req.connection_info();
+        return format!("{}://{}", conn.scheme(), conn.host());
+    }
+    let scheme = if req.app_config().secure() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = req
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+        .unwrap_or_else(|| req.app_config().host());
+    format!("{}://{}", scheme, host)
+}
+
+impl NetworkFilter {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Determines the address of the client, following `X-Forwarded-For`
+    /// through trusted proxies.
+    ///
+    /// Hops are taken from the right, as only the entries appended by trusted
+    /// proxies can be relied on; the client controls everything left of them.
+    fn client_ip(&self, peer: IpAddr, forwarded_for: &[&str]) -> Option<IpAddr> {
+        let mut client = peer.to_canonical();
+        let hops = forwarded_for.iter().flat_map(|h| h.split(',')).rev();
+        for hop in hops {
+            if !contains(&self.trusted_proxies, client) {
+                break;
+            }
+            client = hop.trim().parse::<IpAddr>().ok()?.to_canonical();
+        }
+        Some(client)
+    }
+
+    /// Whether the request may be served.
+    pub(crate) fn is_allowed(&self, req: &ServiceRequest) -> bool {
+        let is_health = req
+            .path()
+            .strip_prefix(self.path_prefix.as_str())
+            .is_some_and(|path| HEALTH_PATHS.contains(&path));
+        if !self.is_enabled() || self.exempt_health && is_health {
+            return true;
+        }
+        // unix sockets have no address, their access is controlled by the file mode
+        let Some(peer) = req.peer_addr() else {
+            return true;
+        };
+        let forwarded_for = req
+            .headers()
+            .get_all(http::header::X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        self.client_ip(peer.ip(), &forwarded_for)
+            .is_some_and(|ip| contains(&self.allowed, ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn filter() -> NetworkFilter {
+        NetworkFilter {
+            allowed: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            trusted_proxies: vec!["192.168.1.1/32".parse().unwrap()],
+            exempt_health: true,
+            path_prefix: String::new(),
+        }
+    }
+
+    fn allowed(path: &str, peer: &str, forwarded_for: Option<&str>) -> bool {
+        let mut req = TestRequest::with_uri(path).peer_addr(peer.parse().unwrap());
+        if let Some(forwarded_for) = forwarded_for {
+            req = req.insert_header((http::header::X_FORWARDED_FOR, forwarded_for));
+        }
+        filter().is_allowed(&req.to_srv_request())
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        assert!(allowed("/nix-cache-info", "10.1.2.3:1234", None));
+        assert!(allowed("/nix-cache-info", "[fd12::1]:1234", None));
+        assert!(allowed("/nix-cache-info", "[::ffff:10.1.2.3]:1234", None));
+        assert!(!allowed("/nix-cache-info", "8.8.8.8:1234", None));
+        assert!(allowed("/health", "8.8.8.8:1234", None));
+
+        // only trusted proxies may name the client
+        assert!(!allowed(
+            "/nix-cache-info",
+            "8.8.8.8:1234",
+            Some("10.1.2.3")
+        ));
+        assert!(allowed(
+            "/nix-cache-info",
+            "192.168.1.1:1234",
+            Some("10.1.2.3")
+        ));
+        assert!(!allowed(
+            "/nix-cache-info",
+            "192.168.1.1:1234",
+            Some("8.8.8.8")
+        ));
+        // the client can't spoof hops left of what the proxy appended
+        assert!(!allowed(
+            "/nix-cache-info",
+            "192.168.1.1:1234",
+            Some("10.1.2.3, 8.8.8.8")
+        ));
+        assert!(!allowed(
+            "/nix-cache-info",
+            "192.168.1.1:1234",
+            Some("garbage")
+        ));
+
+        assert!(NetworkFilter::default().is_allowed(&TestRequest::default().to_srv_request()));
+
+        let prefixed = NetworkFilter {
+            path_prefix: "/cache".into(),
+            ..filter()
+        };
+        let allowed = |path| {
+            prefixed.is_allowed(
+                &TestRequest::with_uri(path)
+                    .peer_addr("8.8.8.8:1234".parse().unwrap())
+                    .to_srv_request(),
+            )
+        };
+        assert!(allowed("/cache/health"));
+        assert!(!allowed("/health"));
+        assert!(!allowed("/cache/nix-cache-info"));
+    }
+
+    #[test]
+    fn test_origin() {
+        let trusted_proxies = ["192.168.1.1/32".parse().unwrap()];
+        let origin = |peer: Option<&str>| {
+            let mut req = TestRequest::default()
+                .insert_header((http::header::HOST, "harmonia:5000"))
+                .insert_header(("X-Forwarded-Proto", "https"))
+                .insert_header(("X-Forwarded-Host", "cache.example.com"));
+            if let Some(peer) = peer {
+                req = req.peer_addr(peer.parse().unwrap());
+            }
+            origin(&req.to_http_request(), &trusted_proxies)
+        };
+        assert_eq!(
+            origin(Some("192.168.1.1:1234")),
+            "https://cache.example.com"
+        );
+        assert_eq!(origin(None), "https://cache.example.com");
+        assert_eq!(origin(Some("8.8.8.8:1234")), "http://harmonia:5000");
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::upload::check_admin_auth;
+use crate::upstream_cache::Flushed;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Seconds after which clients may retry during maintenance.
+const MAINTENANCE_RETRY_AFTER: u32 = 60;
+
+/// Maintenance mode, e.g. during garbage collection, in which NARs and
+/// narinfos are answered with 503 so that no half-collected paths are served.
+#[derive(Debug, Default)]
+pub(crate) struct Maintenance {
+    enabled: AtomicBool,
+}
+
+impl Maintenance {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            log::info!(
+                "maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    /// Returns the 503 to answer requests with while in maintenance.
+    pub(crate) fn check(&self) -> Option<HttpResponse> {
+        self.is_enabled().then(|| {
+            HttpResponse::ServiceUnavailable()
+                .insert_header(cache_control_no_store())
+                .insert_header((http::header::RETRY_AFTER, MAINTENANCE_RETRY_AFTER))
+                .body("down for maintenance")
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct MaintenanceState {
+    enabled: bool,
+}
+
+/// `POST /admin/maintenance?enabled=<bool>`, returns the new state as JSON.
+pub(crate) async fn set_maintenance(
+    req: HttpRequest,
+    state: web::Query<MaintenanceState>,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    if let Some(res) = check_admin_auth(&req, &settings) {
+        return Ok(res);
+    }
+    settings.maintenance_mode.set(state.enabled);
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(MaintenanceState {
+            enabled: settings.maintenance_mode.is_enabled(),
+        }))
+}
+
+/// Empties the caches of narinfos (and their NARs) from upstream, so that
+/// changes are picked up without a restart.
+pub(crate) async fn flush_caches(settings: &Config) -> Result<Flushed> {
+    let flushed = match &settings.upstream_cache {
+        Some(cache) => cache.flush().await?,
+        None => Flushed::default(),
+    };
+    log::info!(
+        "flushed {} narinfos and {} NARs from the upstream cache",
+        flushed.narinfos,
+        flushed.nars
+    );
+    Ok(flushed)
+}
+
+/// `POST /admin/flush-cache`, returns the number of entries removed as JSON.
+pub(crate) async fn flush_cache(req: HttpRequest, settings: web::Data<Config>) -> ServerResult {
+    if let Some(res) = check_admin_auth(&req, &settings) {
+        return Ok(res);
+    }
+    let flushed = flush_caches(&settings).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(flushed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[tokio::test]
+    async fn test_maintenance() -> Result<(), crate::ServerError> {
+        let settings = web::Data::new(Config {
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        });
+        assert!(settings.maintenance_mode.check().is_none());
+
+        let req = TestRequest::default()
+            .insert_header((http::header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        let res = set_maintenance(
+            req.clone(),
+            web::Query(MaintenanceState { enabled: true }),
+            settings.clone(),
+        )
+        .await?;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let res = settings.maintenance_mode.check().unwrap();
+        assert_eq!(res.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(http::header::RETRY_AFTER).unwrap(), "60");
+
+        set_maintenance(
+            req,
+            web::Query(MaintenanceState { enabled: false }),
+            settings.clone(),
+        )
+        .await?;
+        assert!(settings.maintenance_mode.check().is_none());
+        Ok(())
+    }
+}
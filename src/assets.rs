@@ -0,0 +1,39 @@
+use actix_web::{http, HttpResponse};
+
+use crate::cache_control_max_age_1d;
+use crate::config::Config;
+
+/// Stylesheet covering what the HTML pages use of Bootstrap, built into the
+/// binary so that they also look right without internet access.
+const STYLE_CSS: &str = include_str!("assets/style.css");
+
+const BOOTSTRAP_CDN: &str = r#"
+  <link href="https://cdn.jsdelivr.net/npm/bootstrap@5.2.3/dist/css/bootstrap.min.css"
+        rel="stylesheet"
+        integrity="sha384-rbsA2VBKQhggwzxH7pPCaAqO46MgnOM80zW1RWuH61DGLwZJEdK2Kadq2F9CUG65"
+         crossorigin="anonymous">
+  <script src="https://cdn.jsdelivr.net/npm/bootstrap@5.2.3/dist/js/bootstrap.bundle.min.js"
+          integrity="sha384-kenU1KFdBIe4zVF0s0G1M5b4hcpxyD9F7jL+jjXkk+Q2h455rYXK/7HAuoJl+0I4"
+          crossorigin="anonymous"></script>
+"#;
+
+/// Returns the tags to put into the `<head>` of HTML pages for styling.
+pub(crate) fn head(config: &Config) -> String {
+    if config.use_cdn_assets {
+        BOOTSTRAP_CDN.to_owned()
+    } else {
+        format!(
+            r#"
+  <link href="{}/assets/style.css" rel="stylesheet">
+"#,
+            config.path_prefix
+        )
+    }
+}
+
+pub(crate) async fn style() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(http::header::ContentType(mime::TEXT_CSS_UTF_8))
+        .insert_header(cache_control_max_age_1d())
+        .body(STYLE_CSS)
+}
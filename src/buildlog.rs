@@ -1,17 +1,20 @@
 use actix_files::NamedFile;
 use actix_web::http::header::HeaderValue;
+use actix_web::web::Bytes;
 use actix_web::Responder;
-use actix_web::{http, web, HttpRequest, HttpResponse};
+use actix_web::{http, web, HttpRequest, HttpResponse, HttpResponseBuilder};
 use anyhow::Context;
 use async_compression::tokio::bufread::BzDecoder;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
-use tokio::io::BufReader;
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 
 use crate::config::Config;
+use crate::nar::{multiple_ranges_not_satisfiable, HttpRange};
 use crate::{cache_control_max_age_1y, cache_control_no_store, nixhash, some_or_404};
 
 async fn query_drv_path(settings: &web::Data<Config>, drv: &str) -> Option<String> {
@@ -20,22 +23,22 @@ async fn query_drv_path(settings: &web::Data<Config>, drv: &str) -> Option<Strin
 
 pub fn get_build_log(store: &Path, drv_path: &Path) -> Option<PathBuf> {
     let drv_name = drv_path.file_name()?.as_bytes();
-    let log_path = match store.parent().map(|p| {
+    let log_path = store.parent().map(|p| {
         p.join("var")
             .join("log")
             .join("nix")
             .join("drvs")
             .join(OsStr::from_bytes(&drv_name[0..2]))
             .join(OsStr::from_bytes(&drv_name[2..]))
-    }) {
-        Some(log_path) => log_path,
-        None => return None,
-    };
+    })?;
     if log_path.exists() {
         return Some(log_path);
     }
-    // check if compressed log exists
-    let log_path = log_path.with_extension("drv.bz2");
+    // check if compressed log exists. Append to the full name, as
+    // with_extension would replace anything after the last dot.
+    let mut compressed_name = log_path.file_name()?.to_owned();
+    compressed_name.push(".bz2");
+    let log_path = log_path.with_file_name(compressed_name);
     if log_path.exists() {
         Some(log_path)
     } else {
@@ -43,6 +46,40 @@ pub fn get_build_log(store: &Path, drv_path: &Path) -> Option<PathBuf> {
     }
 }
 
+/// Answers with the decompressed `log`, or the single range of it that was
+/// requested.
+fn ranged_log(mut res: HttpResponseBuilder, req: &HttpRequest, log: Bytes) -> HttpResponse {
+    res.insert_header((http::header::ACCEPT_RANGES, "bytes"));
+    let size = log.len() as u64;
+    let Some(ranges) = req.headers().get(http::header::RANGE) else {
+        return res.body(log);
+    };
+    let Ok(ranges_header) = ranges.to_str() else {
+        return res.status(http::StatusCode::BAD_REQUEST).finish();
+    };
+    match HttpRange::parse(ranges_header, size) {
+        Ok(ranges) if ranges.len() > 1 => multiple_ranges_not_satisfiable(res, size),
+        Ok(ranges) => {
+            let (start, length) = (ranges[0].start, ranges[0].length);
+            res.status(http::StatusCode::PARTIAL_CONTENT)
+                .insert_header((
+                    http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, start + length - 1, size),
+                ))
+                // the range refers to the uncompressed log
+                .insert_header((
+                    http::header::CONTENT_ENCODING,
+                    HeaderValue::from_static("identity"),
+                ))
+                .body(log.slice(start as usize..(start + length) as usize))
+        }
+        Err(_) => res
+            .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header((http::header::CONTENT_RANGE, format!("bytes */{}", size)))
+            .finish(),
+    }
+}
+
 pub(crate) async fn get(
     drv: web::Path<String>,
     req: HttpRequest,
@@ -71,7 +108,7 @@ pub(crate) async fn get(
     }
     let build_log = some_or_404!(get_build_log(
         settings.store.real_store(),
-        &PathBuf::from(drv_path)
+        &PathBuf::from(&drv_path)
     ));
     let ext = match build_log.extension() {
         Some(ext) => ext,
@@ -88,19 +125,55 @@ pub(crate) async fn get(
         .unwrap_or("");
 
     if ext == "bz2" && !accept_encoding.contains("bzip2") {
+        let drv_hash = Path::new(&drv_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.get(..32));
+        if let (Some(cache), Some(drv_hash)) = (&settings.log_cache, drv_hash) {
+            let path = cache.decompressed(drv_hash, &build_log).await?;
+            let log = NamedFile::open_async(&path)
+                .await
+                .with_context(|| format!("Failed to open build log: {:?}", path.display()))?
+                .disable_content_disposition()
+                .set_content_type(mime::TEXT_PLAIN_UTF_8)
+                .customize()
+                .insert_header(cache_control_max_age_1y());
+            return Ok(log.respond_to(&req).map_into_boxed_body());
+        }
+
         // Decompress the bz2 file and serve the decompressed content
         let file = tokio::fs::File::open(&build_log)
             .await
             .with_context(|| format!("Failed to open build log: {:?}", build_log.display()))?;
         let reader = BufReader::new(file);
-        let decompressed_stream = BzDecoder::new(reader);
-        let stream = ReaderStream::new(decompressed_stream);
-        let body = actix_web::body::BodyStream::new(stream);
-
-        return Ok(HttpResponse::Ok()
-            .insert_header(cache_control_max_age_1y())
-            .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8))
-            .body(body));
+        let mut decompressed = BzDecoder::new(reader);
+
+        // Small logs are decompressed into memory, so that the Content-Length
+        // is known and clients can show progress.
+        let mut head = Vec::new();
+        (&mut decompressed)
+            .take(settings.buildlog_inline_max_size + 1)
+            .read_to_end(&mut head)
+            .await
+            .with_context(|| {
+                format!("Failed to decompress build log: {:?}", build_log.display())
+            })?;
+
+        let mut res = HttpResponse::Ok();
+        res.insert_header(cache_control_max_age_1y())
+            .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8));
+        if head.len() as u64 <= settings.buildlog_inline_max_size {
+            return Ok(ranged_log(res, &req, head.into()));
+        }
+        if req.headers().contains_key(http::header::RANGE) {
+            // larger logs are decompressed while streaming, which can't seek
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(cache_control_no_store())
+                .body("ranges are not supported on large compressed logs"));
+        }
+        let stream =
+            tokio_stream::once(Ok(Bytes::from(head))).chain(ReaderStream::new(decompressed));
+        return Ok(res.body(actix_web::body::BodyStream::new(stream)));
     }
 
     // Serve the file as-is with the appropriate Content-Encoding header
@@ -119,3 +192,69 @@ pub(crate) async fn get(
 
     Ok(log.respond_to(&req).map_into_boxed_body())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_ranged_log() -> Result<(), Box<dyn std::error::Error>> {
+        let log = Bytes::from_static(b"building hello\n");
+        let respond = |range: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(range) = range {
+                req = req.insert_header((http::header::RANGE, range));
+            }
+            ranged_log(HttpResponse::Ok(), &req.to_http_request(), log.clone())
+        };
+
+        let res = respond(None);
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(
+            res.headers().get(http::header::ACCEPT_RANGES).unwrap(),
+            "bytes"
+        );
+
+        let res = respond(Some("bytes=9-"));
+        assert_eq!(res.status(), http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_RANGE).unwrap(),
+            "bytes 9-14/15"
+        );
+        let body = actix_web::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body, "hello\n");
+
+        let res = respond(Some("bytes=20-"));
+        assert_eq!(res.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_RANGE).unwrap(),
+            "bytes */15"
+        );
+        let res = respond(Some("bytes=0-1,3-4"));
+        assert_eq!(res.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_build_log() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let store = temp_dir.path().join("nix").join("store");
+        let drvs = temp_dir.path().join("nix/var/log/nix/drvs/5w");
+        std::fs::create_dir_all(&store)?;
+        std::fs::create_dir_all(&drvs)?;
+        let drv_path = store.join("5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv");
+
+        assert_eq!(get_build_log(&store, &drv_path), None);
+
+        let compressed = drvs.join("5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv.bz2");
+        std::fs::write(&compressed, b"")?;
+        assert_eq!(get_build_log(&store, &drv_path), Some(compressed));
+
+        let plain = drvs.join("5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv");
+        std::fs::write(&plain, b"")?;
+        assert_eq!(get_build_log(&store, &drv_path), Some(plain));
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Location of a file's contents inside the bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BundleEntry {
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+/// A tarball laid out like a `file://` binary cache, i.e. containing
+/// `<hash>.narinfo` files and the NARs they reference under `nar/`.
+///
+/// The bundle is indexed once at startup; NARs are later served via range
+/// reads from the tarball, so no nix daemon or store is needed.
+#[derive(Debug)]
+pub(crate) struct Bundle {
+    path: PathBuf,
+    narinfos: HashMap<String, String>,
+    nars: HashMap<String, BundleEntry>,
+    /// Hash parts of the store paths whose narinfo references each NAR, so
+    /// that the path filter also applies to NARs requested without `?hash=`.
+    nar_owners: HashMap<String, Vec<String>>,
+}
+
+/// The NAR URL in a narinfo, relative to the cache root and without query.
+fn narinfo_url(narinfo: &str) -> Option<&str> {
+    let url = narinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("URL:"))?
+        .trim();
+    url.split('?').next()
+}
+
+impl Bundle {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Couldn't open bundle '{}'", path.display()))?;
+        let mut archive = tar::Archive::new(file);
+        let mut narinfos = HashMap::new();
+        let mut nars = HashMap::new();
+
+        for entry in archive
+            .entries_with_seek()
+            .context("Couldn't read bundle entries")?
+        {
+            let mut entry = entry.context("Couldn't read bundle entry")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry
+                .path()
+                .context("Invalid path in bundle")?
+                .to_string_lossy()
+                .trim_start_matches("./")
+                .to_owned();
+
+            if let Some(hash) = name.strip_suffix(".narinfo") {
+                if hash.len() != 32 {
+                    continue;
+                }
+                let hash = hash.to_owned();
+                let mut narinfo = String::new();
+                entry
+                    .read_to_string(&mut narinfo)
+                    .with_context(|| format!("Couldn't read '{}' from bundle", name))?;
+                narinfos.insert(hash, narinfo);
+            } else if name.starts_with("nar/") {
+                nars.insert(
+                    name,
+                    BundleEntry {
+                        offset: entry.raw_file_position(),
+                        size: entry.size(),
+                    },
+                );
+            }
+        }
+        let mut nar_owners: HashMap<String, Vec<String>> = HashMap::new();
+        for (hash, narinfo) in &narinfos {
+            if let Some(url) = narinfo_url(narinfo) {
+                nar_owners
+                    .entry(url.to_owned())
+                    .or_default()
+                    .push(hash.clone());
+            }
+        }
+        log::info!(
+            "indexed {} narinfos and {} nars in bundle {}",
+            narinfos.len(),
+            nars.len(),
+            path.display()
+        );
+
+        Ok(Self {
+            path: path.to_owned(),
+            narinfos,
+            nars,
+            nar_owners,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn narinfo(&self, hash: &str) -> Option<&str> {
+        self.narinfos.get(hash).map(String::as_str)
+    }
+
+    /// Looks up a NAR by its URL relative to the cache root, e.g. `nar/<hash>.nar.xz`.
+    pub(crate) fn nar(&self, url: &str) -> Option<BundleEntry> {
+        self.nars.get(url).copied()
+    }
+
+    /// Hash parts of the store paths whose narinfo references the NAR at `url`.
+    /// NARs that no narinfo references have none.
+    pub(crate) fn nar_owners(&self, url: &str) -> &[String] {
+        self.nar_owners.get(url).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    #[test]
+    fn test_bundle_index() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let bundle_path = temp_dir.path().join("bundle.tar");
+        let nar_name = "nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar.xz";
+        let narinfo = format!(
+            "StorePath: /nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1\nURL: {}?hash=26xbg1ndr7hbcncrlf9nhx5is2b25d13\n",
+            nar_name
+        );
+        let nar = b"not really a nar";
+
+        let mut builder = tar::Builder::new(File::create(&bundle_path)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(narinfo.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(
+            &mut header,
+            "26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo",
+            narinfo.as_bytes(),
+        )?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(nar.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, nar_name, &nar[..])?;
+        builder.finish()?;
+        drop(builder);
+
+        let bundle = Bundle::open(&bundle_path)?;
+        assert_eq!(
+            bundle.narinfo("26xbg1ndr7hbcncrlf9nhx5is2b25d13"),
+            Some(narinfo.as_str())
+        );
+        assert_eq!(bundle.narinfo("sl141d1g77wvhr050ah87lcyz2czdxa3"), None);
+
+        let entry = bundle.nar(nar_name).context("nar not indexed")?;
+        assert_eq!(entry.size, nar.len() as u64);
+        let mut file = File::open(bundle.path())?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut content = vec![0; entry.size as usize];
+        file.read_exact(&mut content)?;
+        assert_eq!(content, nar);
+
+        assert_eq!(
+            bundle.nar_owners(nar_name),
+            ["26xbg1ndr7hbcncrlf9nhx5is2b25d13"]
+        );
+        assert!(bundle.nar_owners("nar/unknown.nar").is_empty());
+        Ok(())
+    }
+}
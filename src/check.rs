@@ -0,0 +1,66 @@
+use anyhow::{bail, Context, Result};
+
+use crate::config::Config;
+use crate::nar::check_nar_hash;
+use crate::store_paths::{SelectedPath, SelectedPaths};
+
+/// Verifies that the NARs of store paths match the NarHash recorded by the
+/// daemon, without serving them.
+///
+/// Checks all valid paths that are allowed to be served if `paths` is empty.
+pub(crate) async fn run(settings: &Config, paths: &[String]) -> Result<()> {
+    let mut daemon = settings.store.daemon.lock().await;
+    let mut paths = SelectedPaths::new(&mut daemon, paths).await?;
+
+    let mut corrupt = 0;
+    let mut checked = 0;
+    while let Some(SelectedPath { path, info, .. }) = paths.next(settings, &mut daemon).await? {
+        checked += 1;
+        let real_path = settings.store.get_real_path(path.as_ref());
+        if !check_nar_hash(real_path, &info.hash)
+            .await
+            .with_context(|| format!("Failed to dump {}", path))?
+        {
+            log::error!("{} does not match its NarHash", path);
+            corrupt += 1;
+        }
+    }
+    if corrupt > 0 {
+        bail!("{} of {} store paths are corrupt", corrupt, checked);
+    }
+    log::info!("all {} store paths match their NarHash", checked);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_store::TempStore;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Fails once a file of a path in a temporary store was modified.
+    #[tokio::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_run() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file("harmonia-test.txt", b"hello harmonia")?;
+        let settings = Config {
+            store: temp_store.store(),
+            ..Default::default()
+        };
+        let paths = [store_path.clone()];
+        run(&settings, &paths).await?;
+
+        let real_path = temp_store.real_path(&store_path);
+        let info = settings
+            .store
+            .query_path_info(&store_path)
+            .await?
+            .context("path vanished")?;
+        std::fs::set_permissions(&real_path, std::fs::Permissions::from_mode(0o644))?;
+        std::fs::write(&real_path, b"hello corruption")?;
+        assert!(!check_nar_hash(real_path, &info.hash).await?);
+        assert!(run(&settings, &paths).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use actix_web::body::BodyStream;
+use actix_web::web::Bytes;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+use tokio_stream::Stream;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Compression algorithms that can be applied to NARs on the fly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    None,
+    Zstd,
+    Xz,
+    Gzip,
+    Brotli,
+}
+
+impl Compression {
+    /// Name used in the `Compression:` field of a narinfo.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+        }
+    }
+
+    /// Suffix appended to `.nar` in NAR URLs.
+    pub(crate) fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zst"),
+            Compression::Xz => Some("xz"),
+            Compression::Gzip => Some("gz"),
+            Compression::Brotli => Some("br"),
+        }
+    }
+
+    pub(crate) fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "zst" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            "gz" => Some(Compression::Gzip),
+            "br" => Some(Compression::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Token used for this algorithm in the `Accept-Encoding` header.
+    fn encoding_token(self) -> &'static str {
+        match self {
+            Compression::None => "identity",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+        }
+    }
+
+    /// Picks the first of the `configured` compressions that the client accepts.
+    ///
+    /// `configured` is ordered by preference. Falls back to `Compression::None`
+    /// if there is no mutually supported encoding.
+    pub(crate) fn negotiate(accept_encoding: &str, configured: &[Compression]) -> Compression {
+        let accepted = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let token = parts.next()?.to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+                if token.is_empty() {
+                    None
+                } else {
+                    Some((token, quality))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let accepts = |c: Compression| {
+            let token = c.encoding_token();
+            let quality = accepted
+                .iter()
+                .find(|(t, _)| t == token)
+                .or_else(|| accepted.iter().find(|(t, _)| t == "*"))
+                .map(|(_, q)| *q);
+            matches!(quality, Some(q) if q > 0.0)
+        };
+
+        configured
+            .iter()
+            .copied()
+            .find(|c| *c != Compression::None && accepts(*c))
+            .unwrap_or(Compression::None)
+    }
+
+    /// Wraps an uncompressed byte stream into a compressed response body.
+    pub(crate) fn encode<S, E>(
+        self,
+        stream: S,
+    ) -> BodyStream<ReaderStream<Box<dyn AsyncRead + Unpin>>>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let reader = StreamReader::new(tokio_stream::StreamExt::map(stream, |r| {
+            r.map_err(std::io::Error::other)
+        }));
+        let reader: Box<dyn AsyncRead + Unpin> = match self {
+            Compression::None => Box::new(reader),
+            Compression::Zstd => Box::new(ZstdEncoder::new(reader)),
+            Compression::Xz => Box::new(XzEncoder::new(reader)),
+            Compression::Gzip => Box::new(GzipEncoder::new(reader)),
+            Compression::Brotli => Box::new(BrotliEncoder::new(reader)),
+        };
+        BodyStream::new(ReaderStream::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let configured = [Compression::Zstd, Compression::Brotli, Compression::Gzip];
+        assert_eq!(
+            Compression::negotiate("gzip, br", &configured),
+            Compression::Brotli
+        );
+        assert_eq!(
+            Compression::negotiate("gzip, zstd;q=0", &configured),
+            Compression::Gzip
+        );
+        assert_eq!(Compression::negotiate("*", &configured), Compression::Zstd);
+        assert_eq!(
+            Compression::negotiate("deflate", &configured),
+            Compression::None
+        );
+        assert_eq!(Compression::negotiate("", &configured), Compression::None);
+        assert_eq!(Compression::negotiate("gzip", &[]), Compression::None);
+    }
+}
@@ -1,9 +1,33 @@
-use crate::signing::parse_secret_key;
+use crate::access::NetworkFilter;
+use crate::admin::Maintenance;
+use crate::bundle::Bundle;
+use crate::compression::Compression;
+use crate::daemon::RetryPolicy;
+use crate::log_cache::LogCache;
+use crate::metrics::Metrics;
+use crate::nar::{NarDumps, DEFAULT_NAR_CHANNEL_CAPACITY};
+use crate::narinfo::is_standard_field;
+use crate::readiness::Readiness;
+use crate::signing::{
+    parse_public_key, parse_secret_key, parse_secret_key_str, self_test_secret_key,
+};
 use crate::store::Store;
-use anyhow::{Context, Result};
+use crate::upload::PendingUploads;
+use crate::upstream::Upstream;
+use crate::upstream_cache::UpstreamCache;
+use crate::version::StartTime;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::HttpRequest;
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 fn default_bind() -> String {
     "[::]:5000".into()
@@ -17,10 +41,91 @@ fn default_connection_rate() -> usize {
     256
 }
 
+fn default_keep_alive() -> u64 {
+    5
+}
+
+fn default_client_request_timeout() -> u64 {
+    // actix' default of 5 seconds is too small for mass requests on slow machines
+    30
+}
+
+fn default_client_disconnect_timeout_ms() -> u64 {
+    1000
+}
+
 fn default_priority() -> usize {
     30
 }
 
+fn default_nar_cache_control_max_age() -> u32 {
+    365 * 24 * 60 * 60
+}
+
+fn default_narinfo_cache_control_max_age() -> u32 {
+    24 * 60 * 60
+}
+
+fn default_narinfo_missing_status() -> u16 {
+    404
+}
+
+fn default_cache_max_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_buildlog_cache_max_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_buildlog_inline_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+fn default_sign_narinfos() -> bool {
+    true
+}
+
+fn default_path_info_cache_size() -> usize {
+    10000
+}
+
+fn default_max_serve_depth() -> usize {
+    256
+}
+
+fn default_nar_channel_capacity() -> usize {
+    DEFAULT_NAR_CHANNEL_CAPACITY
+}
+
+fn default_unix_socket_mode() -> u32 {
+    0o777
+}
+
+fn default_daemon_max_retries() -> u32 {
+    RetryPolicy::default().max_retries
+}
+
+fn default_daemon_retry_backoff_ms() -> u64 {
+    RetryPolicy::default().initial_backoff.as_millis() as u64
+}
+
+fn default_nar_dump_queue_timeout() -> u64 {
+    30
+}
+
+fn default_daemon_timeout() -> u64 {
+    60
+}
+
+fn default_pending_upload_ttl() -> u64 {
+    60 * 60
+}
+
 fn default_virtual_store() -> String {
     "/nix/store".into()
 }
@@ -31,6 +136,104 @@ pub(crate) struct SigningKey {
     pub(crate) key: Vec<u8>,
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct PublicKey {
+    pub(crate) name: String,
+    pub(crate) key: Vec<u8>,
+}
+
+/// Restricts which store paths are served, keyed by their hash part.
+#[derive(Debug, Default)]
+pub(crate) struct PathFilter {
+    pub(crate) allow: Option<HashSet<String>>,
+    pub(crate) deny: HashSet<String>,
+}
+
+impl PathFilter {
+    pub(crate) fn is_allowed(&self, hash: &str) -> bool {
+        if self.deny.contains(hash) {
+            return false;
+        }
+        match &self.allow {
+            Some(allow) => allow.contains(hash),
+            None => true,
+        }
+    }
+}
+
+/// Extracts the hash part from a store hash, a store path basename or a full store path.
+fn path_list_entry_hash(entry: &str) -> Result<String> {
+    let name = Path::new(entry)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(entry);
+    match name.get(0..32) {
+        Some(hash) if name.len() == 32 || name.as_bytes()[32] == b'-' => Ok(hash.to_owned()),
+        _ => bail!("'{}' is neither a store hash nor a store path", entry),
+    }
+}
+
+fn load_path_list(entries: &[String], file: Option<&Path>) -> Result<HashSet<String>> {
+    let mut hashes = HashSet::new();
+    for entry in entries {
+        hashes.insert(path_list_entry_hash(entry)?);
+    }
+    if let Some(file) = file {
+        let content = read_to_string(file)
+            .with_context(|| format!("Couldn't read path list '{}'", file.display()))?;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            hashes.insert(
+                path_list_entry_hash(line)
+                    .with_context(|| format!("Invalid entry in '{}'", file.display()))?,
+            );
+        }
+    }
+    Ok(hashes)
+}
+
+fn lookup_uid(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).with_context(|| format!("Invalid user name '{}'", user))?;
+    // SAFETY: name is a valid C string, the result is only read before the next lookup
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        bail!("Unknown user '{}'", user);
+    }
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+fn lookup_gid(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).with_context(|| format!("Invalid group name '{}'", group))?;
+    // SAFETY: name is a valid C string, the result is only read before the next lookup
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        bail!("Unknown group '{}'", group);
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+/// Another store that can be selected with the `X-Nix-Store-Dir` header.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct StoreConfig {
+    /// Physical location of the store, if it differs from the store dir.
+    #[serde(default)]
+    pub(crate) real_nix_store: Option<String>,
+    /// Socket of the daemon managing the store. The default daemon if unset.
+    #[serde(default)]
+    pub(crate) daemon_socket: Option<PathBuf>,
+}
+
+/// Header that selects one of the `stores` by its store dir.
+pub(crate) const STORE_DIR_HEADER: &str = "X-Nix-Store-Dir";
+
 // TODO(conni2461): users to restrict access
 #[derive(Deserialize, Debug, Default)]
 pub(crate) struct Config {
@@ -40,41 +243,451 @@ pub(crate) struct Config {
     pub(crate) workers: usize,
     #[serde(default = "default_connection_rate")]
     pub(crate) max_connection_rate: usize,
+    /// Maximum number of blocking threads per worker, which do the file I/O.
+    /// Defaults to 512 divided by the number of CPUs.
+    #[serde(default)]
+    pub(crate) blocking_threads: Option<usize>,
+    /// Seconds an idle connection is kept open for further requests, 0 to
+    /// close connections after each response.
+    #[serde(default = "default_keep_alive")]
+    pub(crate) keep_alive: u64,
+    /// Seconds a client has to send the request headers, 0 for no limit.
+    #[serde(default = "default_client_request_timeout")]
+    pub(crate) client_request_timeout: u64,
+    /// Milliseconds a client has to acknowledge the shutdown of a connection,
+    /// 0 for no limit.
+    #[serde(default = "default_client_disconnect_timeout_ms")]
+    pub(crate) client_disconnect_timeout_ms: u64,
     #[serde(default = "default_priority")]
     pub(crate) priority: usize,
 
+    /// `Cache-Control: max-age` in seconds for NARs.
+    #[serde(default = "default_nar_cache_control_max_age")]
+    pub(crate) nar_cache_control_max_age: u32,
+    /// `Cache-Control: max-age` in seconds for narinfos.
+    #[serde(default = "default_narinfo_cache_control_max_age")]
+    pub(crate) narinfo_cache_control_max_age: u32,
+
+    /// Status for narinfos that are not in the cache: 404, or 200 with an
+    /// empty body for proxies that don't cache errors.
+    #[serde(default = "default_narinfo_missing_status")]
+    pub(crate) narinfo_missing_status: u16,
+
+    /// Only return the status in error responses, instead of the error chain,
+    /// which may contain store paths and other internals.
+    #[serde(default)]
+    pub(crate) hide_error_details: bool,
+
+    /// Hash NARs while they are served and cut off those not matching their NarHash.
+    #[serde(default)]
+    pub(crate) verify_nar_hash: bool,
+    /// Wait for the start of an uncompressed NAR before sending the response
+    /// headers, so that they go out together.
+    #[serde(default)]
+    pub(crate) nar_prebuffer_header: bool,
+
+    /// Serve compressed NARs from `<store path>.nar.<ext>` files instead of
+    /// compressing them on the fly, if such a file exists.
+    #[serde(default)]
+    pub(crate) precompressed_nars: bool,
+
+    /// Compressed build logs up to this many bytes (decompressed) are served
+    /// with a Content-Length, larger ones are streamed.
+    #[serde(default = "default_buildlog_inline_max_size")]
+    pub(crate) buildlog_inline_max_size: u64,
+    /// Keep decompressed build logs in `cache_dir`, so that they are only
+    /// decompressed once.
+    #[serde(default)]
+    pub(crate) buildlog_cache: bool,
+    /// Size in bytes above which the logs of the oldest builds are evicted
+    /// from the build log cache.
+    #[serde(default = "default_buildlog_cache_max_size")]
+    pub(crate) buildlog_cache_max_size: u64,
+
+    /// NARs larger than this many bytes are not served. Unlimited if unset.
+    #[serde(default)]
+    pub(crate) max_nar_size: Option<u64>,
+
+    /// Number of NARs that are dumped at once. Unlimited if unset.
+    #[serde(default)]
+    pub(crate) max_concurrent_nar_dumps: Option<usize>,
+    /// Seconds a NAR request waits for one of `max_concurrent_nar_dumps` before
+    /// giving up with 503, 0 to wait forever.
+    #[serde(default = "default_nar_dump_queue_timeout")]
+    pub(crate) nar_dump_queue_timeout: u64,
+    /// Seconds the path infos queried from the daemon are cached for, 0 disables the cache.
+    #[serde(default)]
+    pub(crate) path_info_cache_ttl: u64,
+    /// Number of path infos that are cached at most.
+    #[serde(default = "default_path_info_cache_size")]
+    pub(crate) path_info_cache_size: usize,
+    /// Open files harmonia needs at least. The soft limit is raised to it at
+    /// startup if possible, with a warning otherwise. Defaults to `workers`
+    /// times `max_concurrent_nar_dumps` if that is set.
+    #[serde(default)]
+    pub(crate) min_open_files: Option<u64>,
+    /// `/diskspace` answers with 503 if fewer bytes are available in the store.
+    #[serde(default)]
+    pub(crate) min_free_disk_space: Option<u64>,
+    /// Chunks of up to 16 KiB buffered per NAR dump and per client.
+    #[serde(default = "default_nar_channel_capacity")]
+    pub(crate) nar_channel_capacity: usize,
+    /// NAR dumps taking longer than this many seconds are logged.
+    #[serde(default)]
+    pub(crate) slow_nar_log_threshold: Option<u64>,
+    /// Count requests by the Nix version in the `User-Agent` in `/metrics`.
+    #[serde(default)]
+    pub(crate) client_version_metrics: bool,
+
+    /// Requests with a longer path and query are rejected with 414.
+    #[serde(default)]
+    pub(crate) max_uri_length: Option<usize>,
+    /// `/serve` requests with more path components within the store path are
+    /// rejected with 400, before resolving them.
+    #[serde(default = "default_max_serve_depth")]
+    pub(crate) max_serve_depth: usize,
+    /// Request bodies, like uploads, larger than this are rejected with 413.
+    /// Defaults to the limits of actix for narinfos and JSON and no limit for NARs.
+    #[serde(default)]
+    pub(crate) max_payload_size: Option<usize>,
+
+    /// Permissions of the socket when binding to a `unix:` URL.
+    #[serde(default = "default_unix_socket_mode")]
+    pub(crate) unix_socket_mode: u32,
+    /// User (name or uid) owning the socket when binding to a `unix:` URL.
+    #[serde(default)]
+    pub(crate) unix_socket_owner: Option<String>,
+    /// Group (name or gid) owning the socket when binding to a `unix:` URL.
+    #[serde(default)]
+    pub(crate) unix_socket_group: Option<String>,
+
+    /// How often a failed daemon query is retried before giving up.
+    #[serde(default = "default_daemon_max_retries")]
+    pub(crate) daemon_max_retries: u32,
+    /// Delay before the first retry in milliseconds, doubled for every further retry.
+    #[serde(default = "default_daemon_retry_backoff_ms")]
+    pub(crate) daemon_retry_backoff_ms: u64,
+    /// Seconds to wait for each read or write on the daemon socket before
+    /// giving up with 503, 0 to wait forever.
+    #[serde(default = "default_daemon_timeout")]
+    pub(crate) daemon_timeout: u64,
+
+    /// URL under which clients reach the cache, shown on the landing page and
+    /// used for absolute NAR URLs in narinfos. Derived from the request for
+    /// the landing page and relative NAR URLs if unset.
+    #[serde(default)]
+    pub(crate) public_url: Option<String>,
+    /// Path all endpoints are served below, like `/cache`, e.g. to share a
+    /// host with other services behind a reverse proxy.
+    #[serde(default)]
+    pub(crate) path_prefix: String,
+    /// Style HTML pages with Bootstrap from a CDN instead of the builtin stylesheet.
+    #[serde(default)]
+    pub(crate) use_cdn_assets: bool,
+    /// Redirect `/` to this URL instead of showing the landing page.
+    #[serde(default)]
+    pub(crate) root_redirect: Option<String>,
+    /// Serve this HTML file at `/` instead of the landing page.
+    #[serde(default)]
+    pub(crate) root_html_path: Option<PathBuf>,
+
     #[serde(default = "default_virtual_store")]
     pub(crate) virtual_nix_store: String,
 
     pub(crate) real_nix_store: Option<String>,
 
+    /// Further stores by their store dir, served to requests that name the
+    /// store dir in the `X-Nix-Store-Dir` header.
+    #[serde(default)]
+    pub(crate) stores: HashMap<String, StoreConfig>,
+
     #[serde(default)]
     pub(crate) sign_key_path: Option<String>,
     #[serde(default)]
     pub(crate) sign_key_paths: Vec<PathBuf>,
+    /// Sign narinfos with the signing keys when serving them. Without, only
+    /// the signatures already attached to the paths in the store are served.
+    #[serde(default = "default_sign_narinfos")]
+    pub(crate) sign_narinfos: bool,
+    /// Serve the signatures attached to the paths along with the own ones,
+    /// instead of only the own ones.
+    #[serde(default)]
+    pub(crate) merge_signatures: bool,
+    /// Fail with 500 instead of serving narinfos without any signature.
+    #[serde(default)]
+    pub(crate) require_signatures: bool,
     #[serde(default)]
     pub(crate) tls_cert_path: Option<String>,
     #[serde(default)]
     pub(crate) tls_key_path: Option<String>,
+    /// Offer HTTP/2 to TLS clients, which multiplexes their parallel requests.
+    #[serde(default = "default_http2")]
+    pub(crate) http2: bool,
+
+    /// Embed the store path hash into NAR URLs (`nar/<outhash>-<narhash>.nar`)
+    /// instead of passing it as `?hash=` query parameter.
+    #[serde(default)]
+    pub(crate) query_free_nar_urls: bool,
+
+    /// Add the id of the realisation to narinfos of content-addressed
+    /// derivation outputs, as a `Realisation` field.
+    #[serde(default)]
+    pub(crate) narinfo_realisations: bool,
 
+    /// Static fields appended to every narinfo, e.g. for provenance metadata.
+    #[serde(default)]
+    pub(crate) extra_narinfo_fields: BTreeMap<String, String>,
+
+    /// Start in maintenance mode, answering NAR and narinfo requests with 503.
+    /// Can be toggled at runtime with `SIGUSR2` or `/admin/maintenance`.
+    #[serde(default)]
+    pub(crate) maintenance: bool,
+
+    /// Headers added to every response that doesn't set them itself, e.g.
+    /// security headers like `X-Content-Type-Options`.
+    #[serde(default)]
+    pub(crate) response_headers: BTreeMap<String, String>,
+
+    /// NAR compressions offered to clients, in order of preference.
+    #[serde(default)]
+    pub(crate) compression: Vec<Compression>,
+
+    /// Store hashes or store paths that may be served. All paths are served if unset.
+    #[serde(default)]
+    pub(crate) allowed_paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub(crate) allowed_paths_file: Option<PathBuf>,
+    /// Store hashes or store paths that are never served.
+    #[serde(default)]
+    pub(crate) denied_paths: Vec<String>,
+    #[serde(default)]
+    pub(crate) denied_paths_file: Option<PathBuf>,
+
+    /// Networks that may access the cache, e.g. `10.0.0.0/8`. Everyone if empty.
+    #[serde(default)]
+    pub(crate) allowed_networks: Vec<IpNet>,
+    /// Proxies that are trusted to name the client in `X-Forwarded-For`, and
+    /// the scheme and host in `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    /// Answer health checks regardless of `allowed_networks`.
+    #[serde(default)]
+    pub(crate) allowed_networks_exempt_health: bool,
+
+    /// Serve narinfos and NARs from this tarball instead of the nix store.
+    #[serde(default)]
+    pub(crate) bundle_path: Option<PathBuf>,
+
+    /// Content types of files served by /serve by extension, overriding the guessed ones.
+    #[serde(default)]
+    pub(crate) mime_types: HashMap<String, String>,
+    /// Content type of files served by /serve whose type can't be guessed,
+    /// instead of `application/octet-stream`.
+    #[serde(default)]
+    pub(crate) default_mime_type: Option<String>,
+
+    /// Binary cache that requests for paths missing locally are proxied to.
+    #[serde(default)]
+    pub(crate) upstream: Option<String>,
+
+    /// Directory where narinfos and NARs proxied from upstream are kept.
+    #[serde(default)]
+    pub(crate) cache_dir: Option<PathBuf>,
+    /// Size in bytes above which the least recently used NARs are evicted from `cache_dir`.
+    #[serde(default = "default_cache_max_size")]
+    pub(crate) cache_max_size: u64,
+
+    /// File containing the token that authorizes uploads. Uploads are disabled if unset.
+    #[serde(default)]
+    pub(crate) upload_token_path: Option<PathBuf>,
+    /// Seconds an uploaded NAR waits for its narinfo before it is dropped.
+    #[serde(default = "default_pending_upload_ttl")]
+    pub(crate) pending_upload_ttl: u64,
+    /// File containing the token that authorizes administrative endpoints like
+    /// `/roots`. They are disabled if unset.
+    #[serde(default)]
+    pub(crate) admin_token_path: Option<PathBuf>,
+    /// Serve `/manifest`, listing all servable paths. Requires the admin token.
+    #[serde(default)]
+    pub(crate) manifest: bool,
+
+    /// Keys of which uploaded paths need a signature from, in `nix.conf` format.
+    #[serde(default)]
+    pub(crate) trusted_public_keys: Vec<String>,
+
+    #[serde(skip, default)]
+    /// Swapped when the configuration is reloaded.
+    pub(crate) secret_keys: ArcSwap<Vec<SigningKey>>,
     #[serde(skip, default)]
-    pub(crate) secret_keys: Vec<SigningKey>,
+    pub(crate) public_keys: Vec<PublicKey>,
+    #[serde(skip)]
+    pub(crate) unix_socket_uid: Option<u32>,
+    #[serde(skip)]
+    pub(crate) unix_socket_gid: Option<u32>,
+    #[serde(skip)]
+    pub(crate) path_filter: PathFilter,
+    /// Parsed `response_headers`.
+    #[serde(skip)]
+    pub(crate) default_headers: Vec<(HeaderName, HeaderValue)>,
+    #[serde(skip)]
+    pub(crate) network_filter: NetworkFilter,
+    #[serde(skip)]
+    pub(crate) bundle: Option<Bundle>,
+    #[serde(skip)]
+    pub(crate) upstream_proxy: Option<Upstream>,
+    #[serde(skip)]
+    pub(crate) upstream_cache: Option<UpstreamCache>,
+    #[serde(skip)]
+    pub(crate) log_cache: Option<LogCache>,
+    #[serde(skip)]
+    pub(crate) upload_token: Option<String>,
+    #[serde(skip)]
+    pub(crate) admin_token: Option<String>,
+    #[serde(skip)]
+    pub(crate) uploads: PendingUploads,
+    #[serde(skip)]
+    pub(crate) nar_dumps: Arc<NarDumps>,
+    #[serde(skip)]
+    pub(crate) readiness: Readiness,
+    #[serde(skip)]
+    pub(crate) metrics: Metrics,
+    #[serde(skip)]
+    pub(crate) started: StartTime,
+    #[serde(skip)]
+    pub(crate) maintenance_mode: Maintenance,
     #[serde(skip)]
     pub(crate) store: Store,
+    #[serde(skip)]
+    pub(crate) extra_stores: HashMap<String, Store>,
+}
+
+impl Config {
+    /// Returns the store selected by the `X-Nix-Store-Dir` header of `req`,
+    /// falling back to the default store for absent or unknown store dirs.
+    pub(crate) fn store_for(&self, req: &HttpRequest) -> &Store {
+        req.headers()
+            .get(STORE_DIR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|dir| self.extra_stores.get(dir.trim_end_matches('/')))
+            .unwrap_or(&self.store)
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<toml::Table> {
+    toml::from_str(
+        &read_to_string(path)
+            .with_context(|| format!("Couldn't read config file '{}'", path.display()))?,
+    )
+    .with_context(|| format!("Couldn't parse config file '{}'", path.display()))
+}
+
+/// Parses the configured `response_headers`. Headers that describe the body
+/// are rejected, since the body is encoded per response.
+fn parse_response_headers(
+    headers: &BTreeMap<String, String>,
+) -> Result<Vec<(HeaderName, HeaderValue)>> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = HeaderName::try_from(name.as_str())
+                .with_context(|| format!("Invalid response header name '{}'", name))?;
+            if [
+                header::CONTENT_ENCODING,
+                header::CONTENT_LENGTH,
+                header::TRANSFER_ENCODING,
+            ]
+            .contains(&name)
+            {
+                bail!("Response header '{}' can't be configured", name);
+            }
+            let value = HeaderValue::try_from(value.as_str())
+                .with_context(|| format!("Invalid value of response header '{}'", name))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Returns the `*.toml` files in `dir` in lexical order.
+fn config_dir_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(dir)
+        .with_context(|| format!("Couldn't read config directory '{}'", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Couldn't read config directory '{}'", dir.display()))?;
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    files.sort();
+    Ok(files)
+}
+
+/// Merges `overlay` into `base`. Tables are merged recursively, any other
+/// value in `overlay` replaces the one in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn check_real_nix_store(real_nix_store: Option<&str>) -> Result<()> {
+    if let Some(real_nix_store) = real_nix_store {
+        // otherwise get_real_path would silently point into the void
+        if !Path::new(real_nix_store).is_dir() {
+            bail!(
+                "real_nix_store '{}' does not exist or is not a directory",
+                real_nix_store
+            );
+        }
+    }
+    Ok(())
+}
+
+fn read_token(kind: &str, path: &Path) -> Result<String> {
+    let token = read_to_string(path)
+        .with_context(|| format!("Couldn't read {} token from '{}'", kind, path.display()))?;
+    if token.trim().is_empty() {
+        bail!("{} token in '{}' is empty", kind, path.display());
+    }
+    Ok(token.trim().to_owned())
+}
+
+fn check_extra_narinfo_fields(fields: &BTreeMap<String, String>) -> Result<()> {
+    for (key, value) in fields {
+        if key.is_empty() || key.contains([':', '\n']) || value.contains('\n') {
+            bail!("Invalid extra narinfo field '{}: {}'", key, value);
+        }
+        if is_standard_field(key) {
+            bail!(
+                "Extra narinfo field '{}' would override a standard field",
+                key
+            );
+        }
+    }
+    Ok(())
 }
 
 pub(crate) fn load() -> Result<Config> {
     let settings_file = std::env::var("CONFIG_FILE").unwrap_or_else(|_| "settings.toml".to_owned());
 
-    let mut settings: Config = if Path::new(&settings_file).exists() {
-        toml::from_str(
-            &read_to_string(&settings_file)
-                .with_context(|| format!("Couldn't read config file '{settings_file}'"))?,
-        )
-        .with_context(|| format!("Couldn't parse config file '{settings_file}'"))?
+    let mut table = if Path::new(&settings_file).exists() {
+        read_config_file(Path::new(&settings_file))?
     } else {
-        Config::default()
+        toml::Table::new()
     };
+    if let Ok(config_dir) = std::env::var("CONFIG_DIR") {
+        for file in config_dir_files(Path::new(&config_dir))? {
+            merge_tables(&mut table, read_config_file(&file)?);
+        }
+    }
+    // go through serde, so that the field defaults apply
+    let mut settings: Config = toml::Value::Table(table)
+        .try_into()
+        .context("Couldn't parse configuration")?;
 
     if let Some(sign_key_path) = &settings.sign_key_path {
         log::warn!(
@@ -93,17 +706,369 @@ pub(crate) fn load() -> Result<Config> {
             settings.sign_key_paths.push(PathBuf::from(sign_key_path));
         }
     }
+    let mut secret_keys = Vec::new();
     for sign_key_path in &settings.sign_key_paths {
-        settings
-            .secret_keys
-            .push(parse_secret_key(sign_key_path).with_context(|| {
-                format!(
-                    "Couldn't parse secret key from '{}'",
-                    sign_key_path.display()
-                )
-            })?);
+        secret_keys.push(parse_secret_key(sign_key_path).with_context(|| {
+            format!(
+                "Couldn't parse secret key from '{}'",
+                sign_key_path.display()
+            )
+        })?);
+    }
+    if let Ok(sign_key) = std::env::var("SIGN_KEY") {
+        log::warn!("The SIGN_KEY environment variable is deprecated. Use SIGN_KEYS instead.");
+        secret_keys.push(
+            parse_secret_key_str(&sign_key).context("Couldn't parse secret key from SIGN_KEY")?,
+        );
+    }
+    if let Ok(sign_keys) = std::env::var("SIGN_KEYS") {
+        for sign_key in sign_keys.split_whitespace() {
+            secret_keys.push(
+                parse_secret_key_str(sign_key)
+                    .context("Couldn't parse secret key from SIGN_KEYS")?,
+            );
+        }
+    }
+    for secret_key in &secret_keys {
+        self_test_secret_key(secret_key)?;
+    }
+    if secret_keys.is_empty() && settings.sign_narinfos {
+        if settings.require_signatures {
+            log::warn!("No signing key is configured, narinfos of paths without signatures will fail with 500");
+        } else {
+            log::warn!("No signing key is configured, narinfos of paths without signatures are served unsigned and rejected by clients");
+        }
+    }
+    settings.secret_keys = ArcSwap::from_pointee(secret_keys);
+    settings.path_filter = PathFilter {
+        allow: if settings.allowed_paths.is_some() || settings.allowed_paths_file.is_some() {
+            Some(load_path_list(
+                settings.allowed_paths.as_deref().unwrap_or_default(),
+                settings.allowed_paths_file.as_deref(),
+            )?)
+        } else {
+            None
+        },
+        deny: load_path_list(
+            &settings.denied_paths,
+            settings.denied_paths_file.as_deref(),
+        )?,
+    };
+    settings.path_prefix = settings.path_prefix.trim_end_matches('/').to_owned();
+    if !settings.path_prefix.is_empty() && !settings.path_prefix.starts_with('/') {
+        bail!(
+            "path_prefix '{}' must start with a slash",
+            settings.path_prefix
+        );
+    }
+    settings.network_filter = NetworkFilter {
+        allowed: settings.allowed_networks.clone(),
+        trusted_proxies: settings.trusted_proxies.clone(),
+        exempt_health: settings.allowed_networks_exempt_health,
+        path_prefix: settings.path_prefix.clone(),
+    };
+    for public_key in &settings.trusted_public_keys {
+        settings.public_keys.push(
+            parse_public_key(public_key)
+                .with_context(|| format!("Couldn't parse public key '{}'", public_key))?,
+        );
+    }
+    if let Some(upload_token_path) = &settings.upload_token_path {
+        settings.upload_token = Some(read_token("upload", upload_token_path)?);
+    }
+    settings.uploads = PendingUploads::new(Duration::from_secs(settings.pending_upload_ttl));
+    if let Some(admin_token_path) = &settings.admin_token_path {
+        settings.admin_token = Some(read_token("admin", admin_token_path)?);
+    }
+    settings.default_headers = parse_response_headers(&settings.response_headers)?;
+    settings.maintenance_mode.set(settings.maintenance);
+    check_extra_narinfo_fields(&settings.extra_narinfo_fields)?;
+    if ![404, 200].contains(&settings.narinfo_missing_status) {
+        bail!(
+            "narinfo_missing_status must be 404 or 200, not {}",
+            settings.narinfo_missing_status
+        );
+    }
+    if settings.unix_socket_mode > 0o7777 {
+        bail!(
+            "unix_socket_mode {:o} is not a valid file mode",
+            settings.unix_socket_mode
+        );
+    }
+    if let Some(owner) = &settings.unix_socket_owner {
+        settings.unix_socket_uid = Some(lookup_uid(owner)?);
+    }
+    if let Some(group) = &settings.unix_socket_group {
+        settings.unix_socket_gid = Some(lookup_gid(group)?);
+    }
+    if let Some(bundle_path) = &settings.bundle_path {
+        settings.bundle = Some(Bundle::open(bundle_path)?);
+    }
+    // extensions are looked up in lower case and without the dot
+    settings.mime_types = std::mem::take(&mut settings.mime_types)
+        .into_iter()
+        .map(|(ext, mime_type)| (ext.trim_start_matches('.').to_lowercase(), mime_type))
+        .collect();
+    for mime_type in settings
+        .mime_types
+        .values()
+        .chain(settings.default_mime_type.iter())
+    {
+        if mime_type.parse::<mime::Mime>().is_err() {
+            bail!("Invalid MIME type '{}'", mime_type);
+        }
+    }
+    if let Some(upstream) = &settings.upstream {
+        settings.upstream_proxy = Some(Upstream::new(upstream)?);
+    }
+    if let Some(cache_dir) = &settings.cache_dir {
+        if settings.upstream.is_none() && !settings.buildlog_cache {
+            bail!("cache_dir is set, but neither upstream nor buildlog_cache to cache");
+        }
+        if settings.upstream.is_some() {
+            if settings.public_keys.is_empty() {
+                log::warn!(
+                    "no trusted_public_keys configured, nothing from upstream will be cached"
+                );
+            }
+            settings.upstream_cache = Some(UpstreamCache::new(cache_dir, settings.cache_max_size)?);
+        }
+        if settings.buildlog_cache {
+            settings.log_cache = Some(LogCache::new(
+                &cache_dir.join("logs"),
+                settings.buildlog_cache_max_size,
+            )?);
+        }
+    } else if settings.buildlog_cache {
+        bail!("buildlog_cache requires cache_dir");
     }
     let store_dir = std::env::var("NIX_STORE_DIR").unwrap_or(settings.virtual_nix_store.clone());
-    settings.store = Store::new(store_dir, settings.real_nix_store.clone());
+    if store_dir != settings.virtual_nix_store {
+        log::warn!(
+            "virtual_nix_store '{}' is overridden by NIX_STORE_DIR '{}'",
+            settings.virtual_nix_store,
+            store_dir
+        );
+    }
+    if settings.max_concurrent_nar_dumps == Some(0) {
+        bail!("max_concurrent_nar_dumps must be at least 1");
+    }
+    if settings.nar_channel_capacity == 0 {
+        bail!("nar_channel_capacity must be at least 1");
+    }
+    settings.metrics = Metrics::new(settings.slow_nar_log_threshold.map(Duration::from_secs))?;
+    settings.nar_dumps = Arc::new(
+        NarDumps::new(
+            settings.max_concurrent_nar_dumps,
+            Some(Duration::from_secs(settings.nar_dump_queue_timeout)).filter(|t| !t.is_zero()),
+        )
+        .with_metrics(settings.metrics.nar_dumps.clone())
+        .with_channel_capacity(settings.nar_channel_capacity),
+    );
+    if settings.root_redirect.is_some() && settings.root_html_path.is_some() {
+        bail!("root_redirect and root_html_path are mutually exclusive");
+    }
+    if let Some(root_html_path) = &settings.root_html_path {
+        if !root_html_path.is_file() {
+            bail!(
+                "root_html_path '{}' does not exist or is not a file",
+                root_html_path.display()
+            );
+        }
+    }
+    check_real_nix_store(settings.real_nix_store.as_deref())?;
+    let retry = RetryPolicy {
+        max_retries: settings.daemon_max_retries,
+        initial_backoff: Duration::from_millis(settings.daemon_retry_backoff_ms),
+        ..Default::default()
+    };
+    let timeout = Some(Duration::from_secs(settings.daemon_timeout)).filter(|t| !t.is_zero());
+    let path_info_cache = Some(Duration::from_secs(settings.path_info_cache_ttl))
+        .filter(|ttl| !ttl.is_zero() && settings.path_info_cache_size > 0);
+    settings.store = Store::new(store_dir, settings.real_nix_store.clone(), retry, timeout);
+    if let Some(ttl) = path_info_cache {
+        settings.store = settings
+            .store
+            .with_path_info_cache(ttl, settings.path_info_cache_size);
+    }
+    for (store_dir, store) in &settings.stores {
+        if !store_dir.starts_with('/') || store_dir.len() > 1 && store_dir.ends_with('/') {
+            bail!(
+                "store dir '{}' in stores must be an absolute path without trailing slash",
+                store_dir
+            );
+        }
+        check_real_nix_store(store.real_nix_store.as_deref())?;
+        let mut extra_store = Store::new(
+            store_dir.clone(),
+            store.real_nix_store.clone(),
+            retry,
+            timeout,
+        );
+        if let Some(daemon_socket) = &store.daemon_socket {
+            extra_store = extra_store.with_daemon_socket(daemon_socket.clone());
+        }
+        if let Some(ttl) = path_info_cache {
+            extra_store = extra_store.with_path_info_cache(ttl, settings.path_info_cache_size);
+        }
+        settings.extra_stores.insert(store_dir.clone(), extra_store);
+    }
     Ok(settings)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_dir() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        std::fs::write(
+            temp_dir.path().join("10-base.toml"),
+            "bind = \"[::]:5000\"\npriority = 30\ncompression = [\"zstd\"]\n",
+        )?;
+        std::fs::write(
+            temp_dir.path().join("20-override.toml"),
+            "priority = 50\ncompression = [\"xz\"]\n",
+        )?;
+        std::fs::write(temp_dir.path().join("README"), "not a config")?;
+
+        let files = config_dir_files(temp_dir.path())?;
+        assert_eq!(files.len(), 2);
+        let mut table = toml::Table::new();
+        for file in files {
+            merge_tables(&mut table, read_config_file(&file)?);
+        }
+        let config: Config = toml::Value::Table(table).try_into()?;
+        assert_eq!(config.bind, "[::]:5000");
+        assert_eq!(config.priority, 50);
+        assert_eq!(config.compression, vec![Compression::Xz]);
+        assert_eq!(config.workers, default_workers());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_extra_narinfo_fields() {
+        let fields = |key: &str| BTreeMap::from([(key.to_owned(), "value".to_owned())]);
+        assert!(check_extra_narinfo_fields(&fields("X-Team")).is_ok());
+        for key in [
+            "System",
+            "system",
+            "NarHash",
+            "nar_hash",
+            "STORE_PATH",
+            "Url",
+            "a:b",
+        ] {
+            assert!(check_extra_narinfo_fields(&fields(key)).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_merge_tables() -> Result<()> {
+        let mut base: toml::Table = toml::from_str("a = 1\n[t]\nx = 1\ny = 2\n")?;
+        merge_tables(&mut base, toml::from_str("b = 2\n[t]\ny = 3\n")?);
+        assert_eq!(
+            base,
+            toml::from_str::<toml::Table>("a = 1\nb = 2\n[t]\nx = 1\ny = 3\n")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_filter() -> Result<()> {
+        let hash = "26xbg1ndr7hbcncrlf9nhx5is2b25d13";
+        let other = "sl141d1g77wvhr050ah87lcyz2czdxa3";
+        assert_eq!(path_list_entry_hash(hash)?, hash);
+        assert_eq!(
+            path_list_entry_hash("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1")?,
+            hash
+        );
+        assert!(path_list_entry_hash("hello").is_err());
+
+        let filter = PathFilter {
+            allow: Some(load_path_list(&[hash.to_owned()], None)?),
+            deny: HashSet::new(),
+        };
+        assert!(filter.is_allowed(hash));
+        assert!(!filter.is_allowed(other));
+
+        let filter = PathFilter {
+            allow: None,
+            deny: load_path_list(&[other.to_owned()], None)?,
+        };
+        assert!(filter.is_allowed(hash));
+        assert!(!filter.is_allowed(other));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_ids() -> Result<()> {
+        assert_eq!(lookup_uid("1234")?, 1234);
+        assert_eq!(lookup_gid("1234")?, 1234);
+        assert_eq!(lookup_uid("root")?, 0);
+        assert!(lookup_uid("harmonia-no-such-user").is_err());
+        assert!(lookup_gid("harmonia-no-such-group").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_for() {
+        let mut config = Config {
+            store: Store::new("/nix/store".into(), None, Default::default(), None),
+            ..Default::default()
+        };
+        config.extra_stores.insert(
+            "/tenant/nix/store".into(),
+            Store::new("/tenant/nix/store".into(), None, Default::default(), None),
+        );
+        let store_dir = |header: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(header) = header {
+                req = req.insert_header((STORE_DIR_HEADER, header));
+            }
+            config
+                .store_for(&req.to_http_request())
+                .virtual_store()
+                .to_owned()
+        };
+        assert_eq!(store_dir(None), "/nix/store");
+        assert_eq!(store_dir(Some("/tenant/nix/store")), "/tenant/nix/store");
+        assert_eq!(store_dir(Some("/tenant/nix/store/")), "/tenant/nix/store");
+        assert_eq!(store_dir(Some("/unknown/nix/store")), "/nix/store");
+    }
+
+    #[test]
+    fn test_parse_response_headers() -> Result<()> {
+        let headers = parse_response_headers(&BTreeMap::from([
+            ("X-Content-Type-Options".into(), "nosniff".into()),
+            (
+                "Strict-Transport-Security".into(),
+                "max-age=31536000".into(),
+            ),
+        ]))?;
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    header::STRICT_TRANSPORT_SECURITY,
+                    HeaderValue::from_static("max-age=31536000")
+                ),
+                (
+                    header::X_CONTENT_TYPE_OPTIONS,
+                    HeaderValue::from_static("nosniff")
+                ),
+            ]
+        );
+        for (name, value) in [
+            ("Content-Encoding", "gzip"),
+            ("X Bad", "1"),
+            ("X-Ok", "a\nb"),
+        ] {
+            assert!(
+                parse_response_headers(&BTreeMap::from([(name.into(), value.into())])).is_err()
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,29 +1,144 @@
 use std::fmt;
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
 use std::str;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
 };
 
 const SOCKET_PATH: &str = "/nix/var/nix/daemon-socket/socket";
 
+/// Maximum number of requests written before their responses are read.
+const PIPELINE_BATCH_SIZE: usize = 64;
+
+/// How often and how patiently idempotent daemon operations are retried
+/// when the connection fails, e.g. while the daemon restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry.
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Only I/O failures are worth retrying; protocol errors such as a bad
+/// magic number or an error reported by the daemon would just repeat.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| cause.downcast_ref::<std::io::Error>().is_some())
+}
+
+/// Context of errors that persisted after retrying, i.e. the daemon can't be reached.
+#[derive(Debug)]
+pub(crate) struct DaemonUnavailable;
+
+impl std::fmt::Display for DaemonUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nix daemon unavailable")
+    }
+}
+
+/// Marks `err` as [`DaemonUnavailable`], unless it already is.
+fn unavailable(err: anyhow::Error) -> anyhow::Error {
+    if err.downcast_ref::<DaemonUnavailable>().is_some() {
+        err
+    } else {
+        err.context(DaemonUnavailable)
+    }
+}
+
+/// Runs a daemon operation, reconnecting with exponential backoff on transient failures.
+///
+/// Must only wrap operations that are safe to repeat from the start.
+macro_rules! with_retry {
+    ($self:ident, $op:expr) => {{
+        let mut attempt = 0;
+        loop {
+            match $op.await {
+                Err(e) if attempt < $self.retry.max_retries && is_transient(&e) => {
+                    let delay = $self.retry.backoff(attempt);
+                    log::warn!("daemon operation failed, retrying in {:?}: {:#}", delay, e);
+                    $self.socket = None;
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) if is_transient(&e) => break Err(unavailable(e)),
+                res => break res,
+            }
+        }
+    }};
+}
+
+/// Fails with [`DaemonUnavailable`] if `fut` doesn't complete within `timeout`,
+/// so that a hung daemon can't block a worker forever.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow!("no response within {:?}", timeout).context(DaemonUnavailable))
+            }),
+        None => fut.await,
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct DaemonConnection {
     socket: Option<UnixStream>,
+    retry: RetryPolicy,
+    /// Limit for every single read or write, `None` waits forever.
+    timeout: Option<Duration>,
+    /// Socket of the daemon, [`SOCKET_PATH`] if unset.
+    socket_path: Option<PathBuf>,
     #[allow(dead_code)]
     server_features: Vec<String>,
-    #[allow(dead_code)]
+    protocol_version: ProtocolVersion,
     daemon_version: String,
-    #[allow(dead_code)]
     is_trusted: bool,
 }
 
+/// What the daemon told us about itself during the handshake.
+#[derive(Debug, Serialize)]
+pub(crate) struct DaemonInfo {
+    /// Protocol version both sides agreed on, e.g. `1.38`.
+    pub(crate) protocol_version: String,
+    /// Nix version of the daemon.
+    pub(crate) version: String,
+    /// Whether the daemon treats harmonia as a trusted user.
+    pub(crate) trusted: bool,
+}
+
 const WORKER_MAGIC_1: u64 = 0x6e697863;
 const WORKER_MAGIC_2: u64 = 0x6478696f;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct ProtocolVersion {
     major: u8,
     minor: u8,
@@ -37,6 +152,12 @@ impl From<u64> for ProtocolVersion {
     }
 }
 
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
 impl From<ProtocolVersion> for u64 {
     fn from(ProtocolVersion { major, minor }: ProtocolVersion) -> Self {
         ((major as u64) << 8) | minor as u64
@@ -167,7 +288,7 @@ impl TryFrom<u64> for OpCode {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct ValidPathInfo {
     pub deriver: String,
     pub hash: String,
@@ -319,6 +440,7 @@ async fn write_string_list(socket: &mut UnixStream, list: &[String]) -> Result<(
 
 struct Handshake {
     server_features: Vec<String>,
+    protocol_version: ProtocolVersion,
     daemon_version: String,
     is_trusted: bool,
 }
@@ -371,6 +493,7 @@ async fn handshake(socket: &mut UnixStream) -> Result<Handshake> {
 
     Ok(Handshake {
         server_features,
+        protocol_version: ProtocolVersion::from(protocol_version).min(CLIENT_VERSION),
         daemon_version,
         is_trusted,
     })
@@ -458,25 +581,66 @@ async fn forward_stderr(socket: &mut UnixStream) -> Result<()> {
 }
 
 impl DaemonConnection {
+    pub(crate) fn new(retry: RetryPolicy, timeout: Option<Duration>) -> Self {
+        Self {
+            retry,
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Talks to the daemon listening on `socket_path` instead of the default one.
+    pub(crate) fn with_socket_path(mut self, socket_path: PathBuf) -> Self {
+        self.socket_path = Some(socket_path);
+        self
+    }
+
     async fn connect(&mut self) -> Result<&mut UnixStream> {
         if let Some(ref mut socket) = self.socket {
             Ok(socket)
         } else {
-            let mut socket = UnixStream::connect(SOCKET_PATH)
-                .await
-                .with_context(|| format!("Failed to reconnect to {}", SOCKET_PATH))?;
-            let data = handshake(&mut socket).await?;
+            let socket_path = self
+                .socket_path
+                .as_deref()
+                .unwrap_or(Path::new(SOCKET_PATH));
+            let (socket, data) = with_timeout(self.timeout, async {
+                // also for operations that aren't retried, e.g. while the daemon is down
+                let mut socket = UnixStream::connect(socket_path)
+                    .await
+                    .with_context(|| format!("Failed to reconnect to {}", socket_path.display()))
+                    .map_err(unavailable)?;
+                let data = handshake(&mut socket).await?;
+                Ok((socket, data))
+            })
+            .await?;
             self.socket = Some(socket);
             self.server_features = data.server_features;
+            self.protocol_version = data.protocol_version;
             self.daemon_version = data.daemon_version;
             self.is_trusted = data.is_trusted;
             Ok(self.socket.as_mut().unwrap())
         }
     }
 
+    /// Connects and performs the handshake, unless already connected.
+    pub(crate) async fn ensure_connected(&mut self) -> Result<()> {
+        self.connect().await.map(|_| ())
+    }
+
+    /// Connects if needed and returns what the daemon reported in the handshake.
+    pub(crate) async fn info(&mut self) -> Result<DaemonInfo> {
+        self.ensure_connected().await?;
+        Ok(DaemonInfo {
+            protocol_version: self.protocol_version.to_string(),
+            version: self.daemon_version.clone(),
+            trusted: self.is_trusted,
+        })
+    }
+
     async fn write_num<T: Into<u64>>(&mut self, num: T) -> Result<()> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        match write_num(socket, num).await {
+        match with_timeout(timeout, write_num(socket, num)).await {
             Err(e) => {
                 self.socket = None;
                 Err(e)
@@ -486,8 +650,9 @@ impl DaemonConnection {
     }
 
     pub async fn read_num<T: From<u64>>(&mut self) -> Result<T> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        match read_num(socket).await {
+        match with_timeout(timeout, read_num(socket)).await {
             Err(e) => {
                 self.socket = None;
                 Err(e)
@@ -497,8 +662,35 @@ impl DaemonConnection {
     }
 
     async fn write_string(&mut self, s: &str) -> Result<()> {
+        let timeout = self.timeout;
+        let socket = self.connect().await?;
+        if let Err(e) = with_timeout(timeout, write_string(socket, s)).await {
+            self.socket = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn write_string_list(&mut self, list: &[String]) -> Result<()> {
+        let timeout = self.timeout;
+        let socket = self.connect().await?;
+        if let Err(e) = with_timeout(timeout, write_string_list(socket, list)).await {
+            self.socket = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        if let Err(e) = write_string(socket, s).await {
+        let write = async {
+            socket
+                .write_all(bytes)
+                .await
+                .context("Failed to write bytes")
+        };
+        if let Err(e) = with_timeout(timeout, write).await {
             self.socket = None;
             return Err(e);
         }
@@ -506,8 +698,9 @@ impl DaemonConnection {
     }
 
     async fn read_string(&mut self) -> Result<String> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        match read_string(socket).await {
+        match with_timeout(timeout, read_string(socket)).await {
             Err(e) => {
                 self.socket = None;
                 Err(e)
@@ -517,8 +710,9 @@ impl DaemonConnection {
     }
 
     async fn read_string_list(&mut self) -> Result<Vec<String>> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        match read_string_list(socket).await {
+        match with_timeout(timeout, read_string_list(socket)).await {
             Err(e) => {
                 self.socket = None;
                 Err(e)
@@ -528,8 +722,9 @@ impl DaemonConnection {
     }
 
     pub async fn forward_stderr(&mut self) -> Result<()> {
+        let timeout = self.timeout;
         let socket = self.connect().await?;
-        if let Err(e) = forward_stderr(socket).await {
+        if let Err(e) = with_timeout(timeout, forward_stderr(socket)).await {
             self.socket = None;
             return Err(e);
         }
@@ -549,6 +744,10 @@ impl DaemonConnection {
 
     #[allow(dead_code)]
     pub(crate) async fn is_valid_path(&mut self, path: &str) -> Result<bool> {
+        with_retry!(self, self.is_valid_path_once(path))
+    }
+
+    async fn is_valid_path_once(&mut self, path: &str) -> Result<bool> {
         self.send_op(OpCode::IsValidPath)
             .await
             .context("Failed to send opcode")?;
@@ -571,6 +770,10 @@ impl DaemonConnection {
         &mut self,
         hash_part: &str,
     ) -> Result<Option<String>> {
+        with_retry!(self, self.query_path_from_hash_part_once(hash_part))
+    }
+
+    async fn query_path_from_hash_part_once(&mut self, hash_part: &str) -> Result<Option<String>> {
         self.send_op(OpCode::QueryPathFromHashPart)
             .await
             .context("Failed to send opcode")?;
@@ -593,8 +796,54 @@ impl DaemonConnection {
         }
     }
 
+    /// Resolves many hash parts at once.
+    ///
+    /// The requests are pipelined, i.e. all of a batch are written before the
+    /// responses are read, which saves a round trip per hash.
+    pub(crate) async fn query_paths_from_hash_parts(
+        &mut self,
+        parts: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        let mut paths = Vec::with_capacity(parts.len());
+        // bounded, so neither side blocks on a full socket buffer
+        for batch in parts.chunks(PIPELINE_BATCH_SIZE) {
+            paths.extend(with_retry!(
+                self,
+                self.query_paths_from_hash_parts_once(batch)
+            )?);
+        }
+        Ok(paths)
+    }
+
+    async fn query_paths_from_hash_parts_once(
+        &mut self,
+        parts: &[String],
+    ) -> Result<Vec<Option<String>>> {
+        for hash_part in parts {
+            self.send_op(OpCode::QueryPathFromHashPart)
+                .await
+                .context("Failed to send opcode")?;
+            self.write_string(hash_part)
+                .await
+                .context("Failed to write hash part")?;
+        }
+        let mut paths = Vec::with_capacity(parts.len());
+        for _ in parts {
+            self.forward_stderr()
+                .await
+                .context("Failed to forward stderr")?;
+            let resp = self.read_string().await.context("Failed to read path")?;
+            paths.push(Some(resp).filter(|p| !p.is_empty()));
+        }
+        Ok(paths)
+    }
+
     #[allow(dead_code)]
     pub(crate) async fn query_path_info(&mut self, path: &str) -> Result<QueryPathInfoResponse> {
+        with_retry!(self, self.query_path_info_once(path))
+    }
+
+    async fn query_path_info_once(&mut self, path: &str) -> Result<QueryPathInfoResponse> {
         self.send_op(OpCode::QueryPathInfo)
             .await
             .context("Failed to send opcode")?;
@@ -648,6 +897,191 @@ impl DaemonConnection {
             path: Some(path_info),
         })
     }
+
+    pub(crate) async fn query_all_valid_paths(&mut self) -> Result<Vec<String>> {
+        with_retry!(self, self.query_all_valid_paths_once())
+    }
+
+    async fn query_all_valid_paths_once(&mut self) -> Result<Vec<String>> {
+        self.send_op(OpCode::QueryAllValidPaths)
+            .await
+            .context("Failed to send opcode")?;
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")?;
+        self.read_string_list()
+            .await
+            .context("Failed to read paths")
+    }
+
+    /// Returns the valid derivations in the store that produce `path`.
+    pub(crate) async fn query_valid_derivers(&mut self, path: &str) -> Result<Vec<String>> {
+        with_retry!(self, self.query_valid_derivers_once(path))
+    }
+
+    async fn query_valid_derivers_once(&mut self, path: &str) -> Result<Vec<String>> {
+        self.send_op(OpCode::QueryValidDerivers)
+            .await
+            .context("Failed to send opcode")?;
+        self.write_string(path)
+            .await
+            .context("Failed to write path")?;
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")?;
+        self.read_string_list()
+            .await
+            .context("Failed to read derivers")
+    }
+
+    /// Returns the GC roots as pairs of the root (e.g. a symlink or
+    /// `{censored}` for roots of processes) and the store path it keeps alive.
+    pub(crate) async fn find_roots(&mut self) -> Result<Vec<(String, String)>> {
+        with_retry!(self, self.find_roots_once())
+    }
+
+    async fn find_roots_once(&mut self) -> Result<Vec<(String, String)>> {
+        self.send_op(OpCode::FindRoots)
+            .await
+            .context("Failed to send opcode")?;
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")?;
+        let count = self
+            .read_num::<u64>()
+            .await
+            .context("Failed to read number of roots")?;
+        let mut roots = Vec::new();
+        for _ in 0..count {
+            let link = self.read_string().await.context("Failed to read root")?;
+            let target = self
+                .read_string()
+                .await
+                .context("Failed to read root target")?;
+            roots.push((link, target));
+        }
+        Ok(roots)
+    }
+
+    /// Returns the realisations of a derivation output like `sha256:<hash>!out`,
+    /// as JSON documents.
+    pub(crate) async fn query_realisation(&mut self, output_id: &str) -> Result<Vec<String>> {
+        with_retry!(self, self.query_realisation_once(output_id))
+    }
+
+    async fn query_realisation_once(&mut self, output_id: &str) -> Result<Vec<String>> {
+        self.send_op(OpCode::QueryRealisation)
+            .await
+            .context("Failed to send opcode")?;
+        self.write_string(output_id)
+            .await
+            .context("Failed to write output id")?;
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")?;
+        // since protocol 1.31 the realisations are sent as JSON
+        self.read_string_list()
+            .await
+            .context("Failed to read realisations")
+    }
+
+    /// Adds signatures to a path in the store, so they are returned by
+    /// `query_path_info` from then on.
+    pub(crate) async fn add_signatures(&mut self, path: &str, sigs: &[String]) -> Result<()> {
+        self.send_op(OpCode::AddSignatures)
+            .await
+            .context("Failed to send opcode")?;
+        self.write_string(path)
+            .await
+            .context("Failed to write path")?;
+        self.write_string_list(sigs)
+            .await
+            .context("Failed to write sigs")?;
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")?;
+        self.read_num::<u64>()
+            .await
+            .context("Failed to read result")?;
+        Ok(())
+    }
+
+    /// Imports a NAR into the store.
+    ///
+    /// `info.hash` is the base16 encoded sha256 of the uncompressed NAR.
+    /// The NAR is streamed to the daemon in frames, so it never has to be
+    /// held in memory as a whole.
+    pub(crate) async fn add_to_store_nar<R: AsyncRead + Unpin>(
+        &mut self,
+        path: &str,
+        info: &ValidPathInfo,
+        mut nar: R,
+        repair: bool,
+        dont_check_sigs: bool,
+    ) -> Result<()> {
+        self.send_op(OpCode::AddToStoreNar)
+            .await
+            .context("Failed to send opcode")?;
+        self.write_string(path)
+            .await
+            .context("Failed to write path")?;
+        self.write_string(&info.deriver)
+            .await
+            .context("Failed to write deriver")?;
+        self.write_string(&info.hash)
+            .await
+            .context("Failed to write hash")?;
+        self.write_string_list(&info.references)
+            .await
+            .context("Failed to write references")?;
+        self.write_num(info.registration_time)
+            .await
+            .context("Failed to write registration time")?;
+        self.write_num(info.nar_size)
+            .await
+            .context("Failed to write nar size")?;
+        self.write_num(info.ultimate)
+            .await
+            .context("Failed to write ultimate")?;
+        self.write_string_list(&info.sigs)
+            .await
+            .context("Failed to write sigs")?;
+        self.write_string(info.content_address.as_deref().unwrap_or(""))
+            .await
+            .context("Failed to write content address")?;
+        self.write_num(repair)
+            .await
+            .context("Failed to write repair")?;
+        self.write_num(dont_check_sigs)
+            .await
+            .context("Failed to write dont_check_sigs")?;
+
+        // framed NAR: each frame is prefixed with its length, an empty frame ends the stream
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = match nar.read(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    // the daemon still waits for the rest of the frames
+                    self.socket = None;
+                    return Err(e).context("Failed to read nar");
+                }
+            };
+            self.write_num(n as u64)
+                .await
+                .context("Failed to write frame length")?;
+            if n == 0 {
+                break;
+            }
+            self.write_bytes(&buf[..n])
+                .await
+                .context("Failed to write frame")?;
+        }
+
+        self.forward_stderr()
+            .await
+            .context("Failed to forward stderr")
+    }
 }
 
 #[cfg(test)]
@@ -656,6 +1090,63 @@ mod test {
     use std::path::Path;
     use std::process::Command;
 
+    #[test]
+    fn test_retry_policy() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(retry.backoff(0), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(400));
+        assert_eq!(retry.backoff(4), Duration::from_secs(1));
+        assert_eq!(retry.backoff(100), Duration::from_secs(1));
+
+        let io_err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            .context("Failed to read magic 2");
+        assert!(is_transient(&io_err));
+        assert!(!is_transient(&anyhow::anyhow!("Invalid magic number: 42")));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let retry = RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        };
+        let mut conn =
+            DaemonConnection::new(retry, None).with_socket_path(dir.path().join("socket"));
+        let err = conn.ensure_connected().await.unwrap_err();
+        assert!(
+            err.downcast_ref::<DaemonUnavailable>().is_some(),
+            "{:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout() -> Result<()> {
+        // a daemon that accepts requests but never answers
+        let (client, _daemon) = UnixStream::pair()?;
+        let mut conn = DaemonConnection::new(Default::default(), Some(Duration::from_millis(50)));
+        conn.socket = Some(client);
+
+        let err = conn
+            .query_path_info("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1")
+            .await
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<DaemonUnavailable>().is_some(),
+            "{:#}",
+            err
+        );
+        // the connection is out of sync, so it must not be reused
+        assert!(conn.socket.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_nix_daemon() -> Result<()> {
         if !Path::new(SOCKET_PATH).exists() {
@@ -709,6 +1200,21 @@ mod test {
             .unwrap();
         assert_eq!(res, store_path);
 
+        let res = conn
+            .query_paths_from_hash_parts(&[
+                hash_part.to_owned(),
+                "00000000000000000000000000000000".to_owned(),
+            ])
+            .await
+            .context("Failed to resolve hash parts")
+            .unwrap();
+        assert_eq!(res, vec![Some(store_path), None]);
+
+        let roots = conn.find_roots().await.context("Failed to find roots")?;
+        assert!(roots
+            .iter()
+            .all(|(_, target)| target.starts_with("/nix/store/")));
+
         Ok(())
     }
 }
@@ -0,0 +1,620 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+
+use crate::signing::to_hex;
+use crate::store::Store;
+
+/// Extracts the `system` of a derivation in the ATerm format of `.drv` files,
+/// e.g. `Derive([outputs],[inputDrvs],[inputSrcs],"x86_64-linux",...)`.
+pub(crate) fn parse_system(drv: &str) -> Option<String> {
+    let (rest, skip) = if let Some(rest) = drv.strip_prefix("Derive(") {
+        (rest, 3)
+    } else if let Some(rest) = drv.strip_prefix("DrvWithVersion(") {
+        // the version comes first
+        (rest, 4)
+    } else {
+        return None;
+    };
+
+    let mut depth = 0;
+    let mut commas = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' if depth == 0 && commas == skip => return parse_string(&rest[i..]),
+            '"' => in_string = true,
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => commas += 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the quoted ATerm string at the beginning of `s`.
+fn parse_string(s: &str) -> Option<String> {
+    split_string(s).map(|(res, _)| res)
+}
+
+/// Parses the quoted ATerm string at the beginning of `s` and returns it
+/// along with the rest of `s`.
+fn split_string(s: &str) -> Option<(String, &str)> {
+    let mut res = String::new();
+    let mut chars = s.strip_prefix('"')?.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some((res, chars.as_str())),
+            '\\' => match chars.next()? {
+                'n' => res.push('\n'),
+                'r' => res.push('\r'),
+                't' => res.push('\t'),
+                c => res.push(c),
+            },
+            c => res.push(c),
+        }
+    }
+    None
+}
+
+/// Reads the `system` of the derivation at `drv_path`, if it is available.
+pub(crate) async fn read_system(drv_path: &Path) -> Option<String> {
+    let drv = tokio::fs::read_to_string(drv_path).await.ok()?;
+    parse_system(&drv)
+}
+
+#[derive(Debug, PartialEq)]
+struct DerivationOutput {
+    name: String,
+    /// Empty for outputs whose path is only known after building.
+    path: String,
+    /// Like `r:sha256`, empty for input-addressed outputs.
+    hash_algo: String,
+    /// Empty unless the output is fixed.
+    hash: String,
+}
+
+/// A derivation in the `Derive(...)` ATerm format of `.drv` files.
+#[derive(Debug, PartialEq)]
+struct Derivation {
+    outputs: Vec<DerivationOutput>,
+    input_drvs: Vec<(String, Vec<String>)>,
+    input_srcs: Vec<String>,
+    platform: String,
+    builder: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+}
+
+/// Consumes the ATerm at the beginning of a string.
+struct Parser<'a>(&'a str);
+
+impl Parser<'_> {
+    fn expect(&mut self, token: &str) -> Option<()> {
+        self.0 = self.0.strip_prefix(token)?;
+        Some(())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let (res, rest) = split_string(self.0)?;
+        self.0 = rest;
+        Some(res)
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        self.expect("[")?;
+        let mut res = vec![];
+        if self.expect("]").is_some() {
+            return Some(res);
+        }
+        loop {
+            res.push(item(self)?);
+            if self.expect("]").is_some() {
+                return Some(res);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        self.list(Self::string)
+    }
+
+    fn derivation(&mut self) -> Option<Derivation> {
+        self.expect("Derive(")?;
+        let outputs = self.list(|p| {
+            p.expect("(")?;
+            let name = p.string()?;
+            p.expect(",")?;
+            let path = p.string()?;
+            p.expect(",")?;
+            let hash_algo = p.string()?;
+            p.expect(",")?;
+            let hash = p.string()?;
+            p.expect(")")?;
+            Some(DerivationOutput {
+                name,
+                path,
+                hash_algo,
+                hash,
+            })
+        })?;
+        self.expect(",")?;
+        let input_drvs = self.list(|p| {
+            p.expect("(")?;
+            let path = p.string()?;
+            p.expect(",")?;
+            let outputs = p.strings()?;
+            p.expect(")")?;
+            Some((path, outputs))
+        })?;
+        self.expect(",")?;
+        let input_srcs = self.strings()?;
+        self.expect(",")?;
+        let platform = self.string()?;
+        self.expect(",")?;
+        let builder = self.string()?;
+        self.expect(",")?;
+        let args = self.strings()?;
+        self.expect(",")?;
+        let env = self.list(|p| {
+            p.expect("(")?;
+            let key = p.string()?;
+            p.expect(",")?;
+            let value = p.string()?;
+            p.expect(")")?;
+            Some((key, value))
+        })?;
+        self.expect(")")?;
+        Some(Derivation {
+            outputs,
+            input_drvs,
+            input_srcs,
+            platform,
+            builder,
+            args,
+            env,
+        })
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_strings<'a>(out: &mut String, strings: impl IntoIterator<Item = &'a str>) {
+    out.push('[');
+    for (i, s) in strings.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, s);
+    }
+    out.push(']');
+}
+
+impl Derivation {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parser = Parser(s);
+        let drv = parser.derivation()?;
+        parser.0.is_empty().then_some(drv)
+    }
+
+    fn is_fixed_output(&self) -> bool {
+        !self.outputs.is_empty() && self.outputs.iter().all(|o| !o.hash.is_empty())
+    }
+
+    /// Formats the derivation like Nix does for hashing it, with the input
+    /// derivations replaced by `input_drvs` and optionally without the paths
+    /// of the outputs.
+    fn unparse(
+        &self,
+        mask_outputs: bool,
+        input_drvs: &BTreeMap<String, BTreeSet<String>>,
+    ) -> String {
+        let mut out = String::from("Derive([");
+        for (i, output) in self.outputs.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('(');
+            write_string(&mut out, &output.name);
+            out.push(',');
+            write_string(&mut out, if mask_outputs { "" } else { &output.path });
+            out.push(',');
+            write_string(&mut out, &output.hash_algo);
+            out.push(',');
+            write_string(&mut out, &output.hash);
+            out.push(')');
+        }
+        out.push_str("],[");
+        for (i, (hash, outputs)) in input_drvs.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('(');
+            write_string(&mut out, hash);
+            out.push(',');
+            write_strings(&mut out, outputs.iter().map(String::as_str));
+            out.push(')');
+        }
+        out.push_str("],");
+        write_strings(&mut out, self.input_srcs.iter().map(String::as_str));
+        out.push(',');
+        write_string(&mut out, &self.platform);
+        out.push(',');
+        write_string(&mut out, &self.builder);
+        out.push(',');
+        write_strings(&mut out, self.args.iter().map(String::as_str));
+        out.push_str(",[");
+        for (i, (key, value)) in self.env.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let masked = mask_outputs && self.outputs.iter().any(|o| &o.name == key);
+            out.push('(');
+            write_string(&mut out, key);
+            out.push(',');
+            write_string(&mut out, if masked { "" } else { value });
+            out.push(')');
+        }
+        out.push_str("])");
+        out
+    }
+
+    /// The base16 hashes of the outputs modulo fixed-output derivations, like
+    /// `hashDerivationModulo` in Nix. Fixed-output derivations are hashed by
+    /// their output, so that changing how a source is fetched doesn't change
+    /// the hashes of everything depending on it.
+    async fn hash_modulo(
+        &self,
+        store: &Store,
+        mask_outputs: bool,
+    ) -> Result<BTreeMap<String, String>> {
+        if self.is_fixed_output() {
+            return Ok(self
+                .outputs
+                .iter()
+                .map(|o| {
+                    let fingerprint = format!("fixed:out:{}:{}:{}", o.hash_algo, o.hash, o.path);
+                    let hash = to_hex(&openssl::sha::sha256(fingerprint.as_bytes()));
+                    (o.name.clone(), hash)
+                })
+                .collect());
+        }
+        let mut input_drvs = BTreeMap::<String, BTreeSet<String>>::new();
+        for (drv_path, outputs) in &self.input_drvs {
+            // boxed, as input derivations are hashed recursively
+            let hashes = Box::pin(store.drv_hashes.get(store, drv_path)).await?;
+            for output in outputs {
+                let hash = hashes
+                    .get(output)
+                    .with_context(|| format!("{} has no output {}", drv_path, output))?;
+                input_drvs
+                    .entry(hash.clone())
+                    .or_default()
+                    .insert(output.clone());
+            }
+        }
+        let hash = to_hex(&openssl::sha::sha256(
+            self.unparse(mask_outputs, &input_drvs).as_bytes(),
+        ));
+        Ok(self
+            .outputs
+            .iter()
+            .map(|o| (o.name.clone(), hash.clone()))
+            .collect())
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.env
+            .iter()
+            .find(|(key, _)| key == "name")
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The output that `store_path` is of, also if its path is not fixed in
+    /// the derivation, like for floating content-addressed derivations.
+    fn output_of(&self, store_path: &str) -> Option<&str> {
+        if let Some(output) = self.outputs.iter().find(|o| o.path == store_path) {
+            return Some(&output.name);
+        }
+        let (_, path_name) = Path::new(store_path)
+            .file_name()?
+            .to_str()?
+            .split_once('-')?;
+        let output = match path_name.strip_prefix(self.name()?)? {
+            "" => "out",
+            suffix => suffix.strip_prefix('-')?,
+        };
+        self.outputs
+            .iter()
+            .find(|o| o.name == output)
+            .map(|o| o.name.as_str())
+    }
+}
+
+/// The hashes modulo of derivations by their path, which are needed
+/// recursively for all input derivations. Derivations never change, so
+/// entries stay valid until the derivation is garbage collected.
+#[derive(Debug, Default)]
+pub(crate) struct DrvHashes(Mutex<DrvHashEntries>);
+
+#[derive(Debug, Default)]
+struct DrvHashEntries {
+    hashes: HashMap<String, Arc<BTreeMap<String, String>>>,
+    /// Paths in the order they were added, to evict the oldest first.
+    order: VecDeque<String>,
+}
+
+/// Bounds the memory used for `DrvHashes`, a closure of e.g. a NixOS system
+/// has a few thousand derivations.
+const MAX_DRV_HASHES: usize = 100_000;
+
+async fn read_derivation(store: &Store, drv_path: &str) -> Result<Derivation> {
+    let real_path = store.get_real_path(Path::new(drv_path));
+    let drv = tokio::fs::read_to_string(&real_path)
+        .await
+        .with_context(|| format!("Failed to read {}", real_path.display()))?;
+    match Derivation::parse(&drv) {
+        Some(drv) => Ok(drv),
+        None => bail!("Failed to parse derivation {}", drv_path),
+    }
+}
+
+impl DrvHashes {
+    async fn get(&self, store: &Store, drv_path: &str) -> Result<Arc<BTreeMap<String, String>>> {
+        if let Some(hashes) = self.0.lock().unwrap().hashes.get(drv_path) {
+            return Ok(hashes.clone());
+        }
+        let drv = read_derivation(store, drv_path).await?;
+        let hashes = Arc::new(drv.hash_modulo(store, false).await?);
+        self.insert(drv_path, hashes.clone(), MAX_DRV_HASHES);
+        Ok(hashes)
+    }
+
+    fn insert(&self, drv_path: &str, hashes: Arc<BTreeMap<String, String>>, max_entries: usize) {
+        let mut entries = self.0.lock().unwrap();
+        if entries.hashes.contains_key(drv_path) {
+            return;
+        }
+        while entries.hashes.len() >= max_entries {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            entries.hashes.remove(&oldest);
+        }
+        entries.hashes.insert(drv_path.to_owned(), hashes);
+        entries.order.push_back(drv_path.to_owned());
+    }
+}
+
+/// The id under which Nix records the realisation of `store_path` when it
+/// was built by `deriver` with `ca-derivations`, like `sha256:<hash>!out`.
+pub(crate) async fn drv_output_id(
+    store: &Store,
+    deriver: &str,
+    store_path: &str,
+) -> Result<Option<String>> {
+    let drv = read_derivation(store, deriver).await?;
+    let Some(output) = drv.output_of(store_path) else {
+        return Ok(None);
+    };
+    let hashes = drv.hash_modulo(store, true).await?;
+    Ok(hashes
+        .get(output)
+        .map(|hash| format!("sha256:{}!{}", hash, output)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_system() {
+        let drv = r#"Derive([("out","/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1","","")],[("/nix/store/5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-bash-5.2.drv",["out"])],["/nix/store/sl141d1g77wvhr050ah87lcyz2czdxa3-builder \"quoted\", [x].sh"],"x86_64-linux","/bin/sh",["-e"],[("name","hello")])"#;
+        assert_eq!(parse_system(drv), Some("x86_64-linux".into()));
+        let drv = r#"DrvWithVersion("xp-dyn-drv",[("out","","r:sha256","")],[],[],"aarch64-darwin","/bin/sh",[],[])"#;
+        assert_eq!(parse_system(drv), Some("aarch64-darwin".into()));
+        assert_eq!(parse_system("not a derivation"), None);
+        assert_eq!(parse_system("Derive([],[],[]"), None);
+    }
+
+    const HELLO: &str = r#"Derive([("out","/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1","",""),("doc","/nix/store/6xbg1ndr7hbcncrlf9nhx5is2b25d13a-hello-2.12.1-doc","","")],[("/nix/store/5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-bash-5.2.drv",["out"])],["/nix/store/sl141d1g77wvhr050ah87lcyz2czdxa3-builder \"quoted\", [x].sh"],"x86_64-linux","/bin/sh",["-e","line\nbreak"],[("doc","/nix/store/6xbg1ndr7hbcncrlf9nhx5is2b25d13a-hello-2.12.1-doc"),("name","hello-2.12.1"),("out","/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1")])"#;
+
+    #[test]
+    fn test_parse_derivation() {
+        let drv = Derivation::parse(HELLO).unwrap();
+        assert_eq!(drv.outputs.len(), 2);
+        assert_eq!(drv.outputs[1].name, "doc");
+        assert_eq!(
+            drv.input_drvs,
+            vec![(
+                "/nix/store/5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-bash-5.2.drv".into(),
+                vec!["out".into()]
+            )]
+        );
+        assert_eq!(
+            drv.input_srcs,
+            vec!["/nix/store/sl141d1g77wvhr050ah87lcyz2czdxa3-builder \"quoted\", [x].sh"]
+        );
+        assert_eq!(drv.args, vec!["-e", "line\nbreak"]);
+        assert_eq!(drv.name(), Some("hello-2.12.1"));
+
+        // formatting with the original inputs gives back the file
+        let input_drvs = drv
+            .input_drvs
+            .iter()
+            .map(|(path, outputs)| (path.clone(), outputs.iter().cloned().collect()))
+            .collect();
+        assert_eq!(drv.unparse(false, &input_drvs), HELLO);
+        let masked = drv.unparse(true, &input_drvs);
+        assert!(masked.starts_with(r#"Derive([("out","","",""),("doc","","","")]"#));
+        assert!(masked.ends_with(r#"("name","hello-2.12.1"),("out","")])"#));
+
+        assert_eq!(Derivation::parse(&HELLO[..HELLO.len() - 1]), None);
+        assert_eq!(Derivation::parse(&format!("{}x", HELLO)), None);
+    }
+
+    #[test]
+    fn test_output_of() {
+        let drv = Derivation::parse(HELLO).unwrap();
+        assert_eq!(
+            drv.output_of("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1"),
+            Some("out")
+        );
+        // floating content-addressed outputs are matched by name
+        assert_eq!(
+            drv.output_of("/nix/store/0000000000000000000000000000000-hello-2.12.1-doc"),
+            Some("doc")
+        );
+        assert_eq!(
+            drv.output_of("/nix/store/0000000000000000000000000000000-hello-2.12.1-dev"),
+            None
+        );
+        assert_eq!(
+            drv.output_of("/nix/store/0000000000000000000000000000000-other"),
+            None
+        );
+    }
+
+    /// A derivation instantiated by Nix, from `derivation { name = "myname";
+    /// builder = "mybuilder"; system = "mysystem"; }` as in the Nix Pills.
+    const MYNAME_DRV_PATH: &str = "/nix/store/z3hhlxbckx4g3n9sw91nnvlkjvyw754p-myname.drv";
+    const MYNAME: &str =
+        include_str!("../tests/fixtures/z3hhlxbckx4g3n9sw91nnvlkjvyw754p-myname.drv");
+
+    /// Computes a store path like `makeStorePath` in Nix.
+    fn make_store_path(kind: &str, hash: &str, name: &str) -> Result<String> {
+        let digest =
+            openssl::sha::sha256(format!("{kind}:sha256:{hash}:/nix/store:{name}").as_bytes());
+        let mut compressed = [0u8; 20];
+        for (i, byte) in digest.iter().enumerate() {
+            compressed[i % 20] ^= byte;
+        }
+        Ok(format!(
+            "/nix/store/{}-{}",
+            crate::signing::convert_base16_to_nix32(&to_hex(&compressed))?,
+            name
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_hash_modulo() -> Result<()> {
+        // the fixture is what Nix wrote, its path is the hash of the contents
+        let contents_hash = to_hex(&openssl::sha::sha256(MYNAME.as_bytes()));
+        assert_eq!(
+            make_store_path("text", &contents_hash, "myname.drv")?,
+            MYNAME_DRV_PATH
+        );
+
+        // Nix derived the path of the input-addressed output from the hash
+        // modulo with the outputs masked
+        let drv = Derivation::parse(MYNAME).unwrap();
+        assert_eq!(drv.unparse(false, &BTreeMap::new()), MYNAME);
+        let hashes = drv.hash_modulo(&Store::default(), true).await?;
+        assert_eq!(
+            make_store_path("output:out", &hashes["out"], "myname")?,
+            drv.outputs[0].path
+        );
+        assert_eq!(
+            drv.outputs[0].path,
+            "/nix/store/40s0qmrfb45vlh6610rk29ym318dswdr-myname"
+        );
+
+        let fixed = Derivation::parse(
+            r#"Derive([("out","/nix/store/0m48ddc48jcpmsyvs5dwn2w7vzd2ivw1-source","r:sha256","0a53d7b3b1c0e7d6a1d2d3c4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718")],[],[],"builtin","builtin:fetchurl",[],[("name","source"),("out","/nix/store/0m48ddc48jcpmsyvs5dwn2w7vzd2ivw1-source")])"#,
+        )
+        .unwrap();
+        let hashes = fixed.hash_modulo(&Store::default(), true).await?;
+        let expected = to_hex(&openssl::sha::sha256(
+            b"fixed:out:r:sha256:0a53d7b3b1c0e7d6a1d2d3c4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718:/nix/store/0m48ddc48jcpmsyvs5dwn2w7vzd2ivw1-source",
+        ));
+        assert_eq!(hashes["out"], expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drv_hashes_eviction() {
+        let hashes = DrvHashes::default();
+        for path in ["a.drv", "b.drv", "c.drv"] {
+            hashes.insert(path, Default::default(), 2);
+        }
+        let entries = hashes.0.lock().unwrap();
+        // only the oldest entry made room
+        assert!(!entries.hashes.contains_key("a.drv"));
+        assert!(entries.hashes.contains_key("b.drv") && entries.hashes.contains_key("c.drv"));
+    }
+
+    #[tokio::test]
+    async fn test_drv_output_id() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = Store::new(
+            "/nix/store".into(),
+            Some(dir.path().to_str().unwrap().into()),
+            Default::default(),
+            None,
+        );
+        std::fs::write(
+            dir.path()
+                .join("z3hhlxbckx4g3n9sw91nnvlkjvyw754p-myname.drv"),
+            MYNAME,
+        )?;
+        let dependent = HELLO.replace(
+            "/nix/store/5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-bash-5.2.drv",
+            MYNAME_DRV_PATH,
+        );
+        std::fs::write(dir.path().join("hello.drv"), &dependent)?;
+
+        let id = drv_output_id(
+            &store,
+            "/nix/store/hello.drv",
+            "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1-doc",
+        )
+        .await?
+        .context("no output id")?;
+        assert!(id.starts_with("sha256:") && id.ends_with("!doc"));
+        // inputs are hashed without masking their outputs, which for an
+        // input-addressed derivation is the hash of its file
+        let cached = store
+            .drv_hashes
+            .0
+            .lock()
+            .unwrap()
+            .hashes
+            .get(MYNAME_DRV_PATH)
+            .cloned()
+            .context("input derivation not cached")?;
+        assert_eq!(
+            cached["out"],
+            to_hex(&openssl::sha::sha256(MYNAME.as_bytes()))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use actix_web::{web, HttpResponse};
+
+use crate::config::Config;
+use crate::{cache_control_max_age_1d, nixhash, some_or_404, ServerResult};
+
+/// Lists the valid derivations in the store that produce the store path of `hash`.
+pub(crate) async fn get(hash: web::Path<String>, settings: web::Data<Config>) -> ServerResult {
+    let store_path = some_or_404!(nixhash(&settings, &hash).await);
+    let derivers = settings
+        .store
+        .daemon
+        .lock()
+        .await
+        .query_valid_derivers(&store_path)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_max_age_1d())
+        .json(derivers))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_store::TempStore;
+    use actix_web::body::MessageBody;
+    use actix_web::http;
+
+    #[tokio::test]
+    async fn test_not_a_hash() -> Result<(), crate::ServerError> {
+        let res = get(
+            web::Path::from("not-a-hash".to_owned()),
+            web::Data::new(Config::default()),
+        )
+        .await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    /// A path added to the store, rather than built, has no derivers.
+    #[tokio::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_no_derivers() -> anyhow::Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file("harmonia-test.txt", b"hello harmonia")?;
+        let res = get(
+            web::Path::from(store_path["/nix/store/".len()..][..32].to_owned()),
+            web::Data::new(Config {
+                store: temp_store.store(),
+                ..Default::default()
+            }),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let body = res
+            .into_body()
+            .try_into_bytes()
+            .map_err(|_| anyhow::anyhow!("streamed body"))?;
+        assert_eq!(
+            serde_json::from_slice::<Vec<String>>(&body)?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use actix_web::{web, HttpResponse};
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::{cache_control_no_store, ServerResult};
+
+#[derive(Debug, Serialize)]
+struct DiskSpace {
+    /// Bytes that unprivileged processes, like builds, can still use.
+    available_bytes: u64,
+    total_bytes: u64,
+}
+
+fn statvfs(path: &Path) -> Result<DiskSpace> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path {}", path.display()))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to statvfs {}", path.display()));
+    }
+    Ok(DiskSpace {
+        available_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64,
+    })
+}
+
+/// Reports the free space of the filesystem of the store, with 503 if it is
+/// below `min_free_disk_space`, so that monitoring notices before builds and
+/// garbage collection fail.
+pub(crate) async fn get(settings: web::Data<Config>) -> ServerResult {
+    let space = statvfs(settings.store.real_store())?;
+    let mut res = if settings
+        .min_free_disk_space
+        .is_some_and(|min| space.available_bytes < min)
+    {
+        HttpResponse::ServiceUnavailable()
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(res.insert_header(cache_control_no_store()).json(space))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http;
+
+    #[tokio::test]
+    async fn test_get() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let space = statvfs(dir.path())?;
+        assert!(space.available_bytes <= space.total_bytes);
+        assert!(statvfs(&dir.path().join("missing")).is_err());
+
+        let settings = |min_free_disk_space| {
+            web::Data::new(Config {
+                store: crate::store::Store::new(
+                    dir.path().to_str().unwrap().into(),
+                    None,
+                    Default::default(),
+                    None,
+                ),
+                min_free_disk_space,
+                ..Default::default()
+            })
+        };
+        let res = get(settings(None)).await.map_err(|e| e.err)?;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let body = actix_web::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let json: serde_json::Value = serde_json::from_slice(&body)?;
+        assert!(json["available_bytes"].is_u64());
+
+        let res = get(settings(Some(u64::MAX))).await.map_err(|e| e.err)?;
+        assert_eq!(res.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+}
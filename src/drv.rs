@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::{cache_control_max_age_1y, nixhash, some_or_404, ServerResult};
+
+/// Serves the `.drv` file of the derivation `drv` in the ATerm format, e.g. to
+/// debug a build next to its log from `/log/<drv>`. `drv` is either the hash or
+/// the full name of the derivation.
+pub(crate) async fn get(
+    drv: web::Path<String>,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    let hash = drv.get(..32).unwrap_or(&drv);
+    let drv_path = some_or_404!(nixhash(&settings, hash)
+        .await
+        .filter(|path| path.ends_with(".drv")));
+    let real_path = settings.store.get_real_path(Path::new(&drv_path));
+
+    let file = NamedFile::open_async(&real_path)
+        .await
+        .with_context(|| format!("Failed to open {}", real_path.display()))?
+        .disable_content_disposition()
+        .set_content_type(mime::TEXT_PLAIN_UTF_8)
+        // store paths are immutable
+        .customize()
+        .insert_header(cache_control_max_age_1y());
+    Ok(file.respond_to(&req).map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http, test::TestRequest};
+
+    #[tokio::test]
+    async fn test_not_a_hash() -> Result<(), crate::ServerError> {
+        let res = get(
+            web::Path::from("not-a-hash".to_owned()),
+            TestRequest::default().to_http_request(),
+            web::Data::new(Config::default()),
+        )
+        .await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
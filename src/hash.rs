@@ -0,0 +1,93 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::nar::{nar_hash, nar_hash_matches};
+use crate::signing::{convert_base16_to_nix32, to_hex};
+use crate::upload::check_admin_auth;
+use crate::{cache_control_no_store, nixhash, some_or_404, ServerResult};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HashCheck {
+    path: String,
+    /// As recorded by the daemon.
+    nar_hash: String,
+    /// Of the NAR dumped from the store just now.
+    computed_nar_hash: String,
+    #[serde(rename = "match")]
+    matches: bool,
+}
+
+/// Dumps the NAR of a store path into a hasher instead of sending it, and
+/// compares the hash with the one recorded by the daemon, to detect store
+/// corruption remotely. Requires the admin token, since it reads the whole
+/// store path.
+pub(crate) async fn get(
+    hash: web::Path<String>,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    if let Some(res) = check_admin_auth(&req, &settings) {
+        return Ok(res);
+    }
+    let store_path = some_or_404!(nixhash(&settings, &hash).await);
+    let info = some_or_404!(settings.store.query_path_info(&store_path).await?);
+    let digest = nar_hash(settings.store.get_real_path(store_path.as_ref()))
+        .await
+        .with_context(|| format!("Failed to dump {}", store_path))?;
+    let nix32 = |hex: &str| -> anyhow::Result<String> {
+        Ok(format!(
+            "sha256:{}",
+            convert_base16_to_nix32(hex).context("failed to convert hash")?
+        ))
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(HashCheck {
+            nar_hash: nix32(&info.hash)?,
+            computed_nar_hash: nix32(&to_hex(&digest))?,
+            matches: nar_hash_matches(&digest, &info.hash),
+            path: store_path,
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http, test::TestRequest};
+
+    #[test]
+    fn test_hash_check_json() -> anyhow::Result<()> {
+        let json = serde_json::to_value(HashCheck {
+            path: "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into(),
+            nar_hash: "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into(),
+            computed_nar_hash: "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into(),
+            matches: true,
+        })?;
+        assert_eq!(json["match"], true);
+        assert_eq!(
+            json["computedNarHash"],
+            "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> Result<(), crate::ServerError> {
+        let req = TestRequest::default().to_http_request();
+        let hash = || web::Path::from("26xbg1ndr7hbcncrlf9nhx5is2b25d13".to_owned());
+        let res = get(hash(), req.clone(), web::Data::new(Config::default())).await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+
+        let settings = web::Data::new(Config {
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        });
+        let res = get(hash(), req, settings).await?;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::daemon::ValidPathInfo;
+use crate::signing::convert_base16_to_nix32;
+use crate::{cache_control_max_age_1d, nixhash, some_or_404, ServerResult};
+
+/// The path info as returned by the daemon.
+///
+/// Unlike a narinfo, references are full store paths and the registration time is included.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfo {
+    path: String,
+    deriver: Option<String>,
+    nar_hash: String,
+    references: Vec<String>,
+    registration_time: u64,
+    nar_size: u64,
+    ultimate: bool,
+    sigs: Vec<String>,
+    ca: Option<String>,
+}
+
+impl PathInfo {
+    fn new(path: String, info: ValidPathInfo) -> anyhow::Result<Self> {
+        let nar_hash =
+            convert_base16_to_nix32(&info.hash).context("failed to convert path info hash")?;
+        Ok(Self {
+            path,
+            deriver: Some(info.deriver).filter(|d| !d.is_empty()),
+            nar_hash: format!("sha256:{}", nar_hash),
+            references: info.references,
+            registration_time: info.registration_time,
+            nar_size: info.nar_size,
+            ultimate: info.ultimate,
+            sigs: info.sigs,
+            ca: info.content_address,
+        })
+    }
+}
+
+pub(crate) async fn get(hash: web::Path<String>, settings: web::Data<Config>) -> ServerResult {
+    let store_path = some_or_404!(nixhash(&settings, &hash).await);
+    let info = some_or_404!(settings.store.query_path_info(&store_path).await?);
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_max_age_1d())
+        .json(PathInfo::new(store_path, info)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http;
+
+    #[test]
+    fn test_path_info() -> anyhow::Result<()> {
+        let info = ValidPathInfo {
+            deriver: String::new(),
+            hash: "7a9bd8e9e1c6f9e3c5e8e0e9c0a0b8e0d0c1b2a3f4e5d6c7b8a9f0e1d2c3b4a5".into(),
+            references: vec!["/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into()],
+            registration_time: 1700000000,
+            nar_size: 226560,
+            ultimate: true,
+            sigs: vec![],
+            content_address: None,
+        };
+        let path = "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".to_owned();
+        let json = serde_json::to_value(PathInfo::new(path.clone(), info.clone())?)?;
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "path": path,
+                "deriver": null,
+                "narHash": format!("sha256:{}", convert_base16_to_nix32(&info.hash)?),
+                "references": info.references,
+                "registrationTime": 1700000000,
+                "narSize": 226560,
+                "ultimate": true,
+                "sigs": [],
+                "ca": null,
+            })
+        );
+
+        let deriver = "/nix/store/qnavcbp5ydyd12asgz7rpr7is7hlswaz-hello-2.12.1.drv";
+        let info = ValidPathInfo {
+            deriver: deriver.into(),
+            ..info
+        };
+        let json = serde_json::to_value(PathInfo::new(path, info)?)?;
+        assert_eq!(json["deriver"], deriver);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_a_hash() -> Result<(), crate::ServerError> {
+        let res = get(
+            web::Path::from("not-a-hash".to_owned()),
+            web::Data::new(Config::default()),
+        )
+        .await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+
+/// Number of open files harmonia should be allowed, `min_open_files` or
+/// enough for every worker to dump `max_concurrent_nar_dumps` NARs.
+fn required_open_files(config: &Config) -> Option<u64> {
+    config.min_open_files.or_else(|| {
+        config
+            .max_concurrent_nar_dumps
+            .map(|dumps| (config.workers * dumps) as u64)
+    })
+}
+
+fn open_files_limit() -> Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to get RLIMIT_NOFILE");
+    }
+    Ok(limit)
+}
+
+/// Raises the soft limit of open files to what the configuration needs, as
+/// far as the hard limit allows, and warns if that isn't enough. Running out
+/// of file descriptors during a traffic spike fails requests in confusing ways.
+pub(crate) fn check_open_files_limit(config: &Config) -> Result<()> {
+    let Some(required) = required_open_files(config) else {
+        return Ok(());
+    };
+    let mut limit = open_files_limit()?;
+    if limit.rlim_cur as u64 >= required {
+        return Ok(());
+    }
+    let raised = libc::rlimit {
+        rlim_cur: (required as libc::rlim_t).min(limit.rlim_max),
+        rlim_max: limit.rlim_max,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+        log::info!(
+            "raised the limit of open files from {} to {}",
+            limit.rlim_cur,
+            raised.rlim_cur
+        );
+        limit = raised;
+    }
+    if (limit.rlim_cur as u64) < required {
+        log::warn!(
+            "the limit of open files is {}, but {} are recommended for this configuration; \
+             requests may fail with \"too many open files\" under load",
+            limit.rlim_cur,
+            required
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_required_open_files() {
+        assert_eq!(required_open_files(&Config::default()), None);
+        let config = Config {
+            workers: 4,
+            max_concurrent_nar_dumps: Some(64),
+            ..Default::default()
+        };
+        assert_eq!(required_open_files(&config), Some(256));
+        let config = Config {
+            min_open_files: Some(65536),
+            ..config
+        };
+        assert_eq!(required_open_files(&config), Some(65536));
+    }
+
+    #[test]
+    fn test_check_open_files_limit() -> Result<()> {
+        let limit = open_files_limit()?;
+        let config = Config {
+            min_open_files: Some(limit.rlim_cur as u64),
+            ..Default::default()
+        };
+        check_open_files_limit(&config)?;
+        assert_eq!(open_files_limit()?.rlim_cur, limit.rlim_cur);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_compression::tokio::bufread::BzDecoder;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+/// Prefix of files that are still being decompressed.
+const TMP_PREFIX: &str = ".tmp";
+
+/// Keeps decompressed copies of bzip2 compressed build logs on disk, so that
+/// logs viewed often are only decompressed once. Entries are keyed by the
+/// hash of the derivation and carry the modification time of the compressed
+/// log, so that they are decompressed again if it changes. The logs of the
+/// oldest builds are evicted once `max_size` is exceeded.
+#[derive(Debug, Clone)]
+pub(crate) struct LogCache {
+    dir: PathBuf,
+    max_size: u64,
+    evicting: Arc<Mutex<()>>,
+}
+
+impl LogCache {
+    pub(crate) fn new(dir: &Path, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            max_size,
+            evicting: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Removes the temporary files left behind by interrupted decompressions,
+    /// must only run at startup.
+    pub(crate) fn remove_stale_tmp(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read '{}'", self.dir.display()))?
+        {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                std::fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to remove '{}'", entry.path().display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of the decompressed copy of the compressed build log
+    /// `log` of the derivation with `hash`, decompressing it first if needed.
+    pub(crate) async fn decompressed(&self, hash: &str, log: &Path) -> Result<PathBuf> {
+        let modified = tokio::fs::metadata(log)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to stat build log '{}'", log.display()))?;
+        let path = self.dir.join(format!("{}.log", hash));
+        let cached = tokio::fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified());
+        if cached.is_ok_and(|cached| cached == modified) {
+            return Ok(path);
+        }
+
+        let tmp = tempfile::Builder::new()
+            .prefix(TMP_PREFIX)
+            .tempfile_in(&self.dir)
+            .context("Failed to create temporary file")?;
+        let mut file = tokio::fs::File::from_std(tmp.as_file().try_clone()?);
+        let source = tokio::fs::File::open(log)
+            .await
+            .with_context(|| format!("Failed to open build log '{}'", log.display()))?;
+        tokio::io::copy(&mut BzDecoder::new(BufReader::new(source)), &mut file)
+            .await
+            .with_context(|| format!("Failed to decompress build log '{}'", log.display()))?;
+        file.flush().await?;
+        // the modification time tells whether the copy is still current
+        tmp.as_file()
+            .set_modified(modified)
+            .with_context(|| format!("Failed to set mtime of {}", tmp.path().display()))?;
+        tmp.persist(&path)
+            .context("Failed to cache decompressed build log")?;
+        self.evict(&path).await?;
+        Ok(path)
+    }
+
+    /// Removes the logs of the oldest builds until the cache fits into
+    /// `max_size`, except for `keep`, which is about to be served.
+    async fn evict(&self, keep: &Path) -> Result<()> {
+        let _guard = self.evicting.lock().await;
+        let mut logs = vec![];
+        let mut total = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "log") {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            total += metadata.len();
+            logs.push((metadata.modified()?, metadata.len(), path));
+        }
+        logs.sort();
+
+        for (_, len, path) in logs {
+            if total <= self.max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            log::info!("evicting {} from the build log cache", path.display());
+            tokio::fs::remove_file(&path).await?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_compression::tokio::write::BzEncoder;
+    use std::time::{Duration, SystemTime};
+
+    async fn write_log(path: &Path, content: &[u8], modified: SystemTime) -> Result<()> {
+        let mut encoder = BzEncoder::new(Vec::new());
+        encoder.write_all(content).await?;
+        encoder.shutdown().await?;
+        std::fs::write(path, encoder.into_inner())?;
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decompressed() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = LogCache::new(&temp_dir.path().join("logs"), 1024)?;
+        let log = temp_dir.path().join("hello.drv.bz2");
+        let built = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        write_log(&log, b"building hello\n", built).await?;
+
+        let hash = "5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6";
+        let path = cache.decompressed(hash, &log).await?;
+        assert_eq!(std::fs::read(&path)?, b"building hello\n");
+
+        // served from the cache while the log is unchanged
+        std::fs::write(&path, b"cached\n")?;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(built)?;
+        assert_eq!(
+            std::fs::read(cache.decompressed(hash, &log).await?)?,
+            b"cached\n"
+        );
+
+        // and decompressed again once it changed
+        write_log(&log, b"rebuilding hello\n", built + Duration::from_secs(1)).await?;
+        assert_eq!(
+            std::fs::read(cache.decompressed(hash, &log).await?)?,
+            b"rebuilding hello\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = LogCache::new(&temp_dir.path().join("logs"), 1500)?;
+        let built = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut paths = vec![];
+        for (i, hash) in ["a", "b", "c"].into_iter().enumerate() {
+            let log = temp_dir.path().join(format!("{}.drv.bz2", hash));
+            write_log(&log, &[b'x'; 600], built + Duration::from_secs(i as u64)).await?;
+            paths.push(cache.decompressed(hash, &log).await?);
+        }
+        // the log of the oldest build went first
+        assert!(!paths[0].exists());
+        assert!(paths[1].exists() && paths[2].exists());
+
+        // a log larger than the cache is still served
+        let log = temp_dir.path().join("d.drv.bz2");
+        write_log(&log, &[b'x'; 2000], built).await?;
+        assert!(cache.decompressed("d", &log).await?.exists());
+        Ok(())
+    }
+}
@@ -1,61 +1,100 @@
 #![warn(clippy::dbg_macro)]
 
+use actix_web::dev::Service;
 use actix_web::middleware;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand};
 use config::Config;
+use std::ffi::CString;
 use std::fs;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{fmt::Display, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
 use url::Url;
 
-use actix_web::{http, web, App, HttpResponse, HttpServer};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_web::{http, http::KeepAlive, web, App, HttpResponse, HttpServer};
+use openssl::ssl::{
+    AlpnError, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+};
 
+mod access;
+mod admin;
+mod assets;
 mod buildlog;
+mod bundle;
 mod cacheinfo;
+mod check;
+mod compression;
 mod config;
 mod daemon;
+mod derivation;
+mod derivers;
+mod diskspace;
+mod drv;
+mod hash;
 mod health;
+mod info;
+mod limits;
+mod log_cache;
+mod manifest;
+mod metrics;
 mod nar;
 mod narinfo;
 mod narlist;
+mod path_info_cache;
+mod readiness;
+mod realisation;
+mod resign;
+mod resolve;
 mod root;
+mod roots;
+mod routes;
 mod serve;
 mod signing;
 mod store;
+mod store_paths;
+#[cfg(test)]
+mod test_store;
+mod upload;
+mod upstream;
+mod upstream_cache;
+mod verify;
 mod version;
 
 async fn nixhash(settings: &web::Data<Config>, hash: &str) -> Option<String> {
-    if hash.len() != 32 {
-        return None;
+    try_nixhash(settings, &settings.store, hash)
+        .await
+        .unwrap_or(None)
+}
+
+/// Like [`nixhash`], but fails if the daemon doesn't answer instead of
+/// treating the path as missing.
+async fn try_nixhash(
+    settings: &web::Data<Config>,
+    store: &store::Store,
+    hash: &str,
+) -> Result<Option<String>> {
+    if hash.len() != 32 || !settings.path_filter.is_allowed(hash) {
+        return Ok(None);
     }
-    settings
-        .store
+    store
         .daemon
         .lock()
         .await
         .query_path_from_hash_part(hash)
         .await
-        .unwrap_or(None)
 }
 
-const BOOTSTRAP_SOURCE: &str = r#"
-  <link href="https://cdn.jsdelivr.net/npm/bootstrap@5.2.3/dist/css/bootstrap.min.css"
-        rel="stylesheet"
-        integrity="sha384-rbsA2VBKQhggwzxH7pPCaAqO46MgnOM80zW1RWuH61DGLwZJEdK2Kadq2F9CUG65"
-         crossorigin="anonymous">
-  <script src="https://cdn.jsdelivr.net/npm/bootstrap@5.2.3/dist/js/bootstrap.bundle.min.js"
-          integrity="sha384-kenU1KFdBIe4zVF0s0G1M5b4hcpxyD9F7jL+jjXkk+Q2h455rYXK/7HAuoJl+0I4"
-          crossorigin="anonymous"></script>
-"#;
-
 const CARGO_NAME: &str = env!("CARGO_PKG_NAME");
 const CARGO_VERSION: &str = env!("CARGO_PKG_VERSION");
 const CARGO_HOME_PAGE: &str = env!("CARGO_PKG_HOMEPAGE");
-const NIXBASE32_ALPHABET: &str = "0123456789abcdfghijklmnpqrsvwxyz";
 
 fn cache_control_max_age(max_age: u32) -> http::header::CacheControl {
     http::header::CacheControl(vec![http::header::CacheDirective::MaxAge(max_age)])
@@ -102,7 +141,57 @@ impl Display for ServerError {
     }
 }
 
-impl actix_web::error::ResponseError for ServerError {}
+impl ServerError {
+    fn status(&self) -> http::StatusCode {
+        if self
+            .err
+            .downcast_ref::<daemon::DaemonUnavailable>()
+            .is_some()
+        {
+            return http::StatusCode::SERVICE_UNAVAILABLE;
+        }
+        let not_found = self.err.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+        });
+        if not_found {
+            http::StatusCode::NOT_FOUND
+        } else {
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Seconds after which clients may retry when the daemon is unavailable,
+/// e.g. while it restarts.
+const DAEMON_RETRY_AFTER: u32 = 5;
+
+/// Set from `hide_error_details`, since `error_response` has no access to the config.
+static HIDE_ERROR_DETAILS: AtomicBool = AtomicBool::new(false);
+
+impl actix_web::error::ResponseError for ServerError {
+    fn status_code(&self) -> http::StatusCode {
+        self.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let mut res = HttpResponse::build(status);
+        res.insert_header(cache_control_no_store())
+            .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8));
+        if status == http::StatusCode::SERVICE_UNAVAILABLE {
+            res.insert_header((http::header::RETRY_AFTER, DAEMON_RETRY_AFTER));
+        }
+        if HIDE_ERROR_DETAILS.load(Ordering::Relaxed) {
+            // the details may contain store paths or other internals
+            log::error!("{}", self);
+            res.body(status.canonical_reason().unwrap_or("Error"))
+        } else {
+            res.body(self.to_string())
+        }
+    }
+}
 
 impl From<anyhow::Error> for ServerError {
     fn from(err: anyhow::Error) -> ServerError {
@@ -112,51 +201,156 @@ impl From<anyhow::Error> for ServerError {
 
 type ServerResult = Result<HttpResponse, ServerError>;
 
+/// Nix binary cache. Serves the local nix store over http unless a command is given.
+///
+/// The configuration is read from the file in the CONFIG_FILE environment variable.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sign store paths with the configured keys and persist the signatures in the store
+    Resign {
+        /// Store paths to sign, all servable paths if omitted
+        paths: Vec<String>,
+    },
+    /// Check that store paths match their NarHash
+    Check {
+        /// Store paths to check, all servable paths if omitted
+        paths: Vec<String>,
+    },
+    /// Report store paths without a valid signature by the configured keys
+    Verify {
+        /// Store paths to verify, all servable paths if omitted
+        paths: Vec<String>,
+    },
+}
+
 async fn inner_main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
 
     let c = web::Data::new(config::load().with_context(|| "Failed to load configuration")?);
+    HIDE_ERROR_DETAILS.store(c.hide_error_details, Ordering::Relaxed);
+
+    match cli.command {
+        Some(Command::Resign { paths }) => return resign::run(&c, &paths).await,
+        Some(Command::Check { paths }) => return check::run(&c, &paths).await,
+        Some(Command::Verify { paths }) => return verify::run(&c, &paths).await,
+        None => {}
+    }
+
+    if let Some(upstream_cache) = &c.upstream_cache {
+        upstream_cache.remove_stale_tmp()?;
+    }
+    if let Some(log_cache) = &c.log_cache {
+        log_cache.remove_stale_tmp()?;
+    }
+
     let config_data = c.clone();
+    let max_uri_length = c.max_uri_length;
+    let max_payload_size = c.max_payload_size;
+    let network_filter = c.network_filter.clone();
+    let default_headers = c.default_headers.clone();
+    let path_prefix = c.path_prefix.clone();
+    let client_metrics = c.client_version_metrics.then(|| c.metrics.clone());
+
+    limits::check_open_files_limit(&c)?;
 
     log::info!("listening on {}", c.bind);
     let mut server = HttpServer::new(move || {
+        let payload_config =
+            max_payload_size.map_or_else(Default::default, web::PayloadConfig::new);
+        let mut json_config = web::JsonConfig::default();
+        if let Some(max_payload_size) = max_payload_size {
+            json_config = json_config.limit(max_payload_size);
+        }
+        let client_metrics = client_metrics.clone();
         App::new()
             .wrap(middleware::Compress::default())
-            .app_data(config_data.clone())
-            .route("/", web::get().to(root::get))
-            .route("/{hash}.ls", web::get().to(narlist::get))
-            .route("/{hash}.ls", web::head().to(narlist::get))
-            .route("/{hash}.narinfo", web::get().to(narinfo::get))
-            .route("/{hash}.narinfo", web::head().to(narinfo::get))
-            .route(
-                &format!("/nar/{{narhash:[{0}]{{52}}}}.nar", NIXBASE32_ALPHABET),
-                web::get().to(nar::get),
-            )
-            .route(
-                // narinfos served by nix-serve have the narhash embedded in the nar URL.
-                // While we don't do that, if nix-serve is replaced with harmonia, the old nar URLs
-                // will stay in client caches for a while - so support them anyway.
-                &format!(
-                    "/nar/{{outhash:[{0}]{{32}}}}-{{narhash:[{0}]{{52}}}}.nar",
-                    NIXBASE32_ALPHABET
-                ),
-                web::get().to(nar::get),
+            .wrap_fn(move |req, srv| {
+                if let Some(metrics) = &client_metrics {
+                    metrics.observe_client(req.headers());
+                }
+                srv.call(req)
+            })
+            .wrap_fn(move |req, srv| {
+                let uri_length = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+                let res = if max_uri_length.is_some_and(|max| uri_length > max) {
+                    Err(req)
+                } else {
+                    Ok(srv.call(req))
+                };
+                async move {
+                    match res {
+                        Ok(res) => res.await.map(|res| res.map_into_left_body()),
+                        Err(req) => Ok(req
+                            .into_response(
+                                HttpResponse::UriTooLong()
+                                    .insert_header(cache_control_no_store())
+                                    .finish(),
+                            )
+                            .map_into_right_body()),
+                    }
+                }
+            })
+            .wrap_fn({
+                let network_filter = network_filter.clone();
+                move |req, srv| {
+                    let res = if network_filter.is_allowed(&req) {
+                        Ok(srv.call(req))
+                    } else {
+                        Err(req)
+                    };
+                    async move {
+                        match res {
+                            Ok(res) => res.await.map(|res| res.map_into_left_body()),
+                            Err(req) => Ok(req
+                                .into_response(
+                                    HttpResponse::Forbidden()
+                                        .insert_header(cache_control_no_store())
+                                        .finish(),
+                                )
+                                .map_into_right_body()),
+                        }
+                    }
+                }
+            })
+            // outermost, so that rejected requests get the headers too; only
+            // adds headers that the response doesn't already have
+            .wrap(
+                default_headers
+                    .iter()
+                    .cloned()
+                    .fold(middleware::DefaultHeaders::new(), |headers, header| {
+                        headers.add(header)
+                    }),
             )
-            .route("/serve/{hash}{path:.*}", web::get().to(serve::get))
-            .route("/log/{drv}", web::get().to(buildlog::get))
-            .route("/version", web::get().to(version::get))
-            .route("/health", web::get().to(health::get))
-            .route("/nix-cache-info", web::get().to(cacheinfo::get))
+            .app_data(config_data.clone())
+            .app_data(payload_config)
+            .app_data(json_config)
+            .configure(|cfg| routes::configure(cfg, &path_prefix))
+    })
+    .keep_alive(if c.keep_alive == 0 {
+        KeepAlive::Disabled
+    } else {
+        KeepAlive::Timeout(Duration::from_secs(c.keep_alive))
     })
-    // default is 5 seconds, which is too small when doing mass requests on slow machines
-    .client_request_timeout(Duration::from_secs(30))
+    .client_request_timeout(Duration::from_secs(c.client_request_timeout))
+    .client_disconnect_timeout(Duration::from_millis(c.client_disconnect_timeout_ms))
     .workers(c.workers)
     .max_connection_rate(c.max_connection_rate);
+    if let Some(blocking_threads) = c.blocking_threads {
+        server = server.worker_max_blocking_threads(blocking_threads);
+    }
 
     let try_url = Url::parse(&c.bind);
     let (bind, uds) = {
-        if try_url.is_ok() {
-            let url = try_url.as_ref().unwrap();
+        if let Ok(url) = &try_url {
             if url.scheme() != "unix" {
                 (c.bind.as_str(), false)
             } else if url.host().is_none() {
@@ -169,15 +363,29 @@ async fn inner_main() -> Result<()> {
         }
     };
 
+    let mut tls_context = None;
     if c.tls_cert_path.is_some() || c.tls_key_path.is_some() {
         if uds {
             log::error!("TLS is not supported with Unix domain sockets.");
             std::process::exit(1);
         }
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        builder.set_private_key_file(c.tls_key_path.clone().unwrap(), SslFiletype::PEM)?;
-        builder.set_certificate_chain_file(c.tls_cert_path.clone().unwrap())?;
-        server = server.bind_openssl(c.bind.clone(), builder)?;
+        let context = Arc::new(ArcSwap::from_pointee(
+            tls_acceptor_builder(&c)?.build().into_context(),
+        ));
+        let mut builder = tls_acceptor_builder(&c)?;
+        // Use the current context for every handshake, so that certificates
+        // reloaded on SIGHUP apply to new connections.
+        let current = context.clone();
+        builder.set_servername_callback(move |ssl, _| {
+            ssl.set_ssl_context(&current.load())
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+        tls_context = Some(context);
+        server = match scoped_bind_addr(&c.bind)? {
+            Some(addr) => server.bind_openssl(addr, builder),
+            None => server.bind_openssl(c.bind.clone(), builder),
+        }
+        .with_context(|| format!("Failed to bind to {}", c.bind))?;
     } else if uds {
         if !cfg!(unix) {
             log::error!("Binding to Unix domain sockets is only supported on Unix.");
@@ -185,18 +393,296 @@ async fn inner_main() -> Result<()> {
         } else {
             let socket_path = Path::new(bind);
             server = server.bind_uds(socket_path)?;
-            fs::set_permissions(socket_path, fs::Permissions::from_mode(0o777))?;
+            fs::set_permissions(socket_path, fs::Permissions::from_mode(c.unix_socket_mode))
+                .with_context(|| format!("Failed to set permissions of {}", bind))?;
+            if c.unix_socket_uid.is_some() || c.unix_socket_gid.is_some() {
+                std::os::unix::fs::chown(socket_path, c.unix_socket_uid, c.unix_socket_gid)
+                    .with_context(|| format!("Failed to change ownership of {}", bind))?;
+            }
+            let metadata = fs::metadata(socket_path)?;
+            log::info!(
+                "socket {} has mode {:o}",
+                bind,
+                metadata.permissions().mode() & 0o7777
+            );
         }
     } else {
-        server = server.bind(c.bind.clone())?;
+        server = match scoped_bind_addr(&c.bind)? {
+            Some(addr) => server.bind(addr),
+            None => server.bind(c.bind.clone()),
+        }
+        .with_context(|| format!("Failed to bind to {}", c.bind))?;
     }
 
+    spawn_reload_on_sighup(c.clone(), tls_context)?;
+    spawn_flush_on_sigusr1(c.clone())?;
+    spawn_toggle_maintenance_on_sigusr2(c.clone())?;
+
     server.run().await.context("Failed to start server")
 }
 
+/// Parses link-local IPv6 bind addresses with the interface as scope id, like
+/// `[fe80::1%eth0]:5000`, which the standard library only accepts with the
+/// numeric index of the interface. Other addresses are left to the resolver.
+fn scoped_bind_addr(bind: &str) -> Result<Option<SocketAddr>> {
+    let Some((addr, port)) = bind
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+    else {
+        return Ok(None);
+    };
+    let Some((addr, scope)) = addr.split_once('%') else {
+        return Ok(None);
+    };
+    let addr = addr
+        .parse::<Ipv6Addr>()
+        .with_context(|| format!("Invalid IPv6 address in bind address '{}'", bind))?;
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("Invalid port in bind address '{}'", bind))?;
+    let scope_id = match scope.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => {
+            let name = CString::new(scope)
+                .with_context(|| format!("Invalid interface in bind address '{}'", bind))?;
+            // SAFETY: name is a valid C string
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => bail!(
+                    "Unknown network interface '{}' in bind address '{}'",
+                    scope,
+                    bind
+                ),
+                index => index,
+            }
+        }
+    };
+    Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+        addr, port, 0, scope_id,
+    ))))
+}
+
+fn tls_acceptor_builder(c: &Config) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_private_key_file(
+            c.tls_key_path
+                .as_deref()
+                .context("tls_key_path is not set")?,
+            SslFiletype::PEM,
+        )
+        .context("Failed to load TLS key")?;
+    builder
+        .set_certificate_chain_file(
+            c.tls_cert_path
+                .as_deref()
+                .context("tls_cert_path is not set")?,
+        )
+        .context("Failed to load TLS certificate")?;
+    builder
+        .check_private_key()
+        .context("TLS key does not match the certificate")?;
+    // Connections switch to this context in the SNI callback, so the ALPN
+    // that actix configures on the initial context doesn't apply to them.
+    let protocols: &'static [u8] = if c.http2 {
+        b"\x02h2\x08http/1.1"
+    } else {
+        b"\x08http/1.1"
+    };
+    builder.set_alpn_select_callback(move |_, client| {
+        openssl::ssl::select_next_proto(protocols, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+/// Reloads the signing keys and the TLS certificate from the configuration.
+///
+/// Everything else, like the bind address, requires a restart.
+fn reload(c: &Config, tls_context: Option<&ArcSwap<SslContext>>) -> Result<()> {
+    let new = config::load()?;
+    // build everything first, so that a failed reload doesn't apply partially
+    let new_context = match tls_context {
+        Some(_) => Some(tls_acceptor_builder(&new)?.build().into_context()),
+        None => None,
+    };
+    if let (Some(tls_context), Some(new_context)) = (tls_context, new_context) {
+        tls_context.store(Arc::new(new_context));
+    }
+    c.secret_keys.store(new.secret_keys.load_full());
+    log::info!(
+        "reloaded configuration with {} signing keys",
+        c.secret_keys.load().len()
+    );
+    Ok(())
+}
+
+fn spawn_reload_on_sighup(
+    c: web::Data<Config>,
+    tls_context: Option<Arc<ArcSwap<SslContext>>>,
+) -> Result<()> {
+    let mut hangup =
+        signal(SignalKind::hangup()).context("Failed to install the SIGHUP handler")?;
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reload(&c, tls_context.as_deref()) {
+                log::error!("Failed to reload configuration: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn spawn_flush_on_sigusr1(c: web::Data<Config>) -> Result<()> {
+    let mut usr1 =
+        signal(SignalKind::user_defined1()).context("Failed to install the SIGUSR1 handler")?;
+    actix_web::rt::spawn(async move {
+        while usr1.recv().await.is_some() {
+            if let Err(e) = admin::flush_caches(&c).await {
+                log::error!("Failed to flush caches: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn spawn_toggle_maintenance_on_sigusr2(c: web::Data<Config>) -> Result<()> {
+    let mut usr2 =
+        signal(SignalKind::user_defined2()).context("Failed to install the SIGUSR2 handler")?;
+    actix_web::rt::spawn(async move {
+        while usr2.recv().await.is_some() {
+            c.maintenance_mode.set(!c.maintenance_mode.is_enabled());
+        }
+    });
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    inner_main()
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    inner_main().await.map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use openssl::x509::{X509NameBuilder, X509};
+
+    /// Writes a self-signed certificate for localhost and its key to `dir`.
+    fn self_signed_cert(dir: &Path) -> Result<(String, String)> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")?;
+        let name = name.build();
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(&name)?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        cert.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+        cert.sign(&key, MessageDigest::sha256())?;
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, cert.build().to_pem()?)?;
+        fs::write(&key_path, key.private_key_to_pem_pkcs8()?)?;
+        Ok((
+            cert_path.to_string_lossy().into_owned(),
+            key_path.to_string_lossy().into_owned(),
+        ))
+    }
+
+    /// Returns the protocol negotiated by a client offering h2 and http/1.1.
+    fn negotiated_protocol(acceptor: SslAcceptor) -> Result<Vec<u8>> {
+        let (client, server) = std::os::unix::net::UnixStream::pair()?;
+        let server = std::thread::spawn(move || acceptor.accept(server).map(|_| ()));
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(b"\x02h2\x08http/1.1")?;
+        let stream = connector
+            .build()
+            .configure()?
+            .verify_hostname(false)
+            .connect("localhost", client)?;
+        let protocol = stream.ssl().selected_alpn_protocol().unwrap_or_default();
+        server.join().unwrap()?;
+        Ok(protocol.to_vec())
+    }
+
+    #[test]
+    fn test_alpn() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, key) = self_signed_cert(dir.path())?;
+        let mut c = Config {
+            tls_cert_path: Some(cert),
+            tls_key_path: Some(key),
+            http2: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            negotiated_protocol(tls_acceptor_builder(&c)?.build())?,
+            b"h2"
+        );
+        c.http2 = false;
+        assert_eq!(
+            negotiated_protocol(tls_acceptor_builder(&c)?.build())?,
+            b"http/1.1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_bind_addr() -> Result<()> {
+        let lo = unsafe { libc::if_nametoindex(c"lo".as_ptr()) };
+        assert_eq!(
+            scoped_bind_addr("[fe80::1%lo]:5000")?,
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse()?,
+                5000,
+                0,
+                lo
+            )))
+        );
+        assert_eq!(
+            scoped_bind_addr("[fe80::1%2]:5000")?,
+            Some("[fe80::1%2]:5000".parse()?)
+        );
+        let err = scoped_bind_addr("[fe80::1%nonexistent0]:5000").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unknown network interface 'nonexistent0'"));
+        assert!(scoped_bind_addr("[fe80::1%lo]:http").is_err());
+        assert_eq!(scoped_bind_addr("[::]:5000")?, None);
+        assert_eq!(scoped_bind_addr("127.0.0.1:5000")?, None);
+        assert_eq!(scoped_bind_addr("localhost:5000")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_error_status() {
+        let err = ServerError::from(anyhow::anyhow!("bad request"));
+        assert_eq!(err.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let err = ServerError::from(
+            anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound))
+                .context("Failed to open file"),
+        );
+        assert_eq!(err.status(), http::StatusCode::NOT_FOUND);
+
+        let err = ServerError::from(
+            anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound))
+                .context(daemon::DaemonUnavailable)
+                .context("Failed to query path info"),
+        );
+        assert_eq!(err.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        let res = actix_web::error::ResponseError::error_response(&err);
+        assert_eq!(
+            res.headers().get(http::header::RETRY_AFTER).unwrap(),
+            &DAEMON_RETRY_AFTER.to_string()
+        );
+    }
 }
@@ -0,0 +1,109 @@
+use std::convert::Infallible;
+use std::path::Path;
+
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::Context;
+
+use crate::config::{Config, PathFilter};
+use crate::narinfo::with_public_url;
+use crate::upload::check_admin_auth;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Bytes of lines sent at once.
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// The manifest line of `path`, with the URL of its narinfo, unless it may
+/// not be served.
+fn manifest_line(path: &str, path_filter: &PathFilter, public_url: Option<&str>) -> Option<String> {
+    let hash = Path::new(path).file_name()?.to_str()?.get(0..32)?;
+    if !path_filter.is_allowed(hash) {
+        return None;
+    }
+    let url = with_public_url(public_url, format!("{}.narinfo", hash));
+    Some(format!("{} {}\n", path, url))
+}
+
+/// Lists all valid store paths that may be served, one per line followed by
+/// the URL of its narinfo, for mirrors to enumerate the cache. Requires the
+/// admin token and `manifest` to be enabled, since it's expensive.
+pub(crate) async fn get(req: HttpRequest, settings: web::Data<Config>) -> ServerResult {
+    if !settings.manifest {
+        return Ok(HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .body("the manifest is disabled"));
+    }
+    if let Some(res) = check_admin_auth(&req, &settings) {
+        return Ok(res);
+    }
+    // the daemon sends the list at once, but the lines are only formatted
+    // while the response is sent
+    let paths = settings
+        .store_for(&req)
+        .daemon
+        .lock()
+        .await
+        .query_all_valid_paths()
+        .await
+        .context("Failed to query valid paths")?;
+
+    let settings = settings.into_inner();
+    let mut lines = paths.into_iter().filter_map(move |path| {
+        manifest_line(&path, &settings.path_filter, settings.public_url.as_deref())
+    });
+    let batches = std::iter::from_fn(move || {
+        let mut batch = String::new();
+        for line in lines.by_ref() {
+            batch.push_str(&line);
+            if batch.len() >= BATCH_SIZE {
+                break;
+            }
+        }
+        (!batch.is_empty()).then(|| Ok::<_, Infallible>(Bytes::from(batch)))
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8))
+        .streaming(tokio_stream::iter(batches)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_manifest_line() {
+        let path = "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1";
+        let filter = PathFilter::default();
+        assert_eq!(
+            manifest_line(path, &filter, None).as_deref(),
+            Some("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1 26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo\n")
+        );
+        assert_eq!(
+            manifest_line(path, &filter, Some("https://cache.example.com")).as_deref(),
+            Some("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1 https://cache.example.com/26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo\n")
+        );
+        assert_eq!(manifest_line("/nix/store/short", &filter, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_disabled() -> Result<(), crate::ServerError> {
+        let req = TestRequest::default().to_http_request();
+        let settings = web::Data::new(Config {
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        });
+        let res = get(req.clone(), settings).await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+
+        let settings = web::Data::new(Config {
+            admin_token: Some("secret".into()),
+            manifest: true,
+            ..Default::default()
+        });
+        let res = get(req, settings).await?;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
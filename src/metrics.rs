@@ -0,0 +1,215 @@
+use std::path::Path;
+use std::time::Duration;
+
+use actix_web::{http, web, HttpResponse};
+use anyhow::{Context, Result};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::config::Config;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Durations and throughput of NAR dumps, to tell when storage is the bottleneck.
+#[derive(Debug, Clone)]
+pub(crate) struct NarDumpMetrics {
+    duration: Histogram,
+    throughput: Histogram,
+    /// Dumps taking longer than this are logged.
+    slow_threshold: Option<Duration>,
+}
+
+impl NarDumpMetrics {
+    /// Records a finished dump of `bytes` bytes.
+    pub(crate) fn observe(&self, path: &Path, bytes: u64, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        self.duration.observe(secs);
+        if secs > 0.0 {
+            self.throughput.observe(bytes as f64 / secs);
+        }
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            log::warn!(
+                "dumping {} took {:.1}s for {} bytes ({:.0} KiB/s)",
+                path.display(),
+                secs,
+                bytes,
+                bytes as f64 / 1024.0 / secs
+            );
+        }
+    }
+}
+
+/// Highest major and minor version counted separately.
+const MAX_NIX_VERSION: (u32, u32) = (9, 99);
+
+/// Extracts the major and minor version from the `Nix/<version>` token that
+/// Nix appends to the user agent of curl, e.g. `2.18` for
+/// `curl/8.4.0 Nix/2.18.1`. Patch versions are dropped and versions beyond
+/// `MAX_NIX_VERSION` count as other clients, since the user agent is chosen
+/// by the client and every label value is kept in the registry for good.
+fn nix_version(user_agent: &str) -> Option<String> {
+    let version = user_agent
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix("Nix/"))?;
+    let mut parts = version.split('.').map(|part| {
+        let digits = part.len() - part.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        part[..digits].parse::<u32>().ok()
+    });
+    let (Some(Some(major)), Some(Some(minor))) = (parts.next(), parts.next()) else {
+        return None;
+    };
+    if major > MAX_NIX_VERSION.0 || minor > MAX_NIX_VERSION.1 {
+        return None;
+    }
+    Some(format!("{major}.{minor}"))
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) nar_dumps: NarDumpMetrics,
+    client_versions: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new(slow_nar_threshold: Option<Duration>) -> Result<Self> {
+        let registry = Registry::new();
+        let duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "harmonia_nar_dump_duration_seconds",
+                "Time taken to dump a NAR from the store",
+            )
+            .buckets(vec![
+                0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+            ]),
+        )?;
+        let throughput = Histogram::with_opts(
+            HistogramOpts::new(
+                "harmonia_nar_dump_throughput_bytes_per_second",
+                "Throughput of NAR dumps",
+            )
+            // 64 KiB/s to 1 GiB/s
+            .buckets(exponential_buckets(65536.0, 4.0, 8)?),
+        )?;
+        let client_versions = IntCounterVec::new(
+            Opts::new(
+                "harmonia_requests_by_nix_version_total",
+                "Requests by the Nix version of the client, `other` for other clients",
+            ),
+            &["nix_version"],
+        )?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(throughput.clone()))?;
+        registry.register(Box::new(client_versions.clone()))?;
+        Ok(Self {
+            registry,
+            nar_dumps: NarDumpMetrics {
+                duration,
+                throughput,
+                slow_threshold: slow_nar_threshold,
+            },
+            client_versions,
+        })
+    }
+
+    /// Counts a request by the Nix version in its `User-Agent`.
+    pub(crate) fn observe_client(&self, headers: &http::header::HeaderMap) {
+        let version = headers
+            .get(http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(nix_version);
+        self.client_versions
+            .with_label_values(&[version.as_deref().unwrap_or("other")])
+            .inc();
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .context("Failed to encode metrics")?;
+        Ok(buf)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        // registering distinct metrics in a fresh registry can't fail
+        Self::new(None).expect("Failed to create metrics")
+    }
+}
+
+pub(crate) async fn get(settings: web::Data<Config>) -> ServerResult {
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .content_type(TextEncoder::new().format_type())
+        .body(settings.metrics.encode()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        let metrics = Metrics::new(Some(Duration::from_secs(1)))?;
+        metrics.nar_dumps.observe(
+            Path::new("/nix/store/test"),
+            1024 * 1024,
+            Duration::from_secs(2),
+        );
+        let text = String::from_utf8(metrics.encode()?)?;
+        assert!(text.contains("harmonia_nar_dump_duration_seconds_count 1"));
+        assert!(text.contains("harmonia_nar_dump_duration_seconds_sum 2"));
+        assert!(
+            text.contains("harmonia_nar_dump_throughput_bytes_per_second_bucket{le=\"1048576\"} 1")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_nix_version() {
+        assert_eq!(
+            nix_version("curl/8.4.0 Nix/2.18.1").as_deref(),
+            Some("2.18")
+        );
+        assert_eq!(
+            nix_version("curl/8.9.1 Nix/2.25.0pre20241010_abcdef my-suffix").as_deref(),
+            Some("2.25")
+        );
+        assert_eq!(nix_version("Nix/3").as_deref(), None);
+        assert_eq!(nix_version("Nix/9.99").as_deref(), Some("9.99"));
+        assert_eq!(nix_version("Nix/10.0").as_deref(), None);
+        assert_eq!(nix_version("Nix/2.100").as_deref(), None);
+        assert_eq!(nix_version("Nix/1234.5678").as_deref(), None);
+        assert_eq!(nix_version("Mozilla/5.0").as_deref(), None);
+    }
+
+    #[test]
+    fn test_observe_client() -> Result<()> {
+        let metrics = Metrics::default();
+        let mut headers = http::header::HeaderMap::new();
+        metrics.observe_client(&headers);
+        headers.insert(
+            http::header::USER_AGENT,
+            http::header::HeaderValue::from_static("curl/8.4.0 Nix/2.18.1"),
+        );
+        metrics.observe_client(&headers);
+        metrics.observe_client(&headers);
+        headers.insert(
+            http::header::USER_AGENT,
+            http::header::HeaderValue::from_static("Nix/1234.5678"),
+        );
+        metrics.observe_client(&headers);
+        let text = String::from_utf8(metrics.encode()?)?;
+        assert!(text.contains("harmonia_requests_by_nix_version_total{nix_version=\"2.18\"} 2"));
+        assert!(text.contains("harmonia_requests_by_nix_version_total{nix_version=\"other\"} 2"));
+        assert!(!text.contains("1234"));
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
-use std::collections::BTreeMap;
-use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
 use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use actix_files::NamedFile;
 use actix_web::web::Bytes;
-use actix_web::{http, web, HttpRequest, HttpResponse};
+use actix_web::{http, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
 use anyhow::{bail, Context, Result};
+use openssl::sha::Sha256;
 use serde::Deserialize;
 use std::fs::{self, Metadata};
 use std::os::unix::ffi::OsStrExt;
@@ -12,11 +16,16 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use sync::mpsc::Sender;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
 
-use crate::config::Config;
-use crate::signing::convert_base16_to_nix32;
-use crate::{cache_control_max_age_1y, some_or_404};
+use crate::bundle::BundleEntry;
+use crate::compression::Compression;
+use crate::config::{Config, STORE_DIR_HEADER};
+use crate::metrics::NarDumpMetrics;
+use crate::signing::{convert_base16_to_nix32, to_hex};
+use crate::{cache_control_max_age, some_or_404, ServerResult};
 use std::ffi::{OsStr, OsString};
 use tokio::{sync, task};
 
@@ -24,13 +33,17 @@ use tokio::{sync, task};
 #[derive(Debug, Deserialize)]
 pub struct NarRequest {
     hash: Option<String>,
+    /// Set to `1` to have browsers save the NAR under a readable file name.
+    download: Option<String>,
 }
 
 /// Represents the parsed parts in a NAR URL.
 #[derive(Debug, Deserialize)]
 pub struct PathParams {
-    narhash: String,
+    /// Not present for outhash-only lookups, which skip the narhash verification.
+    narhash: Option<String>,
     outhash: Option<String>,
+    ext: Option<String>,
 }
 
 // TODO(conni2461): still missing
@@ -38,9 +51,9 @@ pub struct PathParams {
 
 // Credit actix_web actix-files: https://github.com/actix/actix-web/blob/master/actix-files/src/range.rs
 #[derive(Debug)]
-struct HttpRange {
-    start: u64,
-    length: u64,
+pub(crate) struct HttpRange {
+    pub(crate) start: u64,
+    pub(crate) length: u64,
 }
 
 impl HttpRange {
@@ -48,7 +61,7 @@ impl HttpRange {
     ///
     /// `header` is HTTP Range header (e.g. `bytes=bytes=0-9`).
     /// `size` is full size of response (file).
-    fn parse(
+    pub(crate) fn parse(
         header: &str,
         size: u64,
     ) -> std::result::Result<Vec<Self>, http_range::HttpRangeParseError> {
@@ -64,6 +77,37 @@ impl HttpRange {
     }
 }
 
+/// Returns the part of a chunk, starting at `chunk_start` within the NAR, that
+/// falls into the requested range of `length` bytes at `offset`.
+fn range_in_chunk(
+    chunk_start: u64,
+    chunk_len: usize,
+    offset: u64,
+    length: u64,
+) -> Option<std::ops::Range<usize>> {
+    let chunk_end = chunk_start + chunk_len as u64;
+    let range_end = offset + length;
+    if chunk_end <= offset || chunk_start >= range_end {
+        return None;
+    }
+    // both are within the chunk, so they fit into usize
+    let start = offset.saturating_sub(chunk_start) as usize;
+    let end = (range_end.min(chunk_end) - chunk_start) as usize;
+    Some(start..end)
+}
+
+/// Multipart range responses are not implemented, so rather than silently
+/// serving only the first range, reject requests asking for several.
+pub(crate) fn multiple_ranges_not_satisfiable(
+    mut res: HttpResponseBuilder,
+    size: u64,
+) -> HttpResponse {
+    res.status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+        .insert_header((http::header::CONTENT_RANGE, format!("bytes */{}", size)))
+        .insert_header(crate::cache_control_no_store())
+        .body("multiple ranges are not supported")
+}
+
 // We send this error across thread boundaries, so it must be Send + Sync
 #[derive(Debug)]
 enum ThreadSafeError {}
@@ -74,7 +118,29 @@ impl std::fmt::Display for ThreadSafeError {
     }
 }
 
-fn alignment(size: u64) -> usize {
+/// Fails for a file at `path` that NARs can't represent, naming the path and
+/// its type, e.g. for a FIFO left in a store path by a build.
+pub(crate) fn unsupported_file_type(path: &Path, file_type: std::fs::FileType) -> anyhow::Error {
+    use std::os::unix::fs::FileTypeExt;
+    let kind = if file_type.is_fifo() {
+        "FIFO"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else {
+        "file of unknown type"
+    };
+    anyhow::anyhow!(
+        "{} is a {}, which can't be put into a NAR",
+        path.display(),
+        kind
+    )
+}
+
+pub(crate) fn alignment(size: u64) -> usize {
     let align = 8 - (size % 8);
     if align == 8 {
         0
@@ -165,7 +231,7 @@ async fn dump_contents(
 }
 
 #[cfg(target_os = "macos")]
-fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
+pub(crate) fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
     let needle = b"~nix~case~hack~";
     let pos = s
         .as_bytes()
@@ -179,7 +245,7 @@ fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
 }
 
 #[cfg(not(target_os = "macos"))]
-fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
+pub(crate) fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
     s
 }
 
@@ -313,7 +379,7 @@ async fn dump_path(path: PathBuf, tx: &Sender<Result<Bytes, ThreadSafeError>>) -
             } else if file_type.is_symlink() {
                 dump_symlink(frame, tx).await?;
             } else {
-                bail!("Unsupported file type: {:?}", file_type);
+                return Err(unsupported_file_type(&frame.path, file_type));
             }
             stack.pop();
         }
@@ -322,27 +388,537 @@ async fn dump_path(path: PathBuf, tx: &Sender<Result<Bytes, ThreadSafeError>>) -
     Ok(())
 }
 
+/// Returns true if `digest` matches the base16 encoded `expected` hash,
+/// which may carry a `sha256:` prefix.
+pub(crate) fn nar_hash_matches(digest: &[u8], expected: &str) -> bool {
+    let expected = expected.strip_prefix("sha256:").unwrap_or(expected);
+    to_hex(digest).eq_ignore_ascii_case(expected)
+}
+
+/// Forwards a NAR stream while hashing it.
+///
+/// The last chunk is held back until the hash has been verified. On a
+/// mismatch the stream ends early, so clients get a truncated download
+/// instead of a complete but corrupt one.
+fn verify_nar_stream(mut rx: NarReceiver, expected: String, path: PathBuf) -> NarReceiver {
+    let (tx, verified_rx) =
+        sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(STAGE_CHANNEL_CAPACITY);
+    task::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut pending = None;
+        while let Some(chunk) = rx.recv().await {
+            let Ok(chunk) = chunk;
+            hasher.update(&chunk);
+            if let Some(prev) = pending.replace(chunk) {
+                if tx.send(Ok(prev)).await.is_err() {
+                    return;
+                }
+            }
+        }
+        if nar_hash_matches(&hasher.finish(), &expected) {
+            if let Some(last) = pending {
+                let _ = tx.send(Ok(last)).await;
+            }
+        } else {
+            log::error!(
+                "NAR of {} does not match its NarHash {}, the store may be corrupt",
+                path.display(),
+                expected
+            );
+        }
+    });
+    verified_rx
+}
+
+/// Forwards exactly `expected` bytes of a NAR stream, the length promised in
+/// the Content-Length.
+///
+/// The NAR is dumped from disk, so it can differ from the NarSize the daemon
+/// recorded if the store was modified. Excess bytes are cut off, and a short
+/// NAR ends with an error, so the connection is closed instead of leaving the
+/// client waiting for bytes that never come.
+fn enforce_nar_size(
+    mut rx: NarReceiver,
+    expected: u64,
+    path: PathBuf,
+) -> sync::mpsc::Receiver<std::io::Result<Bytes>> {
+    let (tx, sized_rx) = sync::mpsc::channel(STAGE_CHANNEL_CAPACITY);
+    task::spawn(async move {
+        let mut sent = 0u64;
+        while let Some(chunk) = rx.recv().await {
+            let Ok(mut chunk) = chunk;
+            let remaining = expected - sent;
+            if chunk.len() as u64 > remaining {
+                log::error!(
+                    "NAR of {} is larger than the expected {} bytes, the store may be corrupt",
+                    path.display(),
+                    expected
+                );
+                chunk.truncate(remaining as usize);
+            }
+            sent += chunk.len() as u64;
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+            if sent == expected {
+                return;
+            }
+        }
+        if sent == expected {
+            return;
+        }
+        log::error!(
+            "NAR of {} ended after {} of the expected {} bytes",
+            path.display(),
+            sent,
+            expected
+        );
+        let _ = tx
+            .send(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "NAR is shorter than expected",
+            )))
+            .await;
+    });
+    sized_rx
+}
+
+/// Dumps `path` into a hasher, returning the sha256 digest of its NAR.
+pub(crate) async fn nar_hash(path: PathBuf) -> Result<[u8; 32]> {
+    // hashing keeps up with the dump, there's nothing to buffer for
+    let (tx, mut rx) =
+        sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(STAGE_CHANNEL_CAPACITY);
+    let dump = task::spawn(async move { dump_path(path, &tx).await });
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = rx.recv().await {
+        let Ok(chunk) = chunk;
+        hasher.update(&chunk);
+    }
+    dump.await.context("NAR dump panicked")??;
+    Ok(hasher.finish())
+}
+
+/// Dumps `path` and checks that the NAR matches the base16 encoded `expected` hash.
+pub(crate) async fn check_nar_hash(path: PathBuf, expected: &str) -> Result<bool> {
+    Ok(nar_hash_matches(&nar_hash(path).await?, expected))
+}
+
+/// Default number of chunks of up to 16 KiB that are buffered per NAR dump.
+pub(crate) const DEFAULT_NAR_CHANNEL_CAPACITY: usize = 1000;
+/// Chunks buffered by the stages that only forward a NAR stream, like
+/// hashing. The buffering is up to the channel of the dump, so these stay
+/// small and don't add to the memory of every stream.
+const STAGE_CHANNEL_CAPACITY: usize = 16;
+
+/// Number of leading chunks of a NAR dump that are kept, so that requests
+/// arriving shortly after a dump started can still join it.
+const REPLAY_CHUNKS: usize = 256;
+/// Upper bound for the bytes kept in the replay buffer of a dump.
+const REPLAY_BYTES: usize = 1024 * 1024;
+
+type NarSender = Sender<Result<Bytes, ThreadSafeError>>;
+type NarReceiver = sync::mpsc::Receiver<Result<Bytes, ThreadSafeError>>;
+
+#[derive(Default)]
+struct Subscribers {
+    senders: Vec<NarSender>,
+    /// Chunks sent so far, as long as new subscribers can still join.
+    replay: Vec<Bytes>,
+    replay_bytes: usize,
+    /// False once the replay buffer overflowed or the dump finished.
+    joinable: bool,
+}
+
+/// Coalesces concurrent dumps of the same store path.
+///
+/// The first request for a path starts a single producer, whose chunks are
+/// fanned out to every request for the same path that arrives while the
+/// beginning of the dump is still buffered. The producer waits for the
+/// slowest subscriber, so memory usage stays bounded.
+pub(crate) struct NarDumps {
+    inflight: sync::Mutex<HashMap<PathBuf, Arc<sync::Mutex<Subscribers>>>>,
+    started: AtomicUsize,
+    /// Bounds the number of dumps running at once, joining one is always possible.
+    permits: Option<Arc<sync::Semaphore>>,
+    /// How long a dump may wait for a permit before the request is given up.
+    queue_timeout: Option<Duration>,
+    metrics: Option<NarDumpMetrics>,
+    /// Chunks buffered per dump and per client, trading memory for throughput.
+    channel_capacity: usize,
+}
+
+impl Default for NarDumps {
+    fn default() -> Self {
+        Self {
+            inflight: Default::default(),
+            started: Default::default(),
+            permits: None,
+            queue_timeout: None,
+            metrics: None,
+            channel_capacity: DEFAULT_NAR_CHANNEL_CAPACITY,
+        }
+    }
+}
+
+/// Returned when no dump could be started within the queue timeout.
+#[derive(Debug)]
+pub(crate) struct DumpsBusy;
+
+impl std::fmt::Display for DumpsBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many concurrent NAR dumps")
+    }
+}
+
+impl std::error::Error for DumpsBusy {}
+
+impl DumpsBusy {
+    fn response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .insert_header(crate::cache_control_no_store())
+            .insert_header((http::header::RETRY_AFTER, "1"))
+            .body(self.to_string())
+    }
+}
+
+impl std::fmt::Debug for NarDumps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NarDumps")
+            .field("started", &self.started)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NarDumps {
+    /// Limits the number of dumps running at once to `max_concurrent`. Further
+    /// dumps wait for up to `queue_timeout`, or forever if unset.
+    pub(crate) fn new(max_concurrent: Option<usize>, queue_timeout: Option<Duration>) -> Self {
+        Self {
+            permits: max_concurrent.map(|max| Arc::new(sync::Semaphore::new(max))),
+            queue_timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Records the duration and throughput of every dump in `metrics`.
+    pub(crate) fn with_metrics(self, metrics: NarDumpMetrics) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Buffers up to `capacity` chunks per dump and per client.
+    pub(crate) fn with_channel_capacity(self, capacity: usize) -> Self {
+        Self {
+            channel_capacity: capacity,
+            ..self
+        }
+    }
+
+    /// Joins the running dump of `path` by handing `tx` the chunks sent so far.
+    /// Returns `tx` back if there is none or it can't be joined anymore.
+    async fn join(
+        inflight: &HashMap<PathBuf, Arc<sync::Mutex<Subscribers>>>,
+        path: &Path,
+        tx: NarSender,
+    ) -> Option<NarSender> {
+        if let Some(subscribers) = inflight.get(path) {
+            let mut subscribers = subscribers.lock().await;
+            if subscribers.joinable {
+                for chunk in &subscribers.replay {
+                    // cannot fail: the channel is empty and has room for the whole replay buffer
+                    let _ = tx.try_send(Ok(chunk.clone()));
+                }
+                subscribers.senders.push(tx);
+                return None;
+            }
+        }
+        Some(tx)
+    }
+
+    /// Waits until another dump may be started.
+    async fn acquire(&self) -> Result<Option<sync::OwnedSemaphorePermit>, DumpsBusy> {
+        let Some(permits) = &self.permits else {
+            return Ok(None);
+        };
+        let acquire = permits.clone().acquire_owned();
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire)
+                .await
+                .map_err(|_| DumpsBusy)?,
+            None => acquire.await,
+        };
+        // the semaphore is never closed
+        Ok(permit.ok())
+    }
+
+    /// Returns a stream of the NAR of `path`, joining a running dump if possible.
+    async fn subscribe(self: &Arc<Self>, path: PathBuf) -> Result<NarReceiver, DumpsBusy> {
+        let (tx, rx) = sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(
+            REPLAY_CHUNKS + self.channel_capacity,
+        );
+
+        let Some(tx) = Self::join(&*self.inflight.lock().await, &path, tx).await else {
+            return Ok(rx);
+        };
+        // don't block other paths while waiting
+        let permit = self.acquire().await?;
+        let mut inflight = self.inflight.lock().await;
+        // another request may have started a dump in the meantime
+        let Some(tx) = Self::join(&inflight, &path, tx).await else {
+            return Ok(rx);
+        };
+
+        let subscribers = Arc::new(sync::Mutex::new(Subscribers {
+            senders: vec![tx],
+            joinable: true,
+            ..Default::default()
+        }));
+        inflight.insert(path.clone(), subscribers.clone());
+        drop(inflight);
+        self.started.fetch_add(1, Ordering::Relaxed);
+
+        let (dump_tx, mut dump_rx) =
+            sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(self.channel_capacity);
+        let dump_path_buf = path.clone();
+        task::spawn(async move {
+            // held until the dump finished or every client went away
+            let _permit = permit;
+            let err = dump_path(dump_path_buf.clone(), &dump_tx).await;
+            if let Err(err) = err {
+                log::error!("Error dumping path {}: {:?}", dump_path_buf.display(), err);
+            }
+        });
+
+        let dumps = self.clone();
+        task::spawn(async move {
+            let started = Instant::now();
+            let mut bytes = 0u64;
+            let mut aborted = false;
+            while let Some(chunk) = dump_rx.recv().await {
+                let Ok(chunk) = chunk;
+                bytes += chunk.len() as u64;
+                let senders = {
+                    let mut subscribers = subscribers.lock().await;
+                    if subscribers.joinable {
+                        subscribers.replay_bytes += chunk.len();
+                        subscribers.replay.push(chunk.clone());
+                        if subscribers.replay.len() >= REPLAY_CHUNKS
+                            || subscribers.replay_bytes > REPLAY_BYTES
+                        {
+                            subscribers.joinable = false;
+                            subscribers.replay = Vec::new();
+                        }
+                    }
+                    subscribers.senders.clone()
+                };
+                let mut closed = false;
+                for sender in &senders {
+                    closed |= sender.send(Ok(chunk.clone())).await.is_err();
+                }
+                if closed {
+                    let mut subscribers = subscribers.lock().await;
+                    subscribers.senders.retain(|s| !s.is_closed());
+                    if subscribers.senders.is_empty() {
+                        // every client went away, stop dumping
+                        subscribers.joinable = false;
+                        aborted = true;
+                        break;
+                    }
+                }
+            }
+            if let Some(metrics) = dumps.metrics.as_ref().filter(|_| !aborted) {
+                metrics.observe(&path, bytes, started.elapsed());
+            }
+
+            let mut inflight = dumps.inflight.lock().await;
+            if inflight
+                .get(&path)
+                .is_some_and(|s| Arc::ptr_eq(s, &subscribers))
+            {
+                inflight.remove(&path);
+            }
+            subscribers.lock().await.joinable = false;
+        });
+
+        Ok(rx)
+    }
+}
+
+/// Builds an attachment header named after the store path, e.g. `hello-2.12.1.nar.zst`.
+fn download_content_disposition(
+    store_path: &Path,
+    compression: Compression,
+) -> http::header::ContentDisposition {
+    let base_name = store_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // strip the hash part
+    let name = match base_name.split_once('-') {
+        Some((_, name)) if !name.is_empty() => name,
+        _ => &base_name,
+    };
+    let filename = match compression.extension() {
+        Some(ext) => format!("{}.nar.{}", name, ext),
+        None => format!("{}.nar", name),
+    };
+    http::header::ContentDisposition {
+        disposition: http::header::DispositionType::Attachment,
+        parameters: vec![http::header::DispositionParam::Filename(filename)],
+    }
+}
+
+/// Serves a NAR stored in a bundle, honouring range requests.
+async fn get_from_bundle(
+    bundle_path: &Path,
+    entry: BundleEntry,
+    req: &HttpRequest,
+    settings: &Config,
+) -> ServerResult {
+    let mut res = HttpResponse::Ok();
+    let mut offset = 0;
+    let mut length = entry.size;
+
+    if let Some(ranges) = req.headers().get(http::header::RANGE) {
+        let ranges_header = match ranges.to_str() {
+            Ok(ranges_header) => ranges_header,
+            Err(_) => return Ok(res.status(http::StatusCode::BAD_REQUEST).finish()),
+        };
+        match HttpRange::parse(ranges_header, entry.size) {
+            Ok(ranges) if ranges.len() > 1 => {
+                return Ok(multiple_ranges_not_satisfiable(res, entry.size));
+            }
+            Ok(ranges) => {
+                offset = ranges[0].start;
+                length = ranges[0].length;
+                res.status(http::StatusCode::PARTIAL_CONTENT);
+                res.insert_header((
+                    http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", offset, offset + length - 1, entry.size),
+                ));
+            }
+            Err(_) => {
+                res.insert_header((http::header::CONTENT_RANGE, format!("bytes */{}", length)));
+                return Ok(res.status(http::StatusCode::RANGE_NOT_SATISFIABLE).finish());
+            }
+        }
+    }
+
+    let mut file = File::open(bundle_path)
+        .await
+        .with_context(|| format!("Failed to open bundle {}", bundle_path.display()))?;
+    file.seek(std::io::SeekFrom::Start(entry.offset + offset))
+        .await
+        .context("Failed to seek in bundle")?;
+    let stream = ReaderStream::new(file.take(length));
+
+    Ok(res
+        .insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
+        // the bundled NAR is served as-is, don't let the middleware compress it
+        .insert_header((
+            http::header::CONTENT_ENCODING,
+            http::header::HeaderValue::from_static("identity"),
+        ))
+        .insert_header((http::header::ACCEPT_RANGES, "bytes"))
+        .insert_header(cache_control_max_age(settings.nar_cache_control_max_age))
+        .body(actix_web::body::SizedStream::new(length, stream)))
+}
+
+/// Compresses a NAR on the fly.
+///
+/// Ranges would have to refer to the compressed bytes, whose offsets are only
+/// known once everything before them has been compressed. So ranges are not
+/// advertised and `Range` headers are ignored, always sending the whole NAR.
+fn compressed_nar(
+    mut res: HttpResponseBuilder,
+    compression: Compression,
+    rx: NarReceiver,
+    max_age: u32,
+) -> HttpResponse {
+    let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+    res.insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
+        .insert_header(cache_control_max_age(max_age))
+        .body(compression.encode(rx))
+}
+
+/// Returns the file with the NAR of `real_path` compressed with `compression`
+/// that is kept next to the store path, if there is one.
+fn precompressed_nar_path(real_path: &Path, compression: Compression) -> Option<PathBuf> {
+    let ext = compression.extension()?;
+    let mut path = real_path.as_os_str().to_owned();
+    path.push(format!(".nar.{}", ext));
+    let path = PathBuf::from(path);
+    // a symlink could point anywhere
+    fs::symlink_metadata(&path)
+        .is_ok_and(|m| m.is_file())
+        .then_some(path)
+}
+
+/// Picks the outhash to look up from the URL path and the `hash` query parameter.
+///
+/// For nix-serve style URLs the outhash in the path is authoritative, so a
+/// conflicting query parameter can't redirect the request to another store
+/// path. Returns `None` on such a conflict.
+fn select_outhash<'a>(
+    path_outhash: Option<&'a str>,
+    query_hash: Option<&'a str>,
+) -> Option<Option<&'a str>> {
+    match (path_outhash, query_hash) {
+        (Some(path_outhash), Some(query_hash)) if path_outhash != query_hash => None,
+        (Some(outhash), _) | (None, Some(outhash)) => Some(Some(outhash)),
+        (None, None) => Some(None),
+    }
+}
+
 pub(crate) async fn get(
     path: web::Path<PathParams>,
     req: HttpRequest,
     q: web::Query<NarRequest>,
     settings: web::Data<Config>,
-) -> Result<HttpResponse, Box<dyn Error>> {
-    // Extract the narhash from the query parameter, and bail out if it's missing or invalid.
-    let narhash = some_or_404!(Some(path.narhash.as_str()));
+) -> ServerResult {
+    if let Some(res) = settings.maintenance_mode.check() {
+        return Ok(res);
+    }
+    let narhash = path.narhash.as_deref();
+
+    if let Some(bundle) = &settings.bundle {
+        let narhash = some_or_404!(narhash);
+        let url = match &path.ext {
+            Some(ext) => format!("nar/{}.nar.{}", narhash, ext),
+            None => format!("nar/{}.nar", narhash),
+        };
+        // the query is optional, so check the store paths referencing the NAR
+        if !bundle
+            .nar_owners(&url)
+            .iter()
+            .any(|owner| settings.path_filter.is_allowed(owner))
+        {
+            return Ok(HttpResponse::NotFound()
+                .insert_header(crate::cache_control_no_store())
+                .body("store path not found"));
+        }
+        let entry = some_or_404!(bundle.nar(&url));
+        return get_from_bundle(bundle.path(), entry, &req, &settings).await;
+    }
 
     // lookup the store path.
     // We usually extract the outhash from the query parameter.
     // However, when processing nix-serve URLs, it's present in the path
     // directly.
-    let outhash = if let Some(outhash) = &q.hash {
-        Some(outhash.as_str())
-    } else {
-        path.outhash.as_deref()
+    let outhash = match select_outhash(path.outhash.as_deref(), q.hash.as_deref()) {
+        Some(outhash) => outhash,
+        None => {
+            return Ok(HttpResponse::NotFound()
+                .insert_header(crate::cache_control_no_store())
+                .body("hash mismatch detected"))
+        }
     };
+    let store = settings.store_for(&req);
     let store_path = match outhash {
-        Some(outhash) => settings
-            .store
+        Some(outhash) if !settings.path_filter.is_allowed(outhash) => None,
+        Some(outhash) => store
             .daemon
             .lock()
             .await
@@ -350,30 +926,37 @@ pub(crate) async fn get(
             .await
             .context("failed to query path from hash part")?,
         None => {
+            // e.g. a NAR referenced by a narinfo from upstream
+            if let Some(upstream) = &settings.upstream_proxy {
+                return Ok(upstream
+                    .proxy(&req, settings.nar_cache_control_max_age)
+                    .await?);
+            }
             return Ok(HttpResponse::NotFound()
                 .insert_header(crate::cache_control_no_store())
-                .body("missing outhash"))
+                .body("missing outhash"));
         }
     };
     let store_path = match store_path {
         Some(store_path) => store_path,
         None => {
+            if let Some(upstream) = &settings.upstream_proxy {
+                if let Some(outhash) = outhash.filter(|h| settings.path_filter.is_allowed(h)) {
+                    let max_age = settings.nar_cache_control_max_age;
+                    return Ok(match &settings.upstream_cache {
+                        Some(cache) => cache.nar(upstream, outhash, &req, max_age).await?,
+                        None => upstream.proxy(&req, max_age).await?,
+                    });
+                }
+            }
             return Ok(HttpResponse::NotFound()
                 .insert_header(crate::cache_control_no_store())
-                .body("store path not found"))
+                .body("store path not found"));
         }
     };
 
     // lookup the path info.
-    let info = match settings
-        .store
-        .daemon
-        .lock()
-        .await
-        .query_path_info(&store_path)
-        .await?
-        .path
-    {
+    let info = match store.query_path_info(&store_path).await? {
         Some(info) => info,
         None => {
             return Ok(HttpResponse::NotFound()
@@ -390,34 +973,131 @@ pub(crate) async fn get(
                 .body("failed to convert hash to nix32"));
         }
     };
-    if narhash != info_hash_nix32 {
+    if narhash.is_some_and(|narhash| narhash != info_hash_nix32) {
         return Ok(HttpResponse::NotFound()
             .insert_header(crate::cache_control_no_store())
             .body("hash mismatch detected"));
     }
 
+    if let Some(max_nar_size) = settings.max_nar_size {
+        if info.nar_size > max_nar_size {
+            return Ok(HttpResponse::PayloadTooLarge()
+                .insert_header(crate::cache_control_no_store())
+                .body(format!(
+                    "NAR size {} exceeds the limit of {} bytes",
+                    info.nar_size, max_nar_size
+                )));
+        }
+    }
+
+    let compression = match &path.ext {
+        Some(ext) => some_or_404!(Compression::from_extension(ext)),
+        None => Compression::None,
+    };
+
     let store_path = PathBuf::from(store_path);
+    let mut res = HttpResponse::Ok();
+    // lets clients that only know the outhash verify the NAR
+    res.insert_header(("X-Nar-Hash", format!("sha256:{}", info_hash_nix32)))
+        .insert_header(("X-Nar-Size", info.nar_size));
+    if narhash.is_none() && !settings.extra_stores.is_empty() {
+        // without a narhash in the URL, the content depends on the selected store
+        res.insert_header((http::header::VARY, STORE_DIR_HEADER));
+    }
+    // NARs are either compressed explicitly or served as-is, so that ranges and
+    // Content-Length stay valid. Keep the compression middleware away from them.
+    res.insert_header((
+        http::header::CONTENT_ENCODING,
+        http::header::HeaderValue::from_static("identity"),
+    ));
+
+    if q.download.as_deref() == Some("1") {
+        res.insert_header(download_content_disposition(&store_path, compression));
+    }
+
+    if compression != Compression::None {
+        let real_path = store.get_real_path(&store_path);
+        if settings.precompressed_nars {
+            if let Some(nar_path) = precompressed_nar_path(&real_path, compression) {
+                // a regular file, so unlike live compression this supports
+                // ranges, and HEAD requests are answered from its metadata
+                let mut nar = NamedFile::open_async(&nar_path)
+                    .await
+                    .with_context(|| format!("Failed to open {}", nar_path.display()))?
+                    .disable_content_disposition()
+                    .set_content_type(
+                        "application/x-nix-archive"
+                            .parse()
+                            .context("Failed to parse content type")?,
+                    )
+                    .customize()
+                    .insert_header(("X-Nar-Hash", format!("sha256:{}", info_hash_nix32)))
+                    .insert_header(("X-Nar-Size", info.nar_size))
+                    .insert_header(cache_control_max_age(settings.nar_cache_control_max_age))
+                    .insert_header((
+                        http::header::CONTENT_ENCODING,
+                        http::header::HeaderValue::from_static("identity"),
+                    ));
+                if q.download.as_deref() == Some("1") {
+                    nar = nar.insert_header(download_content_disposition(&store_path, compression));
+                }
+                return Ok(nar.respond_to(&req).map_into_boxed_body());
+            }
+        }
+        if req.method() == http::Method::HEAD {
+            // the compressed size is only known after compressing the whole
+            // NAR, so send the headers without a length instead of dumping it
+            return Ok(res
+                .insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
+                .insert_header(cache_control_max_age(settings.nar_cache_control_max_age))
+                .body(actix_web::body::None::new()));
+        }
+        let mut rx = match settings.nar_dumps.subscribe(real_path.clone()).await {
+            Ok(rx) => rx,
+            Err(busy) => return Ok(busy.response()),
+        };
+        if settings.verify_nar_hash {
+            rx = verify_nar_stream(rx, info.hash.clone(), real_path);
+        }
+        return Ok(compressed_nar(
+            res,
+            compression,
+            rx,
+            settings.nar_cache_control_max_age,
+        ));
+    }
+
+    if req.method() == http::Method::HEAD {
+        // only the headers are sent, so don't bother dumping the NAR
+        return Ok(res
+            .insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
+            .insert_header((http::header::ACCEPT_RANGES, "bytes"))
+            .insert_header(cache_control_max_age(settings.nar_cache_control_max_age))
+            .body(actix_web::body::SizedStream::new(
+                info.nar_size,
+                tokio_stream::empty::<Result<Bytes, ThreadSafeError>>(),
+            )));
+    }
 
     let mut rlength = info.nar_size;
     let offset;
-    let mut res = HttpResponse::Ok();
 
-    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
-    let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+    // If Nix is set to a non-root store, physical store paths will differ from
+    // logical paths. Below we check if that is the case, and rewrite to physical
+    // before dumping.
+    let real_path = store.get_real_path(&store_path);
 
     // Credit actix_web actix-files: https://github.com/actix/actix-web/blob/master/actix-files/src/named.rs#L525
-    if let Some(ranges) = req.headers().get(http::header::RANGE) {
+    let rx = if let Some(ranges) = req.headers().get(http::header::RANGE) {
         if let Ok(ranges_header) = ranges.to_str() {
             if let Ok(ranges) = HttpRange::parse(ranges_header, rlength) {
+                if ranges.len() > 1 {
+                    return Ok(multiple_ranges_not_satisfiable(res, rlength));
+                }
                 rlength = ranges[0].length;
                 offset = ranges[0].start;
 
-                // don't allow compression middleware to modify partial content
-                res.insert_header((
-                    http::header::CONTENT_ENCODING,
-                    http::header::HeaderValue::from_static("identity"),
-                ));
-
+                res.status(http::StatusCode::PARTIAL_CONTENT);
                 res.insert_header((
                     http::header::CONTENT_RANGE,
                     format!(
@@ -436,64 +1116,55 @@ pub(crate) async fn get(
         };
         let mut send: u64 = 0;
 
-        let (tx2, mut rx2) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
-        task::spawn(async move {
-            // If Nix is set to a non-root store, physical store paths will differ from
-            // logical paths. Below we check if that is the case, and rewrite to physical
-            // before dumping.
-
-            let err = dump_path(settings.store.get_real_path(&store_path), &tx2).await;
-            if let Err(err) = err {
-                log::error!("Error dumping path {}: {:?}", store_path.display(), err);
-            }
-        });
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
+        let mut rx2 = match settings.nar_dumps.subscribe(real_path.clone()).await {
+            Ok(rx) => rx,
+            Err(busy) => return Ok(busy.response()),
+        };
         // we keep this closure extra to avoid unaligned copies in the non-range request case.
         task::spawn(async move {
             while let Some(Ok(data)) = rx2.recv().await {
-                let len = data.len() as u64;
-                if send + len > offset {
-                    let start = if send < offset { offset - send } else { 0 };
-                    let end = if send + data.len() as u64 > offset + rlength {
-                        start + rlength
-                    } else {
-                        len
-                    };
-                    // does it fit into usize
-                    let start: usize = match start.try_into() {
-                        Ok(v) => v,
-                        Err(e) => {
-                            log::error!("BUG: start(u64) is too big for usize: {:?}", e);
-                            break;
-                        }
-                    };
-                    let end: usize = match end.try_into() {
-                        Ok(v) => v,
-                        Err(e) => {
-                            log::error!("BUG: end(u64) is too big for usize: {:?}", e);
-                            break;
-                        }
-                    };
-                    if tx.send(Ok(data.slice(start..end))).await.is_err() {
+                if let Some(slice) = range_in_chunk(send, data.len(), offset, rlength) {
+                    if tx.send(Ok(data.slice(slice))).await.is_err() {
                         break;
                     }
                 }
-                send += len;
+                send += data.len() as u64;
+                if send >= offset + rlength {
+                    break;
+                }
             }
         });
+        rx
     } else {
-        task::spawn(async move {
-            let err = dump_path(settings.store.get_real_path(&store_path), &tx).await;
-            if let Err(err) = err {
-                log::error!("Error dumping path {}: {:?}", store_path.display(), err);
-            }
-        });
+        let rx = match settings.nar_dumps.subscribe(real_path.clone()).await {
+            Ok(rx) => rx,
+            Err(busy) => return Ok(busy.response()),
+        };
+        if settings.verify_nar_hash {
+            verify_nar_stream(rx, info.hash.clone(), real_path.clone())
+        } else {
+            rx
+        }
     };
+    let rx = enforce_nar_size(rx, rlength, real_path);
+    let mut rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+    // The dump sends the `nix-archive-1` magic before looking at the path, so
+    // waiting for it hardly delays the headers, and clients get the first
+    // bytes of the body along with them.
+    let first = if settings.nar_prebuffer_header && !req.headers().contains_key(http::header::RANGE)
+    {
+        rx.next().await
+    } else {
+        None
+    };
+    let body = tokio_stream::iter(first).chain(rx);
 
     Ok(res
         .insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
         .insert_header((http::header::ACCEPT_RANGES, "bytes"))
-        .insert_header(cache_control_max_age_1y())
-        .body(actix_web::body::SizedStream::new(rlength, rx)))
+        .insert_header(cache_control_max_age(settings.nar_cache_control_max_age))
+        .body(actix_web::body::SizedStream::new(rlength, body)))
 }
 
 #[cfg(test)]
@@ -503,7 +1174,7 @@ mod test {
     use std::process::Command;
 
     async fn dump_to_vec(path: String) -> Result<Vec<u8>> {
-        let store = Store::new("/nix/store".to_string(), None);
+        let store = Store::new("/nix/store".to_string(), None, Default::default(), None);
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
         task::spawn(async move {
             let e = dump_path(store.get_real_path(&PathBuf::from(&path)), &tx).await;
@@ -560,6 +1231,386 @@ mod test {
         }
     }
 
+    async fn collect(mut rx: NarReceiver) -> Vec<u8> {
+        let mut resp = Vec::new();
+        while let Some(Ok(bytes)) = rx.recv().await {
+            resp.extend_from_slice(&bytes);
+        }
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_fifo() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store-path");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("file"), b"contents")?;
+        let fifo = dir.join("fifo");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_bytes())?;
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+
+        let (tx, _rx) = sync::mpsc::channel(1000);
+        let err = dump_path(dir, &tx).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is a FIFO, which can't be put into a NAR",
+                fifo.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_capacity() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("big"), vec![42u8; 1024 * 1024])?;
+
+        let (tx, rx) = sync::mpsc::channel(1000);
+        let path = dir.clone();
+        task::spawn(async move { dump_path(path, &tx).await });
+        let expected = collect(rx).await;
+
+        let dumps = Arc::new(NarDumps::default().with_channel_capacity(1));
+        let rx = dumps.subscribe(dir).await?;
+        assert_eq!(collect(rx).await, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dump_limit() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let file = temp_dir.path().join("file");
+        fs::write(&file, b"somecontent")?;
+
+        let dumps = Arc::new(NarDumps::new(Some(1), Some(Duration::from_millis(50))));
+        let permit = dumps.acquire().await?;
+        assert!(dumps.subscribe(file.clone()).await.is_err());
+        drop(permit);
+
+        let (tx, rx) = sync::mpsc::channel(1000);
+        let path = file.clone();
+        task::spawn(async move { dump_path(path, &tx).await });
+        let expected = collect(rx).await;
+        assert_eq!(collect(dumps.subscribe(file).await?).await, expected);
+        // released once the dump finished
+        assert!(dumps.acquire().await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_dumps() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("file"), b"somecontent")?;
+        fs::write(dir.join("big"), vec![42u8; 4 * 1024 * 1024])?;
+        let some_dir = dir.join("some_dir");
+        fs::create_dir(&some_dir)?;
+        std::os::unix::fs::symlink("sometarget", some_dir.join("symlink"))?;
+
+        let (tx, rx) = sync::mpsc::channel(1000);
+        let path = dir.clone();
+        task::spawn(async move { dump_path(path, &tx).await });
+        let expected = collect(rx).await;
+
+        let dumps = Arc::new(NarDumps::default());
+        // don't yield to the dump, it could outgrow the replay buffer before all joined
+        let receivers = task::unconstrained(async {
+            let mut receivers = Vec::new();
+            for _ in 0..10 {
+                receivers.push(dumps.subscribe(dir.clone()).await?);
+            }
+            Ok::<_, DumpsBusy>(receivers)
+        })
+        .await?;
+        let mut handles = Vec::new();
+        for rx in receivers {
+            handles.push(task::spawn(collect(rx)));
+        }
+        for handle in handles {
+            assert_eq!(handle.await?, expected);
+        }
+        assert_eq!(dumps.started.load(Ordering::Relaxed), 1);
+        assert!(dumps.inflight.lock().await.is_empty());
+
+        // once the dump is finished, a new request starts a new dump
+        assert_eq!(collect(dumps.subscribe(dir.clone()).await?).await, expected);
+        assert_eq!(dumps.started.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_content_disposition() {
+        let store_path = Path::new("/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1");
+        assert_eq!(
+            download_content_disposition(store_path, Compression::None).to_string(),
+            "attachment; filename=\"hello-2.12.1.nar\""
+        );
+        assert_eq!(
+            download_content_disposition(store_path, Compression::Zstd).to_string(),
+            "attachment; filename=\"hello-2.12.1.nar.zst\""
+        );
+    }
+
+    /// Collects the body a client would get for a NAR of `chunks` that was
+    /// announced with `expected` bytes.
+    async fn sized_body(chunks: &[&'static [u8]], expected: u64) -> Result<Bytes, String> {
+        let (tx, rx) = sync::mpsc::channel(chunks.len().max(1));
+        for chunk in chunks {
+            tx.send(Ok(Bytes::from_static(chunk))).await.unwrap();
+        }
+        drop(tx);
+        let rx = enforce_nar_size(rx, expected, PathBuf::from("/nix/store/test"));
+        let body = actix_web::body::SizedStream::new(
+            expected,
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        );
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            actix_web::body::to_bytes(body),
+        )
+        .await
+        .expect("body must not hang")
+        .map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_enforce_nar_size() {
+        assert_eq!(
+            sized_body(&[b"hello ", b"world"], 11).await.unwrap(),
+            "hello world"
+        );
+        assert_eq!(sized_body(&[], 0).await.unwrap(), "");
+        // cut off at the announced length
+        assert_eq!(
+            sized_body(&[b"hello ", b"world"], 8).await.unwrap(),
+            "hello wo"
+        );
+        // a short NAR fails instead of leaving the client waiting
+        assert!(sized_body(&[b"hello"], 11).await.is_err());
+    }
+
+    #[test]
+    fn test_precompressed_nar_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store_path = dir
+            .path()
+            .join("26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1");
+        fs::create_dir(&store_path)?;
+        let zst = dir
+            .path()
+            .join("26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1.nar.zst");
+        fs::write(&zst, b"compressed")?;
+        let xz = dir
+            .path()
+            .join("26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1.nar.xz");
+        std::os::unix::fs::symlink("/etc/passwd", &xz)?;
+
+        assert_eq!(
+            precompressed_nar_path(&store_path, Compression::Zstd),
+            Some(zst)
+        );
+        assert_eq!(precompressed_nar_path(&store_path, Compression::Xz), None);
+        assert_eq!(precompressed_nar_path(&store_path, Compression::Gzip), None);
+        assert_eq!(precompressed_nar_path(&store_path, Compression::None), None);
+        Ok(())
+    }
+
+    /// Slices `nar` like the range task does when it arrives in `chunk_size` chunks.
+    fn serve_range(nar: &[u8], chunk_size: usize, header: &str) -> Option<Vec<u8>> {
+        let ranges = HttpRange::parse(header, nar.len() as u64).ok()?;
+        let mut out = Vec::new();
+        let mut send = 0;
+        for chunk in nar.chunks(chunk_size) {
+            if let Some(slice) =
+                range_in_chunk(send, chunk.len(), ranges[0].start, ranges[0].length)
+            {
+                out.extend_from_slice(&chunk[slice]);
+            }
+            send += chunk.len() as u64;
+        }
+        Some(out)
+    }
+
+    #[test]
+    fn test_range_in_chunk() {
+        let nar = (0..100u8).collect::<Vec<_>>();
+        for chunk_size in [1, 7, 10, 64, 100, 1000] {
+            // in the middle, crossing chunk boundaries
+            assert_eq!(
+                serve_range(&nar, chunk_size, "bytes=5-34"),
+                Some(nar[5..35].to_vec())
+            );
+            // ending at the exact end
+            assert_eq!(
+                serve_range(&nar, chunk_size, "bytes=90-99"),
+                Some(nar[90..].to_vec())
+            );
+            // exceeding the end is clamped
+            assert_eq!(
+                serve_range(&nar, chunk_size, "bytes=90-200"),
+                Some(nar[90..].to_vec())
+            );
+            // the last byte only
+            assert_eq!(
+                serve_range(&nar, chunk_size, "bytes=-1"),
+                Some(nar[99..].to_vec())
+            );
+            assert_eq!(serve_range(&nar, chunk_size, "bytes=0-"), Some(nar.clone()));
+            // one past the end and zero length ranges are not satisfiable
+            assert_eq!(serve_range(&nar, chunk_size, "bytes=100-"), None);
+            assert_eq!(serve_range(&nar, chunk_size, "bytes=-0"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_nar_stream() -> Result<()> {
+        let chunks = [&b"nix-"[..], b"archive", b"-1"];
+        let digest = openssl::sha::sha256(b"nix-archive-1");
+
+        let collect = |expected: String| async move {
+            let (tx, rx) = sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(10);
+            for chunk in chunks {
+                let _ = tx.send(Ok(Bytes::from_static(chunk))).await;
+            }
+            drop(tx);
+            let mut rx = verify_nar_stream(rx, expected, PathBuf::from("/nix/store/test"));
+            let mut out = Vec::new();
+            while let Some(Ok(chunk)) = rx.recv().await {
+                out.extend_from_slice(&chunk);
+            }
+            out
+        };
+
+        assert_eq!(collect(to_hex(&digest)).await, b"nix-archive-1");
+        assert_eq!(
+            collect(format!("sha256:{}", to_hex(&digest))).await,
+            b"nix-archive-1"
+        );
+        // the last chunk is withheld on a mismatch
+        assert_eq!(collect(to_hex(&[0; 32])).await, b"nix-archive");
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_ranges() -> Result<()> {
+        let ranges = HttpRange::parse("bytes=0-10,20-30", 100)
+            .map_err(|e| anyhow::anyhow!("failed to parse range: {:?}", e))?;
+        assert_eq!(ranges.len(), 2);
+        let res = multiple_ranges_not_satisfiable(HttpResponse::Ok(), 100);
+        assert_eq!(res.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_RANGE),
+            Some(&http::header::HeaderValue::from_static("bytes */100"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_outhash() {
+        let a = "26xbg1ndr7hbcncrlf9nhx5is2b25d13";
+        let b = "sl141d1g77wvhr050ah87lcyz2czdxa3";
+        assert_eq!(select_outhash(Some(a), None), Some(Some(a)));
+        assert_eq!(select_outhash(None, Some(a)), Some(Some(a)));
+        assert_eq!(select_outhash(Some(a), Some(a)), Some(Some(a)));
+        assert_eq!(select_outhash(Some(a), Some(b)), None);
+        assert_eq!(select_outhash(None, None), Some(None));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_nar() -> Result<()> {
+        use async_compression::tokio::bufread::ZstdDecoder;
+
+        let nar = (0..3 * 1024 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let chunks = nar
+            .chunks(64 * 1024)
+            .map(Bytes::copy_from_slice)
+            .collect::<Vec<_>>();
+        task::spawn(async move {
+            for chunk in chunks {
+                let _ = tx.send(Ok(chunk)).await;
+            }
+        });
+        let res = compressed_nar(HttpResponse::Ok(), Compression::Zstd, rx, 60);
+        // ranges of the compressed bytes can't be served
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert!(!res.headers().contains_key(http::header::ACCEPT_RANGES));
+        assert!(!res.headers().contains_key(http::header::CONTENT_RANGE));
+        let body = actix_web::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let mut decoder = ZstdDecoder::new(&body[..]);
+        let mut decompressed = vec![];
+        decoder.read_to_end(&mut decompressed).await?;
+        assert_eq!(decompressed, nar);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_bundle_path_filter() -> Result<()> {
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use std::collections::HashSet;
+
+        let temp_dir = tempfile::tempdir()?;
+        let bundle_path = temp_dir.path().join("bundle.tar");
+        let allowed = "nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar";
+        let denied = "nar/0k8wn8qfcw8hf4jbzpbbhpa6s1ffmsqpxzm7a8lz2h0sdj4fcgzl.nar";
+        let mut builder = tar::Builder::new(fs::File::create(&bundle_path)?);
+        for (hash, name, url) in [
+            ("26xbg1ndr7hbcncrlf9nhx5is2b25d13", "hello-2.12.1", allowed),
+            ("sl141d1g77wvhr050ah87lcyz2czdxa3", "secret", denied),
+        ] {
+            let narinfo = format!("StorePath: /nix/store/{hash}-{name}\nURL: {url}\n");
+            for (path, data) in [
+                (format!("{}.narinfo", hash), narinfo.into_bytes()),
+                (url.to_owned(), format!("nar of {}", name).into_bytes()),
+            ] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, path, &data[..])?;
+            }
+        }
+        builder.finish()?;
+        drop(builder);
+
+        let config = web::Data::new(Config {
+            bundle: Some(crate::bundle::Bundle::open(&bundle_path)?),
+            path_filter: crate::config::PathFilter {
+                allow: None,
+                deny: HashSet::from(["sl141d1g77wvhr050ah87lcyz2czdxa3".to_owned()]),
+            },
+            ..Default::default()
+        });
+        let app = init_service(
+            actix_web::App::new()
+                .app_data(config)
+                .configure(|cfg| crate::routes::configure(cfg, "")),
+        )
+        .await;
+        let status = |uri: String| {
+            let res = call_service(&app, TestRequest::get().uri(&uri).to_request());
+            async move { res.await.status() }
+        };
+        assert_eq!(status(format!("/{}", allowed)).await, http::StatusCode::OK);
+        assert_eq!(
+            status(format!("/{}", denied)).await,
+            http::StatusCode::NOT_FOUND
+        );
+        // naming an allowed path in the query doesn't help either
+        assert_eq!(
+            status(format!("/{}?hash=26xbg1ndr7hbcncrlf9nhx5is2b25d13", denied)).await,
+            http::StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_dump_store() -> Result<()> {
         let temp_dir = tempfile::tempdir()
@@ -1,31 +1,96 @@
-use std::{error::Error, path::Path};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
 
-use actix_web::{http, web, HttpResponse};
+use actix_web::http::header::Header;
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
 use anyhow::Context;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::config::{Config, SigningKey};
+use crate::compression::Compression;
+use crate::config::{Config, SigningKey, STORE_DIR_HEADER};
+use crate::derivation::{drv_output_id, read_system};
 use crate::signing::convert_base16_to_nix32;
 use crate::signing::{fingerprint_path, sign_string};
-use crate::{cache_control_max_age_1d, nixhash, some_or_404};
+use crate::store::Store;
+use crate::upstream::Upstream;
+use crate::{
+    cache_control_max_age, cache_control_max_age_1d, cache_control_no_store, try_nixhash,
+    ServerResult,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Param {
     json: Option<String>,
 }
 
+/// Fields written by harmonia itself, which extra fields must not override.
+pub(crate) const NARINFO_FIELDS: &[&str] = &[
+    "StorePath",
+    "URL",
+    "Compression",
+    "FileHash",
+    "FileSize",
+    "NarHash",
+    "NarSize",
+    "References",
+    "Deriver",
+    "System",
+    "Sig",
+    "CA",
+    "Realisation",
+];
+
+/// Keys of the fields of [`NarInfo`] in its JSON form, which extra fields
+/// must not duplicate either.
+pub(crate) const NARINFO_JSON_FIELDS: &[&str] = &[
+    "store_path",
+    "url",
+    "compression",
+    "nar_hash",
+    "nar_size",
+    "references",
+    "deriver",
+    "system",
+    "sigs",
+    "ca",
+    "realisation",
+];
+
+/// Whether an extra field named `key` would clash with a standard field in
+/// the text or JSON form of a narinfo. Compared case-insensitively, since
+/// clients don't agree on the case.
+pub(crate) fn is_standard_field(key: &str) -> bool {
+    NARINFO_FIELDS
+        .iter()
+        .chain(NARINFO_JSON_FIELDS)
+        .any(|field| field.eq_ignore_ascii_case(key))
+}
+
 #[derive(Debug, Serialize)]
-struct NarInfo {
-    store_path: String,
-    url: String,
-    compression: String,
-    nar_hash: String,
-    nar_size: u64,
-    references: Vec<String>,
-    deriver: Option<String>,
-    sigs: Vec<String>,
-    ca: Option<String>,
+pub(crate) struct NarInfo {
+    pub(crate) store_path: String,
+    pub(crate) url: String,
+    pub(crate) compression: String,
+    pub(crate) nar_hash: String,
+    pub(crate) nar_size: u64,
+    pub(crate) references: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deriver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) system: Option<String>,
+    pub(crate) sigs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ca: Option<String>,
+    /// Id of the realisation of a content-addressed derivation output, like
+    /// `sha256:<hash>!out`, with `narinfo_realisations`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) realisation: Option<String>,
+    /// Additional fields, e.g. configured with `extra_narinfo_fields`.
+    #[serde(flatten)]
+    pub(crate) extra: BTreeMap<String, String>,
 }
 
 fn extract_filename(path: &str) -> Option<String> {
@@ -34,22 +99,59 @@ fn extract_filename(path: &str) -> Option<String> {
         .and_then(|v| v.to_str().map(ToOwned::to_owned))
 }
 
+fn nar_url(hash: &str, nar_hash: &str, compression: Compression, query_free: bool) -> String {
+    let ext = compression
+        .extension()
+        .map(|ext| format!(".{}", ext))
+        .unwrap_or_default();
+    if query_free {
+        // some proxies and CDNs don't take the query string into account for caching
+        format!("nar/{}-{}.nar{}", hash, nar_hash, ext)
+    } else {
+        format!("nar/{}.nar{}?hash={}", nar_hash, ext, hash)
+    }
+}
+
+/// The id of the realisation of `store_path` built by `deriver`, if the
+/// daemon has one. Errors only leave out the field, as Nix doesn't need it.
+async fn realisation_id(store: &Store, deriver: &str, store_path: &str) -> Option<String> {
+    let id = match drv_output_id(store, deriver, store_path).await {
+        Ok(id) => id?,
+        Err(e) => {
+            log::debug!(
+                "Failed to compute the derivation output of {}: {:#}",
+                store_path,
+                e
+            );
+            return None;
+        }
+    };
+    match store.daemon.lock().await.query_realisation(&id).await {
+        Ok(realisations) => (!realisations.is_empty()).then_some(id),
+        Err(e) => {
+            log::warn!("Failed to query realisation {}: {:#}", id, e);
+            None
+        }
+    }
+}
+
+/// Makes `url` absolute by prefixing it with `public_url`, if set.
+pub(crate) fn with_public_url(public_url: Option<&str>, url: String) -> String {
+    match public_url {
+        Some(public_url) => format!("{}/{}", public_url.trim_end_matches('/'), url),
+        None => url,
+    }
+}
+
 async fn query_narinfo(
-    virtual_nix_store: &str,
+    store: &Store,
     store_path: &str,
     hash: &str,
     sign_keys: &Vec<SigningKey>,
+    compression: Compression,
     settings: &web::Data<Config>,
 ) -> Result<Option<NarInfo>> {
-    let path_info = match settings
-        .store
-        .daemon
-        .lock()
-        .await
-        .query_path_info(store_path)
-        .await?
-        .path
-    {
+    let path_info = match store.query_path_info(store_path).await? {
         Some(info) => info,
         None => {
             return Ok(None);
@@ -59,8 +161,11 @@ async fn query_narinfo(
         convert_base16_to_nix32(&path_info.hash).context("failed to convert path info hash")?;
     let mut res = NarInfo {
         store_path: store_path.into(),
-        url: format!("nar/{}.nar?hash={}", nar_hash, hash),
-        compression: "none".into(),
+        url: with_public_url(
+            settings.public_url.as_deref(),
+            nar_url(hash, &nar_hash, compression, settings.query_free_nar_urls),
+        ),
+        compression: compression.name().into(),
         nar_hash: format!("sha256:{}", nar_hash),
         nar_size: path_info.nar_size,
         references: vec![],
@@ -69,9 +174,19 @@ async fn query_narinfo(
         } else {
             extract_filename(&path_info.deriver)
         },
+        system: if path_info.deriver.is_empty() {
+            None
+        } else {
+            read_system(&store.get_real_path(Path::new(&path_info.deriver))).await
+        },
         sigs: vec![],
         ca: path_info.content_address,
+        realisation: None,
+        extra: settings.extra_narinfo_fields.clone(),
     };
+    if settings.narinfo_realisations && res.ca.is_some() && !path_info.deriver.is_empty() {
+        res.realisation = realisation_id(store, &path_info.deriver, store_path).await;
+    }
 
     let refs = path_info.references.clone();
     if !path_info.references.is_empty() {
@@ -83,90 +198,592 @@ async fn query_narinfo(
     }
 
     let fingerprint = fingerprint_path(
-        virtual_nix_store,
+        store.virtual_store(),
         store_path,
         &res.nar_hash,
         res.nar_size,
         &refs,
     )?;
+    let mut own_sigs = vec![];
     for sk in sign_keys {
         if let Some(ref fp) = fingerprint {
-            res.sigs.push(sign_string(sk, fp));
+            own_sigs.push(sign_string(sk, fp));
         }
     }
+    res.sigs = narinfo_sigs(own_sigs, path_info.sigs, settings.merge_signatures);
+
+    Ok(Some(res))
+}
 
-    if res.sigs.is_empty() {
-        res.sigs.clone_from(&path_info.sigs);
+/// Refuses narinfos without signatures if `require_signatures` is set, since
+/// clients would reject them anyway, with less helpful errors.
+fn reject_unsigned(narinfo: &NarInfo, settings: &Config) -> Option<HttpResponse> {
+    if !settings.require_signatures || !narinfo.sigs.is_empty() {
+        return None;
     }
+    log::error!(
+        "refusing to serve the narinfo of {} without signatures",
+        narinfo.store_path
+    );
+    Some(
+        HttpResponse::InternalServerError()
+            .insert_header(cache_control_no_store())
+            .body("narinfo has no signatures"),
+    )
+}
 
-    Ok(Some(res))
+/// Picks the signatures to serve from the ones made with the signing keys
+/// and the ones already attached to the path. The attached ones are only
+/// served if there are no own ones, unless `merge` is set.
+fn narinfo_sigs(own: Vec<String>, attached: Vec<String>, merge: bool) -> Vec<String> {
+    if own.is_empty() {
+        return attached;
+    }
+    if !merge {
+        return own;
+    }
+    let mut sigs = attached;
+    // signatures persisted by `harmonia resign` are the same as the own ones
+    for sig in own {
+        if !sigs.contains(&sig) {
+            sigs.push(sig);
+        }
+    }
+    sigs
 }
 
-fn format_narinfo_txt(narinfo: &NarInfo) -> String {
-    let mut res = vec![
-        format!("StorePath: {}", narinfo.store_path),
-        format!("URL: {}", narinfo.url),
-        format!("Compression: {}", narinfo.compression),
-        format!("FileHash: {}", narinfo.nar_hash),
-        format!("FileSize: {}", narinfo.nar_size),
-        format!("NarHash: {}", narinfo.nar_hash),
-        format!("NarSize: {}", narinfo.nar_size),
-    ];
+fn write_narinfo_txt<W: fmt::Write>(w: &mut W, narinfo: &NarInfo) -> fmt::Result {
+    writeln!(w, "StorePath: {}", narinfo.store_path)?;
+    writeln!(w, "URL: {}", narinfo.url)?;
+    writeln!(w, "Compression: {}", narinfo.compression)?;
+
+    // The compressed size is only known once the NAR has been streamed,
+    // so FileHash and FileSize are omitted for compressed NARs.
+    if narinfo.compression == Compression::None.name() {
+        writeln!(w, "FileHash: {}", narinfo.nar_hash)?;
+        writeln!(w, "FileSize: {}", narinfo.nar_size)?;
+    }
+    writeln!(w, "NarHash: {}", narinfo.nar_hash)?;
+    writeln!(w, "NarSize: {}", narinfo.nar_size)?;
 
     if !narinfo.references.is_empty() {
-        res.push(format!("References: {}", &narinfo.references.join(" ")));
+        writeln!(w, "References: {}", narinfo.references.join(" "))?;
     }
 
     if let Some(drv) = &narinfo.deriver {
-        res.push(format!("Deriver: {}", drv));
+        writeln!(w, "Deriver: {}", drv)?;
+    }
+
+    if let Some(system) = &narinfo.system {
+        writeln!(w, "System: {}", system)?;
     }
 
     for sig in &narinfo.sigs {
-        res.push(format!("Sig: {}", sig));
+        writeln!(w, "Sig: {}", sig)?;
     }
 
     if let Some(ca) = &narinfo.ca {
-        res.push(format!("CA: {}", ca));
+        writeln!(w, "CA: {}", ca)?;
+    }
+
+    if let Some(realisation) = &narinfo.realisation {
+        writeln!(w, "Realisation: {}", realisation)?;
+    }
+
+    for (key, value) in &narinfo.extra {
+        writeln!(w, "{}: {}", key, value)?;
+    }
+    Ok(())
+}
+
+fn format_narinfo_txt(narinfo: &NarInfo) -> String {
+    let mut res = String::new();
+    // writing to a String can't fail
+    let _ = write_narinfo_txt(&mut res, narinfo);
+    res
+}
+
+/// Parses a narinfo in the text format, e.g. one uploaded by `nix copy`.
+pub(crate) fn parse_narinfo_txt(s: &str) -> Result<NarInfo> {
+    let mut store_path = None;
+    let mut url = None;
+    let mut compression = None;
+    let mut nar_hash = None;
+    let mut nar_size = None;
+    let mut references = vec![];
+    let mut deriver = None;
+    let mut system = None;
+    let mut sigs = vec![];
+    let mut ca = None;
+    let mut realisation = None;
+    let mut extra = BTreeMap::new();
+
+    for line in s.lines().filter(|l| !l.is_empty()) {
+        let (key, value) = line
+            .split_once(": ")
+            .with_context(|| format!("invalid narinfo line: {}", line))?;
+        match key {
+            "StorePath" => store_path = Some(value.to_owned()),
+            "URL" => url = Some(value.to_owned()),
+            "Compression" => compression = Some(value.to_owned()),
+            "NarHash" => nar_hash = Some(value.to_owned()),
+            "NarSize" => {
+                nar_size = Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid NarSize: {}", value))?,
+                )
+            }
+            "References" => references = value.split_whitespace().map(ToOwned::to_owned).collect(),
+            "Deriver" => deriver = Some(value.to_owned()),
+            "System" => system = Some(value.to_owned()),
+            "Sig" => sigs.push(value.to_owned()),
+            "CA" => ca = Some(value.to_owned()),
+            "Realisation" => realisation = Some(value.to_owned()),
+            // FileHash and FileSize are recomputed when formatting
+            "FileHash" | "FileSize" => {}
+            _ => {
+                extra.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    Ok(NarInfo {
+        store_path: store_path.context("narinfo is missing StorePath")?,
+        url: url.context("narinfo is missing URL")?,
+        compression: compression.unwrap_or_else(|| "bzip2".into()),
+        nar_hash: nar_hash.context("narinfo is missing NarHash")?,
+        nar_size: nar_size.context("narinfo is missing NarSize")?,
+        references,
+        deriver,
+        system,
+        sigs,
+        ca,
+        realisation,
+        extra,
+    })
+}
+
+/// Whether the narinfo should be returned as JSON, either requested with `?json`
+/// or by preferring `application/json` in the Accept header.
+fn wants_json(param: &Param, req: &HttpRequest) -> bool {
+    param.json.is_some()
+        || http::header::Accept::parse(req)
+            .is_ok_and(|accept| accept.preference() == mime::APPLICATION_JSON)
+}
+
+/// The request headers that narinfo responses depend on.
+fn narinfo_vary(settings: &Config) -> String {
+    let mut vary = vec!["Accept"];
+    if !settings.compression.is_empty() {
+        // the advertised compression depends on the client's Accept-Encoding
+        vary.push("Accept-Encoding");
+    }
+    if !settings.extra_stores.is_empty() {
+        vary.push(STORE_DIR_HEADER);
+    }
+    vary.join(", ")
+}
+
+/// Answers a narinfo request for a path that is not in the cache.
+fn missing_narinfo(status: u16, cache_control: http::header::CacheControl) -> HttpResponse {
+    let status = http::StatusCode::from_u16(status).unwrap_or(http::StatusCode::NOT_FOUND);
+    let mut res = HttpResponse::build(status);
+    res.insert_header(cache_control);
+    if status.is_success() {
+        res.finish()
+    } else {
+        res.body("missed hash")
+    }
+}
+
+/// Fetches the narinfo of `hash` from upstream, or from the local copy if cached.
+async fn upstream_narinfo(
+    settings: &Config,
+    upstream: &Upstream,
+    hash: &str,
+) -> anyhow::Result<Option<Bytes>> {
+    let Some(cache) = &settings.upstream_cache else {
+        return upstream.narinfo(hash).await;
+    };
+    if let Some(narinfo) = cache.narinfo(hash).await {
+        return Ok(Some(narinfo.into()));
     }
+    let Some(narinfo) = upstream.narinfo(hash).await? else {
+        return Ok(None);
+    };
+    let text = String::from_utf8_lossy(&narinfo);
+    let store_dir = settings.store.virtual_store();
+    match cache
+        .insert_narinfo(hash, &text, store_dir, &settings.public_keys)
+        .await?
+    {
+        Some(cached) => Ok(Some(cached.into())),
+        None => {
+            log::debug!("not caching {}.narinfo without a trusted signature", hash);
+            Ok(Some(narinfo))
+        }
+    }
+}
 
-    res.push("".into());
-    res.join("\n")
+/// Answers a narinfo request for a path that is not in the cache, from upstream if possible.
+async fn narinfo_miss(
+    settings: &Config,
+    hash: &str,
+    cache_control: http::header::CacheControl,
+) -> ServerResult {
+    if let Some(upstream) = &settings.upstream_proxy {
+        if settings.path_filter.is_allowed(hash) {
+            if let Some(narinfo) = upstream_narinfo(settings, upstream, hash).await? {
+                return Ok(HttpResponse::Ok()
+                    .insert_header((http::header::CONTENT_TYPE, "text/x-nix-narinfo"))
+                    .insert_header(cache_control_max_age(
+                        settings.narinfo_cache_control_max_age,
+                    ))
+                    .body(narinfo));
+            }
+        }
+    }
+    Ok(missing_narinfo(
+        settings.narinfo_missing_status,
+        cache_control,
+    ))
 }
 
 pub(crate) async fn get(
     hash: web::Path<String>,
     param: web::Query<Param>,
+    req: HttpRequest,
     settings: web::Data<Config>,
-) -> Result<HttpResponse, Box<dyn Error>> {
+) -> ServerResult {
+    if let Some(res) = settings.maintenance_mode.check() {
+        return Ok(res);
+    }
     let hash = hash.into_inner();
-    let store_path = some_or_404!(nixhash(&settings, &hash).await);
+    if let Some(bundle) = &settings.bundle {
+        let narinfo = match bundle
+            .narinfo(&hash)
+            .filter(|_| settings.path_filter.is_allowed(&hash))
+        {
+            Some(narinfo) => narinfo,
+            None => return narinfo_miss(&settings, &hash, cache_control_no_store()).await,
+        };
+        return Ok(HttpResponse::Ok()
+            .insert_header((http::header::CONTENT_TYPE, "text/x-nix-narinfo"))
+            .insert_header(cache_control_max_age(
+                settings.narinfo_cache_control_max_age,
+            ))
+            .body(narinfo.to_owned()));
+    }
+    let store = settings.store_for(&req);
+    // a hung daemon must not make clients cache a miss
+    let store_path = match try_nixhash(&settings, store, &hash).await? {
+        Some(store_path) => store_path,
+        None => return narinfo_miss(&settings, &hash, cache_control_no_store()).await,
+    };
+    if req.method() == http::Method::HEAD
+        && !wants_json(&param, &req)
+        && !settings.require_signatures
+    {
+        // Probes, e.g. by `nix copy --to`, only look at the status. The hash
+        // part lookup above only finds valid paths, so the path info isn't
+        // queried for them; the length and Nix-Link would need it. Whether
+        // the path is signed needs it too.
+        return Ok(HttpResponse::Ok()
+            .insert_header((http::header::VARY, narinfo_vary(&settings)))
+            .insert_header((http::header::CONTENT_TYPE, "text/x-nix-narinfo"))
+            .insert_header(cache_control_max_age(
+                settings.narinfo_cache_control_max_age,
+            ))
+            .body(actix_web::body::None::new()));
+    }
+    let accept_encoding = req
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let compression = Compression::negotiate(accept_encoding, &settings.compression);
+    let sign_keys = if settings.sign_narinfos {
+        settings.secret_keys.load_full()
+    } else {
+        Default::default()
+    };
     let narinfo = match query_narinfo(
-        settings.store.virtual_store(),
+        store,
         &store_path,
         &hash,
-        &settings.secret_keys,
+        &sign_keys,
+        compression,
         &settings,
     )
     .await?
     {
         Some(narinfo) => narinfo,
-        None => {
-            return Ok(HttpResponse::NotFound()
-                .insert_header(cache_control_max_age_1d())
-                .body("missed hash"))
-        }
+        None => return narinfo_miss(&settings, &hash, cache_control_max_age_1d()).await,
     };
+    if let Some(res) = reject_unsigned(&narinfo, &settings) {
+        return Ok(res);
+    }
 
-    if param.json.is_some() {
-        Ok(HttpResponse::Ok()
-            .insert_header(cache_control_max_age_1d())
+    let mut res = HttpResponse::Ok();
+    res.insert_header((http::header::VARY, narinfo_vary(&settings)));
+
+    if wants_json(&param, &req) {
+        Ok(res
+            .insert_header(cache_control_max_age(
+                settings.narinfo_cache_control_max_age,
+            ))
             .json(narinfo))
     } else {
-        let res = format_narinfo_txt(&narinfo);
-        Ok(HttpResponse::Ok()
-            .insert_header((http::header::CONTENT_TYPE, "text/x-nix-narinfo"))
-            .insert_header(("Nix-Link", narinfo.url))
-            .insert_header(cache_control_max_age_1d())
-            .body(res))
+        res.insert_header((http::header::CONTENT_TYPE, "text/x-nix-narinfo"))
+            .insert_header(("Nix-Link", narinfo.url.clone()))
+            .insert_header(cache_control_max_age(
+                settings.narinfo_cache_control_max_age,
+            ));
+        Ok(res.body(format_narinfo_txt(&narinfo)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wants_json() {
+        let text = Param { json: None };
+        let json = Param {
+            json: Some("".into()),
+        };
+        let req = |accept: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(accept) = accept {
+                req = req.insert_header((http::header::ACCEPT, accept));
+            }
+            req.to_http_request()
+        };
+        assert!(!wants_json(&text, &req(None)));
+        assert!(wants_json(&json, &req(None)));
+        assert!(wants_json(&text, &req(Some("application/json"))));
+        assert!(!wants_json(&text, &req(Some("text/x-nix-narinfo"))));
+        assert!(!wants_json(&text, &req(Some("*/*"))));
+        assert!(!wants_json(
+            &text,
+            &req(Some("application/json;q=0.5, text/x-nix-narinfo"))
+        ));
+        assert!(wants_json(&json, &req(Some("text/x-nix-narinfo"))));
+    }
+
+    #[test]
+    fn test_missing_narinfo() {
+        let res = missing_narinfo(404, cache_control_no_store());
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers().get(http::header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+
+        let res = missing_narinfo(200, cache_control_max_age_1d());
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(
+            res.headers().get(http::header::CACHE_CONTROL).unwrap(),
+            "max-age=86400"
+        );
+        assert_eq!(
+            actix_web::body::MessageBody::size(res.body()),
+            actix_web::body::BodySize::Sized(0)
+        );
+    }
+
+    #[test]
+    fn test_nar_url() {
+        let hash = "26xbg1ndr7hbcncrlf9nhx5is2b25d13";
+        let nar_hash = "1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh";
+        assert_eq!(
+            nar_url(hash, nar_hash, Compression::None, false),
+            format!("nar/{}.nar?hash={}", nar_hash, hash)
+        );
+        assert_eq!(
+            nar_url(hash, nar_hash, Compression::Zstd, false),
+            format!("nar/{}.nar.zst?hash={}", nar_hash, hash)
+        );
+        assert_eq!(
+            nar_url(hash, nar_hash, Compression::None, true),
+            format!("nar/{}-{}.nar", hash, nar_hash)
+        );
+        assert_eq!(
+            nar_url(hash, nar_hash, Compression::Xz, true),
+            format!("nar/{}-{}.nar.xz", hash, nar_hash)
+        );
+
+        let url = nar_url(hash, nar_hash, Compression::None, true);
+        assert_eq!(with_public_url(None, url.clone()), url);
+        for public_url in ["https://example.com/cache", "https://example.com/cache/"] {
+            assert_eq!(
+                with_public_url(Some(public_url), url.clone()),
+                format!("https://example.com/cache/nar/{}-{}.nar", hash, nar_hash)
+            );
+        }
+    }
+
+    #[test]
+    fn test_narinfo_sigs() {
+        let own = "cache.example.com-1:aRjUCqKG+v6GyL8Bl9pCdoQbmc4wPM1iKIRUgnX6cAgQfKdIdNqgC4oTBobO6Wg7rctdqSCJhpMu5LIUC2Y9Bw==".to_owned();
+        let upstream = "cache.nixos.org-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==".to_owned();
+
+        assert_eq!(
+            narinfo_sigs(vec![own.clone()], vec![upstream.clone()], false),
+            vec![own.clone()]
+        );
+        assert_eq!(
+            narinfo_sigs(vec![], vec![upstream.clone()], false),
+            vec![upstream.clone()]
+        );
+        let sigs = narinfo_sigs(vec![own.clone()], vec![upstream.clone()], true);
+        assert_eq!(sigs, vec![upstream.clone(), own.clone()]);
+        let txt = format_narinfo_txt(&NarInfo {
+            store_path: "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into(),
+            url: "nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar".into(),
+            compression: "none".into(),
+            nar_hash: "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into(),
+            nar_size: 226560,
+            references: vec![],
+            deriver: None,
+            system: None,
+            sigs,
+            ca: None,
+            realisation: None,
+            extra: Default::default(),
+        });
+        assert!(txt.contains(&format!("Sig: {}\n", upstream)));
+        assert!(txt.contains(&format!("Sig: {}\n", own)));
+
+        // persisted by `harmonia resign`, so it is already attached
+        assert_eq!(
+            narinfo_sigs(vec![own.clone()], vec![own.clone(), upstream.clone()], true),
+            vec![own, upstream]
+        );
+    }
+
+    #[test]
+    fn test_reject_unsigned() {
+        let mut narinfo = NarInfo {
+            store_path: "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into(),
+            url: "nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar".into(),
+            compression: "none".into(),
+            nar_hash: "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into(),
+            nar_size: 226560,
+            references: vec![],
+            deriver: None,
+            system: None,
+            sigs: vec![],
+            ca: None,
+            realisation: None,
+            extra: Default::default(),
+        };
+        let mut settings = Config::default();
+        assert!(reject_unsigned(&narinfo, &settings).is_none());
+
+        settings.require_signatures = true;
+        let res = reject_unsigned(&narinfo, &settings).unwrap();
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        narinfo.sigs = vec!["cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==".into()];
+        assert!(reject_unsigned(&narinfo, &settings).is_none());
+    }
+
+    #[test]
+    fn test_narinfo_roundtrip() -> Result<()> {
+        let narinfo = NarInfo {
+            store_path: "/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into(),
+            url: "nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar?hash=26xbg1ndr7hbcncrlf9nhx5is2b25d13".into(),
+            compression: "none".into(),
+            nar_hash: "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into(),
+            nar_size: 226560,
+            references: vec![
+                "26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into(),
+                "sl141d1g77wvhr050ah87lcyz2czdxa3-glibc-2.40-36".into(),
+            ],
+            deriver: Some("5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv".into()),
+            system: Some("x86_64-linux".into()),
+            sigs: vec!["cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==".into()],
+            ca: None,
+            realisation: None,
+            extra: BTreeMap::from([("X-Team".into(), "infra".into())]),
+        };
+        let txt = format_narinfo_txt(&narinfo);
+        assert!(txt.contains(
+            "\nDeriver: 5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv\nSystem: x86_64-linux\n"
+        ));
+        let parsed = parse_narinfo_txt(&txt)?;
+        assert_eq!(parsed.store_path, narinfo.store_path);
+        assert_eq!(parsed.url, narinfo.url);
+        assert_eq!(parsed.compression, narinfo.compression);
+        assert_eq!(parsed.nar_hash, narinfo.nar_hash);
+        assert_eq!(parsed.nar_size, narinfo.nar_size);
+        assert_eq!(parsed.references, narinfo.references);
+        assert_eq!(parsed.deriver, narinfo.deriver);
+        assert_eq!(parsed.sigs, narinfo.sigs);
+        assert_eq!(parsed.system, narinfo.system);
+        assert_eq!(parsed.ca, narinfo.ca);
+        assert_eq!(parsed.extra, narinfo.extra);
+
+        let json = serde_json::to_value(&narinfo)?;
+        assert_eq!(json["X-Team"], "infra");
+
+        let without_deriver = NarInfo {
+            deriver: None,
+            system: None,
+            ..narinfo
+        };
+        let txt = format_narinfo_txt(&without_deriver);
+        assert!(!txt.contains("Deriver:") && !txt.contains("System:"));
+        let json = serde_json::to_value(&without_deriver)?;
+        for key in ["deriver", "system", "ca", "realisation"] {
+            assert!(json.get(key).is_none(), "{} in {}", key, json);
+        }
+
+        let with_ca = NarInfo {
+            ca: Some("fixed:r:sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh".into()),
+            realisation: Some(
+                "sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!out"
+                    .into(),
+            ),
+            ..without_deriver
+        };
+        let json = serde_json::to_value(&with_ca)?;
+        assert_eq!(
+            json["ca"],
+            "fixed:r:sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh"
+        );
+        assert_eq!(json["realisation"], with_ca.realisation.as_deref().unwrap());
+        let txt = format_narinfo_txt(&with_ca);
+        assert!(txt.ends_with(
+            "Realisation: sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!out\nX-Team: infra\n"
+        ));
+        assert_eq!(parse_narinfo_txt(&txt)?.realisation, with_ca.realisation);
+
+        // extra fields are checked against the JSON keys of all fields
+        let all_fields = NarInfo {
+            deriver: Some("5w5fkyb7kv0b0fgvrbc4f1ckqmchhnx6-hello-2.12.1.drv".into()),
+            system: Some("x86_64-linux".into()),
+            extra: BTreeMap::new(),
+            ..with_ca
+        };
+        let json = serde_json::to_value(&all_fields)?;
+        let mut keys = json
+            .as_object()
+            .context("narinfo is not a JSON object")?
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let mut expected = NARINFO_JSON_FIELDS.to_vec();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert!(is_standard_field("nar_hash") && is_standard_field("NARHASH"));
+        assert!(!is_standard_field("X-Team"));
+
+        assert!(parse_narinfo_txt("URL: nar/foo.nar\n").is_err());
+        Ok(())
     }
 }
@@ -1,18 +1,25 @@
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-
-use actix_web::{http, web, HttpResponse};
-use anyhow::{bail, Context, Result};
-use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
 use std::fs::Metadata;
+use std::io;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs::symlink_metadata;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task;
 
 use crate::config::Config;
+use crate::nar::{alignment, strip_case_hack_suffix, unsupported_file_type};
 use crate::{cache_control_max_age_1y, nixhash, some_or_404};
 
-use std::collections::HashMap;
-use std::path::PathBuf;
-use tokio::fs::symlink_metadata;
+/// Size of the chunks the listing is sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
 
 fn is_false(b: &bool) -> bool {
     !b
@@ -36,124 +43,222 @@ enum NarEntry {
     Symlink { target: String },
 }
 
-#[derive(Debug, Serialize, Eq, PartialEq)]
-struct NarList {
-    version: u16,
-    root: NarEntry,
+/// Collects the JSON and sends it in chunks, so that the listing of a huge
+/// tree never has to be held in memory as a whole.
+struct JsonWriter {
+    buf: Vec<u8>,
+    tx: Sender<io::Result<Bytes>>,
+}
+
+impl JsonWriter {
+    fn new(tx: Sender<io::Result<Bytes>>) -> Self {
+        Self {
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            tx,
+        }
+    }
+
+    fn push(&mut self, s: &str) {
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn push_value<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        serde_json::to_writer(&mut self.buf, value).context("Failed to serialize entry")
+    }
+
+    async fn send(&mut self) -> Result<()> {
+        let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .send(Ok(Bytes::from(buf)))
+            .await
+            .context("Failed to send")
+    }
+
+    async fn send_if_full(&mut self) -> Result<()> {
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send().await?;
+        }
+        Ok(())
+    }
 }
 
+/// A directory whose entries are being written, in the order of `nar::dump_path`.
 struct Frame {
     path: PathBuf,
-    nar_entry: NarEntry,
-    dir_entry: tokio::fs::ReadDir,
+    children: BTreeMap<OsString, OsString>,
+    first_child: bool,
 }
 
-fn file_entry(metadata: Metadata) -> NarEntry {
+fn file_entry(metadata: &Metadata, nar_offset: u64) -> NarEntry {
     NarEntry::Regular {
         size: metadata.len(),
-        executable: metadata.permissions().mode() & 0o111 != 0,
-        nar_offset: None,
+        // same check as nar::dump_file
+        executable: metadata.permissions().mode() & 0o100 != 0,
+        nar_offset: Some(nar_offset),
     }
 }
 
+/// Returns `s` as a JSON compatible string.
+///
+/// Like `nix nar ls --json`, the listing fails for names that aren't valid
+/// UTF-8, instead of listing a lossy name that doesn't match the NAR.
+fn utf8<'a>(s: &'a OsStr, path: &Path) -> Result<&'a str> {
+    s.to_str()
+        .with_context(|| format!("{:?} in {:?} is not valid UTF-8", s, path))
+}
+
 async fn symlink_entry(path: &Path) -> Result<NarEntry> {
-    let target = tokio::fs::read_link(&path).await?;
+    let target = tokio::fs::read_link(&path)
+        .await
+        .with_context(|| format!("Failed to read symlink {:?}", path))?;
     Ok(NarEntry::Symlink {
-        target: target.to_string_lossy().into_owned(),
+        target: utf8(target.as_os_str(), path)?.to_owned(),
     })
 }
 
-async fn get_nar_list(path: PathBuf) -> Result<NarList> {
-    let st = symlink_metadata(&path).await?;
+/// Length of a string in the NAR serialization, including its length and padding.
+fn nar_str_len(s: &str) -> u64 {
+    (8 + s.len() + alignment(s.len() as u64)) as u64
+}
 
-    let file_type = st.file_type();
-    let root = if file_type.is_file() {
-        file_entry(st)
+/// Writes the node at `path`, whose serialization starts at `offset` in the
+/// NAR. Files and symlinks are written completely and the offset after them
+/// is returned; directories are only opened and returned as a frame, with the
+/// offset after their header.
+async fn write_node(
+    out: &mut JsonWriter,
+    path: PathBuf,
+    mut offset: u64,
+) -> Result<(u64, Option<Frame>)> {
+    let metadata = symlink_metadata(&path)
+        .await
+        .with_context(|| format!("Failed to get metadata of {:?}", path))?;
+    let file_type = metadata.file_type();
+    offset += nar_str_len("(") + nar_str_len("type");
+    if file_type.is_file() {
+        offset += nar_str_len("regular");
+        if metadata.permissions().mode() & 0o100 != 0 {
+            offset += nar_str_len("executable") + nar_str_len("");
+        }
+        // the contents follow their size
+        offset += nar_str_len("contents") + 8;
+        out.push_value(&file_entry(&metadata, offset))?;
+        offset += metadata.len() + alignment(metadata.len()) as u64;
+        Ok((offset + nar_str_len(")"), None))
     } else if file_type.is_symlink() {
-        symlink_entry(&path)
-            .await
-            .with_context(|| format!("Failed to read symlink {:?}", path))?
+        let entry = symlink_entry(&path).await?;
+        if let NarEntry::Symlink { target } = &entry {
+            offset += nar_str_len("symlink") + nar_str_len("target") + nar_str_len(target);
+        }
+        out.push_value(&entry)?;
+        Ok((offset + nar_str_len(")"), None))
     } else if file_type.is_dir() {
-        let dir_entry = tokio::fs::read_dir(&path)
+        let mut read_dir = tokio::fs::read_dir(&path)
             .await
             .with_context(|| format!("Failed to read directory {:?}", path))?;
-        let mut stack = vec![Frame {
+        let mut children = BTreeMap::new();
+        while let Some(e) = read_dir
+            .next_entry()
+            .await
+            .context("Failed to read directory")?
+        {
+            let file_name = e.file_name();
+            children.insert(strip_case_hack_suffix(&file_name).to_owned(), file_name);
+        }
+        out.push(r#"{"type":"directory","entries":{"#);
+        let frame = Frame {
             path,
-            dir_entry,
-            nar_entry: NarEntry::Directory {
-                entries: HashMap::new(),
-            },
-        }];
-
-        let mut root: Option<NarEntry> = None;
-
-        while let Some(frame) = stack.last_mut() {
-            if let Some(entry) = frame.dir_entry.next_entry().await? {
-                let name = entry.file_name().to_string_lossy().into_owned();
-                let entry_path = entry.path();
-                let entry_st = symlink_metadata(&entry_path).await?;
-                let entry_file_type = entry_st.file_type();
-
-                let entries = match &mut frame.nar_entry {
-                    NarEntry::Directory { entries, .. } => entries,
-                    _ => unreachable!(),
-                };
-                if entry_file_type.is_file() {
-                    entries.insert(name, file_entry(entry_st));
-                } else if entry_file_type.is_symlink() {
-                    entries.insert(
-                        name,
-                        symlink_entry(&entry_path)
-                            .await
-                            .with_context(|| format!("Failed to read symlink {:?}", entry_path))?,
-                    );
-                } else if entry_file_type.is_dir() {
-                    let dir_entry = tokio::fs::read_dir(&entry_path).await?;
-                    stack.push(Frame {
-                        path: entry_path,
-                        dir_entry,
-                        nar_entry: NarEntry::Directory {
-                            entries: HashMap::new(),
-                        },
-                    });
-                }
-            } else {
-                let entry = stack.pop().unwrap();
-                if let Some(frame) = stack.last_mut() {
-                    let name = match entry.path.file_name() {
-                        Some(name) => name.to_string_lossy().into_owned(),
-                        None => bail!("Failed to get file name {:?}", entry.path),
-                    };
-                    let entries = match &mut frame.nar_entry {
-                        NarEntry::Directory { entries, .. } => entries,
-                        _ => unreachable!(),
-                    };
-                    entries.insert(name, entry.nar_entry);
-                } else {
-                    root = Some(entry.nar_entry);
-                }
+            children,
+            first_child: true,
+        };
+        Ok((offset + nar_str_len("directory"), Some(frame)))
+    } else {
+        Err(unsupported_file_type(&path, file_type))
+    }
+}
+
+/// Writes the listing of `path` as JSON, in the format of
+/// `nix nar ls --json --recursive` wrapped into `{"version":1,"root":...}`,
+/// including the offset of every regular file in the NAR.
+///
+/// Entries are written while walking the tree, so only the names of the
+/// directories currently being listed are kept in memory.
+async fn write_nar_list(path: PathBuf, tx: Sender<io::Result<Bytes>>) -> Result<()> {
+    let mut out = JsonWriter::new(tx);
+    out.push(r#"{"version":1,"root":"#);
+
+    let (mut offset, root) = write_node(&mut out, path, nar_str_len("nix-archive-1")).await?;
+    let mut stack = root.into_iter().collect::<Vec<_>>();
+
+    while let Some(frame) = stack.last_mut() {
+        if let Some((nar_name, name)) = frame.children.pop_first() {
+            if !std::mem::take(&mut frame.first_child) {
+                out.push(",");
+            }
+            let nar_name = utf8(&nar_name, &frame.path)?;
+            out.push_value(&nar_name)?;
+            out.push(":");
+            offset += nar_str_len("entry")
+                + nar_str_len("(")
+                + nar_str_len("name")
+                + nar_str_len(nar_name)
+                + nar_str_len("node");
+            let path = frame.path.join(name);
+            let (next_offset, dir) = write_node(&mut out, path, offset).await?;
+            offset = next_offset;
+            match dir {
+                Some(dir) => stack.push(dir),
+                // end entry
+                None => offset += nar_str_len(")"),
+            }
+        } else {
+            out.push("}}");
+            offset += nar_str_len(")");
+            stack.pop();
+            if !stack.is_empty() {
+                // end the entry of the directory in its parent
+                offset += nar_str_len(")");
             }
         }
+        out.send_if_full().await?;
+    }
 
-        root.unwrap()
-    } else {
-        return Err(anyhow::anyhow!("Unsupported file type {:?}", path));
-    };
+    out.push("}");
+    out.send().await
+}
 
-    Ok(NarList { version: 1, root })
+/// Resolves `hash` to the location of its store path on disk.
+async fn real_store_path(settings: &web::Data<Config>, hash: &str) -> Option<PathBuf> {
+    let store_path = nixhash(settings, hash).await?;
+    Some(settings.store.get_real_path(&PathBuf::from(store_path)))
 }
 
 pub(crate) async fn get(
     hash: web::Path<String>,
+    req: HttpRequest,
     settings: web::Data<Config>,
 ) -> Result<HttpResponse, Box<dyn Error>> {
-    let store_path = PathBuf::from(some_or_404!(nixhash(&settings, &hash).await));
+    let path = some_or_404!(real_store_path(&settings, &hash).await);
+    some_or_404!(symlink_metadata(&path).await.ok());
 
-    let nar_list = get_nar_list(settings.store.get_real_path(&store_path)).await?;
-    Ok(HttpResponse::Ok()
-        .insert_header(cache_control_max_age_1y())
-        .insert_header(http::header::ContentType(mime::APPLICATION_JSON))
-        .body(serde_json::to_string(&nar_list)?))
+    let mut res = HttpResponse::Ok();
+    res.insert_header(cache_control_max_age_1y())
+        .insert_header(http::header::ContentType(mime::APPLICATION_JSON));
+    if req.method() == http::Method::HEAD {
+        // don't walk the whole tree, its length isn't known without serializing it
+        return Ok(res.body(actix_web::body::None::new()));
+    }
+
+    let (tx, rx) = mpsc::channel(16);
+    task::spawn(async move {
+        let err_tx = tx.clone();
+        if let Err(e) = write_nar_list(path.clone(), tx).await {
+            log::error!("Failed to list {}: {:#}", path.display(), e);
+            // ends the response early instead of with truncated JSON that looks complete
+            let _ = err_tx.send(Err(io::Error::other(format!("{:#}", e)))).await;
+        }
+    });
+    Ok(res.streaming(tokio_stream::wrappers::ReceiverStream::new(rx)))
 }
 
 #[cfg(test)]
@@ -162,18 +267,19 @@ mod test {
     use std::fs;
     use std::process::Command;
 
-    pub fn unset_nar_offset(entry: &mut NarEntry) {
-        match entry {
-            NarEntry::Regular { nar_offset, .. } => {
-                *nar_offset = None;
-            }
-            NarEntry::Directory { entries } => {
-                for (_, entry) in entries.iter_mut() {
-                    unset_nar_offset(entry);
-                }
-            }
-            _ => {}
+    async fn get_nar_list(path: PathBuf) -> Result<serde_json::Value> {
+        let (tx, mut rx) = mpsc::channel(16);
+        write_nar_list(path, tx).await?;
+        let mut json = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            json.extend_from_slice(&chunk?);
         }
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn root(list: &serde_json::Value) -> Result<NarEntry> {
+        assert_eq!(list["version"], 1);
+        Ok(serde_json::from_value(list["root"].clone())?)
     }
 
     #[tokio::test]
@@ -210,7 +316,7 @@ mod test {
             .context("Failed to create symlink")
             .unwrap();
 
-        let json = get_nar_list(dir.to_owned()).await.unwrap();
+        let json = root(&get_nar_list(dir.to_owned()).await.unwrap()).unwrap();
 
         //let nar_dump = dump_to_vec(dir.to_str().unwrap().to_owned()).await?;
         let nar_file = temp_dir.path().join("store.nar");
@@ -242,17 +348,120 @@ mod test {
         let parsed_json: serde_json::Value = serde_json::from_slice(&res2.stdout).unwrap();
         let pretty_string = serde_json::to_string_pretty(&parsed_json).unwrap();
         assert!(res2.status.success());
-        let mut reference_json: NarEntry = serde_json::from_str(&pretty_string).unwrap();
-
-        // our posix implementation does not support narOffset
-        unset_nar_offset(&mut reference_json);
+        let reference_json: NarEntry = serde_json::from_str(&pretty_string).unwrap();
 
         println!("get_nar_list:");
-        println!("{}", serde_json::to_string_pretty(&json.root).unwrap());
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
         println!("nix nar ls --json --recursive:");
         println!("{}", pretty_string);
-        assert_eq!(json.root, reference_json);
+        assert_eq!(json, reference_json);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nar_offsets() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir_all(dir.join("some_dir"))?;
+        fs::write(dir.join("file"), b"somecontent")?;
+        let executable_path = dir.join("some_dir").join("executable");
+        fs::write(&executable_path, b"somescript")?;
+        fs::set_permissions(&executable_path, fs::Permissions::from_mode(0o755))?;
+
+        let list = root(&get_nar_list(dir).await?)?;
+        let NarEntry::Directory { entries } = &list else {
+            panic!("expected a directory");
+        };
+        let nar_offset = |entry: &NarEntry| match entry {
+            NarEntry::Regular { nar_offset, .. } => *nar_offset,
+            _ => None,
+        };
+        // magic, directory header, entry header and regular header, followed by the size
+        assert_eq!(nar_offset(&entries["file"]), Some(232));
+        let NarEntry::Directory { entries } = &entries["some_dir"] else {
+            panic!("expected a directory");
+        };
+        assert_eq!(nar_offset(&entries["executable"]), Some(608));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunks() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir(&dir)?;
+        for i in 0..2000 {
+            fs::write(dir.join(format!("file-with-a-long-name-{:0>40}", i)), b"")?;
+        }
+
+        let (tx, mut rx) = mpsc::channel(1000);
+        write_nar_list(dir, tx).await?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk?);
+        }
+        assert!(chunks.len() > 1);
+        let list: serde_json::Value = serde_json::from_slice(&chunks.concat())?;
+        let NarEntry::Directory { entries } = root(&list)? else {
+            panic!("expected a directory");
+        };
+        assert_eq!(entries.len(), 2000);
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    #[tokio::test]
+    async fn test_case_hack() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("Makefile"), b"upper")?;
+        fs::write(dir.join("makefile~nix~case~hack~1"), b"lower")?;
+
+        let list = root(&get_nar_list(dir).await?)?;
+        let NarEntry::Directory { entries } = &list else {
+            panic!("expected a directory");
+        };
+        let mut names = entries.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["Makefile", "makefile"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_names() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let dir = temp_dir.path().join("store");
+        fs::create_dir(&dir)?;
+        let name = OsStr::from_bytes(b"invalid-\xff");
+        if fs::write(dir.join(name), b"").is_err() {
+            // the file system only allows UTF-8 names
+            return Ok(());
+        }
+        let err = get_nar_list(dir.clone()).await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("not valid UTF-8"),
+            "{:#}",
+            err
+        );
 
+        fs::remove_file(dir.join(name))?;
+        std::os::unix::fs::symlink(name, dir.join("symlink"))?;
+        assert!(get_nar_list(dir).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fifo() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let fifo = temp_dir.path().join("fifo");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_encoded_bytes())?;
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let err = get_nar_list(temp_dir.path().to_owned()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains(&format!("{} is a FIFO", fifo.display())));
         Ok(())
     }
 }
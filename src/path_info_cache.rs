@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::daemon::ValidPathInfo;
+
+/// Keeps the path infos returned by the daemon for a while, as the NAR of a
+/// store path usually is requested right after its narinfo.
+///
+/// Valid paths are immutable, but they can be garbage collected. Entries
+/// expire after `ttl`, and handlers look up store paths by hash part
+/// without the cache first, so collected paths are not served either way.
+#[derive(Debug)]
+pub(crate) struct PathInfoCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, ValidPathInfo)>>,
+}
+
+impl PathInfoCache {
+    pub(crate) fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: Default::default(),
+        }
+    }
+
+    pub(crate) fn get(&self, path: &str) -> Option<ValidPathInfo> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some((inserted, info)) if inserted.elapsed() < self.ttl => Some(info.clone()),
+            Some(_) => {
+                entries.remove(path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, path: &str, info: &ValidPathInfo) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(path) {
+            entries.retain(|_, (inserted, _)| inserted.elapsed() < self.ttl);
+            if entries.len() >= self.max_entries {
+                // make room by dropping the entry closest to expiry
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (inserted, _))| *inserted)
+                    .map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(path.to_owned(), (Instant::now(), info.clone()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(nar_size: u64) -> ValidPathInfo {
+        ValidPathInfo {
+            deriver: String::new(),
+            hash: "00".into(),
+            references: vec![],
+            registration_time: 0,
+            nar_size,
+            ultimate: false,
+            sigs: vec![],
+            content_address: None,
+        }
+    }
+
+    #[test]
+    fn test_path_info_cache() {
+        let cache = PathInfoCache::new(Duration::from_secs(60), 2);
+        assert_eq!(cache.get("/nix/store/a"), None);
+        cache.insert("/nix/store/a", &info(1));
+        cache.insert("/nix/store/b", &info(2));
+        assert_eq!(cache.get("/nix/store/a"), Some(info(1)));
+
+        // the oldest entry makes room
+        cache.insert("/nix/store/c", &info(3));
+        assert_eq!(cache.get("/nix/store/a"), None);
+        assert_eq!(cache.get("/nix/store/b"), Some(info(2)));
+        assert_eq!(cache.get("/nix/store/c"), Some(info(3)));
+
+        let cache = PathInfoCache::new(Duration::ZERO, 2);
+        cache.insert("/nix/store/a", &info(1));
+        assert_eq!(cache.get("/nix/store/a"), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use actix_web::{web, HttpResponse};
+
+use crate::config::Config;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Whether harmonia is able to serve requests.
+#[derive(Debug, Default)]
+pub(crate) struct Readiness {
+    daemon_reachable: AtomicBool,
+}
+
+/// Liveness probe: the process is up and handles requests.
+pub(crate) async fn livez() -> ServerResult {
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .body("OK\n"))
+}
+
+/// Readiness probe: the daemon handshake succeeded at least once since
+/// startup and a signing key is loaded, unless signing is disabled.
+pub(crate) async fn readyz(settings: web::Data<Config>) -> ServerResult {
+    let unavailable = |reason: String| {
+        Ok(HttpResponse::ServiceUnavailable()
+            .insert_header(cache_control_no_store())
+            .body(reason))
+    };
+
+    if settings.sign_narinfos && settings.secret_keys.load().is_empty() {
+        return unavailable("no signing key loaded\n".into());
+    }
+    if !settings.readiness.daemon_reachable.load(Ordering::Relaxed) {
+        if let Err(e) = settings.store.daemon.lock().await.ensure_connected().await {
+            return unavailable(format!("nix daemon is not reachable: {:#}\n", e));
+        }
+        settings
+            .readiness
+            .daemon_reachable
+            .store(true, Ordering::Relaxed);
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .body("OK\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_store::unreachable_store;
+    use actix_web::body::MessageBody;
+    use actix_web::http::StatusCode;
+    use anyhow::Result;
+
+    async fn probe(settings: &web::Data<Config>) -> Result<(StatusCode, String)> {
+        let res = readyz(settings.clone())
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let status = res.status();
+        let body = res
+            .into_body()
+            .try_into_bytes()
+            .map_err(|_| anyhow::anyhow!("streamed body"))?;
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    #[actix_web::test]
+    async fn test_no_signing_key() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let settings = web::Data::new(Config {
+            sign_narinfos: true,
+            store: unreachable_store(dir.path()),
+            ..Default::default()
+        });
+        assert_eq!(
+            probe(&settings).await?,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no signing key loaded\n".into()
+            )
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_daemon_unreachable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let settings = web::Data::new(Config {
+            sign_narinfos: false,
+            store: unreachable_store(dir.path()),
+            ..Default::default()
+        });
+        let (status, body) = probe(&settings).await?;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.starts_with("nix daemon is not reachable"), "{}", body);
+
+        // once the daemon was reached, losing it doesn't make harmonia unready
+        settings
+            .readiness
+            .daemon_reachable
+            .store(true, Ordering::Relaxed);
+        assert_eq!(probe(&settings).await?, (StatusCode::OK, "OK\n".into()));
+        Ok(())
+    }
+}
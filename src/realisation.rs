@@ -0,0 +1,68 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::{cache_control_max_age, cache_control_no_store, some_or_404, ServerResult};
+
+/// Checks that `id` looks like a derivation output id, i.e. `sha256:<hash>!<output>`.
+fn is_drv_output(id: &str) -> bool {
+    match id.split_once('!') {
+        Some((hash, output)) => {
+            hash.split_once(':')
+                .is_some_and(|(algo, digest)| !algo.is_empty() && !digest.is_empty())
+                && !output.is_empty()
+        }
+        None => false,
+    }
+}
+
+/// Serves the realisation of a content-addressed derivation output.
+///
+/// Nix requests these as `realisations/<drv-output>.doi` from binary caches.
+pub(crate) async fn get(
+    drv_output: web::Path<String>,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    let id = drv_output.trim_end_matches(".doi");
+    if !is_drv_output(id) {
+        return Ok(HttpResponse::BadRequest()
+            .insert_header(cache_control_no_store())
+            .body("invalid derivation output id"));
+    }
+    let realisations = settings
+        .store
+        .daemon
+        .lock()
+        .await
+        .query_realisation(id)
+        .await?;
+    let realisation = some_or_404!(realisations.into_iter().next());
+    // validate, so that we don't pass on garbage
+    let realisation: serde_json::Value =
+        serde_json::from_str(&realisation).context("Failed to parse realisation")?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_max_age(
+            settings.narinfo_cache_control_max_age,
+        ))
+        .json(realisation))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_drv_output() {
+        assert!(is_drv_output(
+            "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh!out"
+        ));
+        assert!(!is_drv_output(
+            "sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh"
+        ));
+        assert!(!is_drv_output(
+            "1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh!out"
+        ));
+        assert!(!is_drv_output("sha256:abc!"));
+    }
+}
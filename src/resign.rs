@@ -0,0 +1,126 @@
+use anyhow::{bail, Context, Result};
+
+use crate::config::{Config, SigningKey};
+use crate::signing::sign_string;
+use crate::store_paths::{SelectedPath, SelectedPaths};
+
+/// Signs `fingerprint` with each of `keys`, leaving out the signatures that
+/// are already `attached` to the path.
+fn missing_signatures(keys: &[SigningKey], attached: &[String], fingerprint: &str) -> Vec<String> {
+    keys.iter()
+        .map(|key| sign_string(key, fingerprint))
+        .filter(|sig| !attached.contains(sig))
+        .collect()
+}
+
+/// Signs store paths with the configured keys and persists the signatures in
+/// the store, so they survive without on-the-fly signing, e.g. when migrating keys.
+///
+/// Signs all valid paths that are allowed to be served if `paths` is empty.
+pub(crate) async fn run(settings: &Config, paths: &[String]) -> Result<()> {
+    let secret_keys = settings.secret_keys.load_full();
+    if secret_keys.is_empty() {
+        bail!("No signing keys configured, nothing to sign with");
+    }
+    let mut daemon = settings.store.daemon.lock().await;
+    let mut paths = SelectedPaths::new(&mut daemon, paths).await?;
+
+    let mut selected = 0;
+    let mut signed = 0;
+    while let Some(SelectedPath {
+        path,
+        info,
+        fingerprint,
+    }) = paths.next(settings, &mut daemon).await?
+    {
+        selected += 1;
+        let sigs = missing_signatures(&secret_keys, &info.sigs, &fingerprint);
+        if sigs.is_empty() {
+            continue;
+        }
+        daemon
+            .add_signatures(&path, &sigs)
+            .await
+            .with_context(|| format!("Failed to add signatures to {}", path))?;
+        signed += 1;
+    }
+    log::info!("signed {} of {} store paths", signed, selected);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::parse_secret_key;
+    use crate::test_store::TempStore;
+    use arc_swap::ArcSwap;
+    use std::path::PathBuf;
+
+    fn secret_key(name: &str) -> Result<SigningKey> {
+        parse_secret_key(
+            &PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../tests")
+                .join(name),
+        )
+    }
+
+    #[test]
+    fn test_missing_signatures() -> Result<()> {
+        let keys = [secret_key("cache.sk")?, secret_key("cache2.sk")?];
+        let fingerprint = "1;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1;sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh;226560;";
+        let sigs = keys
+            .iter()
+            .map(|key| sign_string(key, fingerprint))
+            .collect::<Vec<_>>();
+
+        assert_eq!(missing_signatures(&keys, &[], fingerprint), sigs);
+        assert_eq!(
+            missing_signatures(&keys, &sigs[..1], fingerprint),
+            sigs[1..]
+        );
+        assert!(missing_signatures(&keys, &sigs, fingerprint).is_empty());
+        // a signature of another fingerprint doesn't count
+        let other = sign_string(&keys[0], &fingerprint.replace("226560", "1"));
+        assert_eq!(missing_signatures(&keys, &[other], fingerprint), sigs);
+        Ok(())
+    }
+
+    /// Persists the signature in a temporary store.
+    #[tokio::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_run() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file("harmonia-test.txt", b"hello harmonia")?;
+        let key = secret_key("cache.sk")?;
+        let key_name = key.name.clone();
+        let settings = Config {
+            store: temp_store.store(),
+            secret_keys: ArcSwap::from_pointee(vec![key]),
+            ..Default::default()
+        };
+
+        let paths = [store_path.clone()];
+        run(&settings, &paths).await?;
+        let info = settings
+            .store
+            .dedicated_daemon()
+            .query_path_info(&store_path)
+            .await?
+            .path
+            .context("path vanished")?;
+        assert_eq!(info.sigs.len(), 1);
+        assert!(info.sigs[0].starts_with(&format!("{}:", key_name)));
+
+        // signing again adds nothing
+        run(&settings, &paths).await?;
+        let info = settings
+            .store
+            .dedicated_daemon()
+            .query_path_info(&store_path)
+            .await?
+            .path
+            .context("path vanished")?;
+        assert_eq!(info.sigs.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use actix_web::{web, HttpResponse};
+
+use crate::config::Config;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Upper bound of hash parts resolved in a single request.
+const MAX_HASHES: usize = 10000;
+
+/// Resolves a JSON list of hash parts to their store paths.
+///
+/// Responds with an object mapping each hash part to its store path, or
+/// `null` if it is not in the store (or not served).
+pub(crate) async fn post(
+    hashes: web::Json<Vec<String>>,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    let hashes = hashes.into_inner();
+    if hashes.len() > MAX_HASHES {
+        return Ok(HttpResponse::PayloadTooLarge()
+            .insert_header(cache_control_no_store())
+            .body(format!(
+                "at most {} hashes can be resolved at once",
+                MAX_HASHES
+            )));
+    }
+
+    let (servable, denied): (Vec<_>, Vec<_>) = hashes
+        .into_iter()
+        .partition(|hash| hash.len() == 32 && settings.path_filter.is_allowed(hash));
+    // anyone can send thousands of hashes, which must not hold up the
+    // shared connection for the other requests
+    let paths = settings
+        .store
+        .dedicated_daemon()
+        .query_paths_from_hash_parts(&servable)
+        .await?;
+
+    let mut resolved = servable
+        .into_iter()
+        .zip(paths)
+        .collect::<HashMap<String, Option<String>>>();
+    resolved.extend(denied.into_iter().map(|hash| (hash, None)));
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(resolved))
+}
@@ -1,11 +1,58 @@
 use std::error::Error;
 
-use actix_web::{http, web, HttpResponse};
+use actix_files::NamedFile;
+use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
+use anyhow::Context;
+use askama_escape::{escape as escape_html_entity, Html};
 
-use crate::BOOTSTRAP_SOURCE;
-use crate::{config, CARGO_HOME_PAGE, CARGO_NAME, CARGO_VERSION};
+use crate::access::origin;
+use crate::assets;
+use crate::signing::public_key_string;
+use crate::{cache_control_no_store, config, CARGO_HOME_PAGE, CARGO_NAME, CARGO_VERSION};
+
+/// Builds a `nix.conf` snippet for using this cache.
+fn nix_conf_snippet(url: &str, public_key: Option<&str>) -> String {
+    let mut snippet = format!("extra-substituters = {}\n", url);
+    if let Some(public_key) = public_key {
+        snippet.push_str(&format!("extra-trusted-public-keys = {}\n", public_key));
+    }
+    snippet
+}
+
+pub(crate) async fn get(
+    req: HttpRequest,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    if let Some(url) = &config.root_redirect {
+        return Ok(HttpResponse::Found()
+            .insert_header((http::header::LOCATION, url.as_str()))
+            .finish());
+    }
+    if let Some(path) = &config.root_html_path {
+        // read on every request, so the page can be changed without a restart
+        let page = NamedFile::open_async(path)
+            .await
+            .with_context(|| format!("Failed to open root_html_path '{}'", path.display()))?
+            .disable_content_disposition()
+            .set_content_type(mime::TEXT_HTML_UTF_8);
+        return Ok(page.respond_to(&req).map_into_boxed_body());
+    }
+    let url = match &config.public_url {
+        Some(url) => url.trim_end_matches('/').to_owned(),
+        None => format!(
+            "{}{}",
+            origin(&req, &config.trusted_proxies),
+            config.path_prefix
+        ),
+    };
+    // without signing, clients have to trust the keys of the original signers
+    let public_key = if config.sign_narinfos {
+        config.secret_keys.load().first().map(public_key_string)
+    } else {
+        None
+    };
+    let snippet = nix_conf_snippet(&url, public_key.as_deref());
 
-pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpResponse, Box<dyn Error>> {
     Ok(HttpResponse::Ok()
         .insert_header(http::header::ContentType(mime::TEXT_HTML_UTF_8))
         .body(format!(
@@ -17,7 +64,7 @@ pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpRespons
   <meta charset="utf-8">
   <meta name="viewport" content="width=device-width, initial-scale=1, shrink-to-fit=no">
   <title>Nix binary cache ({CARGO_NAME} {CARGO_VERSION})</title>
-  {BOOTSTRAP_SOURCE}
+  {head}
 </head>
 <body>
   <div class="container mt-3">
@@ -39,6 +86,14 @@ pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpRespons
       </div>
     </div>
     <hr>
+    <div class="row">
+      <div class="col">
+        <h4 class="mb-3 text-center">Usage</h4>
+        <p>Add the following to your <code>nix.conf</code>:</p>
+        <pre><code>{snippet}</code></pre>
+      </div>
+    </div>
+    <hr>
     <div class="row">
       <div class="col text-center">
         <small class="d-block mb-3 text-muted">
@@ -50,7 +105,133 @@ pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpRespons
 </body>
 </html>
 "#,
+            head = assets::head(&config),
             store = config.store.virtual_store(),
             priority = config.priority,
+            snippet = escape_html_entity(&snippet, Html),
         )))
 }
+
+/// Themed 404 page for the pages meant for browsers, like `/serve`, so that
+/// they don't end up on a blank page.
+pub(crate) fn not_found_page(config: &config::Config) -> HttpResponse {
+    HttpResponse::NotFound()
+        .insert_header(cache_control_no_store())
+        .insert_header(http::header::ContentType(mime::TEXT_HTML_UTF_8))
+        .body(format!(
+            r#"
+<!DOCTYPE html>
+<html lang="en">
+
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1, shrink-to-fit=no">
+  <title>Not found - Nix binary cache ({CARGO_NAME} {CARGO_VERSION})</title>
+  {head}
+</head>
+<body>
+  <div class="container mt-3">
+    <div class="row justify-content-md-center">
+      <div class="col-md-auto text-center">
+        <h1>Not found</h1>
+        <p class="lead">There is nothing to see here.</p>
+        <p><a href="{root}/">Back to the binary cache</a></p>
+      </div>
+    </div>
+  </div>
+</body>
+</html>
+"#,
+            head = assets::head(config),
+            root = config.path_prefix,
+        ))
+}
+
+/// Answers requests that match no route, with [`not_found_page`] for
+/// browsers and an empty 404 for everything else.
+pub(crate) async fn not_found(req: HttpRequest, config: web::Data<config::Config>) -> HttpResponse {
+    let wants_html = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        not_found_page(&config)
+    } else {
+        HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_custom_root() -> Result<(), Box<dyn Error>> {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let config = web::Data::new(config::Config {
+            root_redirect: Some("https://docs.example.com/cache".into()),
+            ..Default::default()
+        });
+        let res = get(req.clone(), config).await?;
+        assert_eq!(res.status(), http::StatusCode::FOUND);
+        assert_eq!(
+            res.headers().get(http::header::LOCATION).unwrap(),
+            "https://docs.example.com/cache"
+        );
+
+        let dir = tempfile::tempdir()?;
+        let page = dir.path().join("index.html");
+        std::fs::write(&page, "<h1>Our cache</h1>")?;
+        let config = web::Data::new(config::Config {
+            root_html_path: Some(page),
+            ..Default::default()
+        });
+        let res = get(req, config).await?;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let body = actix_web::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body, "<h1>Our cache</h1>");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_found() -> Result<(), Box<dyn Error>> {
+        let config = web::Data::new(config::Config {
+            path_prefix: "/cache".into(),
+            ..Default::default()
+        });
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((http::header::ACCEPT, "text/html,application/xhtml+xml"))
+            .to_http_request();
+        let res = not_found(req, config.clone()).await;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        let body = actix_web::body::to_bytes(res.into_body()).await?;
+        let body = std::str::from_utf8(&body)?;
+        assert!(body.contains("<h1>Not found</h1>"));
+        assert!(body.contains(r#"<a href="/cache/">"#));
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let res = not_found(req, config).await;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        assert!(actix_web::body::to_bytes(res.into_body()).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nix_conf_snippet() {
+        assert_eq!(
+            nix_conf_snippet("https://cache.example.com", Some("cache.example.com-1:abc=")),
+            "extra-substituters = https://cache.example.com\nextra-trusted-public-keys = cache.example.com-1:abc=\n"
+        );
+        assert_eq!(
+            nix_conf_snippet("http://localhost:5000", None),
+            "extra-substituters = http://localhost:5000\n"
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::config::Config;
+use crate::upload::check_admin_auth;
+use crate::{cache_control_no_store, ServerResult};
+
+/// Lists the GC roots of the store as a JSON object mapping each root to the
+/// store path it keeps alive, e.g. to find out why garbage collection doesn't
+/// free any space. Requires the admin token.
+pub(crate) async fn get(req: HttpRequest, settings: web::Data<Config>) -> ServerResult {
+    if let Some(res) = check_admin_auth(&req, &settings) {
+        return Ok(res);
+    }
+    let roots = settings.store.daemon.lock().await.find_roots().await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(roots.into_iter().collect::<BTreeMap<_, _>>()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http, test::TestRequest};
+
+    #[tokio::test]
+    async fn test_auth() -> Result<(), crate::ServerError> {
+        let req = TestRequest::default().to_http_request();
+        let res = get(req.clone(), web::Data::new(Config::default())).await?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+
+        let settings = web::Data::new(Config {
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        });
+        let res = get(req, settings.clone()).await?;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::default()
+            .insert_header((http::header::AUTHORIZATION, "Bearer wrong"))
+            .to_http_request();
+        let res = get(req, settings).await?;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
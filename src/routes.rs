@@ -0,0 +1,476 @@
+use actix_web::http::Method;
+use actix_web::{http, web, HttpResponse, Route};
+
+use crate::{
+    admin, assets, buildlog, cacheinfo, derivers, diskspace, drv, hash, health, info, manifest,
+    metrics, nar, narinfo, narlist, readiness, realisation, resolve, root, roots, serve, upload,
+    version,
+};
+
+pub(crate) const NIXBASE32_ALPHABET: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Endpoints by path pattern, with the handlers of the methods they support.
+/// OPTIONS requests are answered from the same table.
+fn endpoints() -> Vec<(String, Vec<(Method, Route)>)> {
+    let narhash = format!("{{narhash:[{0}]{{52}}}}", NIXBASE32_ALPHABET);
+    let outhash = format!("{{outhash:[{0}]{{32}}}}", NIXBASE32_ALPHABET);
+    let get = |route: Route| vec![(Method::GET, route)];
+    vec![
+        // the landing page is also reachable without the trailing slash
+        ("".into(), get(web::to(root::get))),
+        ("/".into(), get(web::to(root::get))),
+        ("/assets/style.css".into(), get(web::to(assets::style))),
+        (
+            "/{hash}.ls".into(),
+            vec![
+                (Method::GET, web::to(narlist::get)),
+                (Method::HEAD, web::to(narlist::get)),
+            ],
+        ),
+        (
+            "/{hash}.narinfo".into(),
+            vec![
+                (Method::GET, web::to(narinfo::get)),
+                (Method::HEAD, web::to(narinfo::get)),
+                (Method::PUT, web::to(upload::put_narinfo)),
+            ],
+        ),
+        (
+            format!("/nar/{}.nar", narhash),
+            vec![
+                (Method::GET, web::to(nar::get)),
+                (Method::HEAD, web::to(nar::get)),
+                (Method::PUT, web::to(upload::put_nar)),
+            ],
+        ),
+        (
+            format!("/nar/{}.nar.{{ext:zst|xz|gz|br}}", narhash),
+            vec![
+                (Method::GET, web::to(nar::get)),
+                (Method::HEAD, web::to(nar::get)),
+                (Method::PUT, web::to(upload::put_nar)),
+            ],
+        ),
+        // only to reject uploads, NARs can't be served with bzip2
+        (
+            format!("/nar/{}.nar.{{ext:bz2}}", narhash),
+            vec![(Method::PUT, web::to(upload::put_nar))],
+        ),
+        // Serves the NAR given only the outhash, without verifying the narhash.
+        // The narhash is returned in the X-Nar-Hash header instead.
+        (
+            format!("/nar/{}.nar", outhash),
+            vec![
+                (Method::GET, web::to(nar::get)),
+                (Method::HEAD, web::to(nar::get)),
+            ],
+        ),
+        // narinfos served by nix-serve have the narhash embedded in the nar URL.
+        // While we don't do that, if nix-serve is replaced with harmonia, the old nar URLs
+        // will stay in client caches for a while - so support them anyway.
+        (
+            format!("/nar/{}-{}.nar", outhash, narhash),
+            get(web::to(nar::get)),
+        ),
+        // emitted by narinfos if query_free_nar_urls is set
+        (
+            format!("/nar/{}-{}.nar.{{ext:zst|xz|gz|br}}", outhash, narhash),
+            vec![
+                (Method::GET, web::to(nar::get)),
+                (Method::HEAD, web::to(nar::get)),
+            ],
+        ),
+        ("/info/{hash}".into(), get(web::to(info::get))),
+        ("/derivers/{hash}".into(), get(web::to(derivers::get))),
+        ("/hash/{hash}".into(), get(web::to(hash::get))),
+        ("/roots".into(), get(web::to(roots::get))),
+        ("/manifest".into(), get(web::to(manifest::get))),
+        (
+            "/admin/flush-cache".into(),
+            vec![(Method::POST, web::to(admin::flush_cache))],
+        ),
+        (
+            "/admin/maintenance".into(),
+            vec![(Method::POST, web::to(admin::set_maintenance))],
+        ),
+        ("/metrics".into(), get(web::to(metrics::get))),
+        (
+            "/resolve".into(),
+            vec![(Method::POST, web::to(resolve::post))],
+        ),
+        (
+            "/realisations/{drv_output}".into(),
+            get(web::to(realisation::get)),
+        ),
+        ("/serve/{hash}{path:.*}".into(), get(web::to(serve::get))),
+        ("/log/{drv}".into(), get(web::to(buildlog::get))),
+        ("/drv/{drv}".into(), get(web::to(drv::get))),
+        ("/version".into(), get(web::to(version::get))),
+        ("/health".into(), get(web::to(health::get))),
+        ("/diskspace".into(), get(web::to(diskspace::get))),
+        ("/livez".into(), get(web::to(readiness::livez))),
+        ("/readyz".into(), get(web::to(readiness::readyz))),
+        ("/nix-cache-info".into(), get(web::to(cacheinfo::get))),
+    ]
+}
+
+/// Registers all endpoints below `path_prefix`.
+pub(crate) fn configure(cfg: &mut web::ServiceConfig, path_prefix: &str) {
+    let mut scope = web::scope(path_prefix);
+    for (pattern, handlers) in endpoints() {
+        let allow = handlers
+            .iter()
+            .map(|(method, _)| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        for (method, route) in handlers {
+            scope = scope.route(&pattern, route.method(method));
+        }
+        scope = scope.route(&pattern, options(allow));
+    }
+    cfg.service(scope.default_service(web::to(root::not_found)));
+}
+
+/// Answers OPTIONS requests with the methods in `allow`.
+fn options(allow: String) -> Route {
+    web::route().method(Method::OPTIONS).to(move || {
+        let allow = allow.clone();
+        async move {
+            HttpResponse::NoContent()
+                .insert_header((http::header::ALLOW, allow))
+                .finish()
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::config::Config;
+    use crate::test_store::{unreachable_store, TempStore};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use anyhow::{Context, Result};
+
+    #[actix_web::test]
+    async fn test_nix_cache_info() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = web::Data::new(Config {
+            priority: 30,
+            store: unreachable_store(dir.path()),
+            ..Default::default()
+        });
+        for prefix in ["", "/cache"] {
+            let app = init_service(
+                App::new()
+                    .app_data(config.clone())
+                    .configure(|cfg| configure(cfg, prefix)),
+            )
+            .await;
+            let req = TestRequest::get()
+                .uri(&format!("{}/nix-cache-info", prefix))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), http::StatusCode::OK);
+            assert_eq!(
+                res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+                "text/x-nix-cache-info"
+            );
+            let body = read_body(res).await;
+            assert_eq!(
+                body,
+                "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 30\n"
+            );
+        }
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_routes_without_daemon() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = web::Data::new(Config {
+            store: unreachable_store(dir.path()),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(config)
+                .configure(|cfg| configure(cfg, "")),
+        )
+        .await;
+
+        let status = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            let res = call_service(&app, req);
+            async move { res.await.status() }
+        };
+        // not a hash, so the daemon isn't asked
+        assert_eq!(status("/hello.narinfo").await, http::StatusCode::NOT_FOUND);
+        assert_eq!(status("/nar/hello.nar").await, http::StatusCode::NOT_FOUND);
+        // a miss must not be reported while the daemon is down
+        assert_eq!(
+            status("/26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo").await,
+            http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status("/nar/26xbg1ndr7hbcncrlf9nhx5is2b25d13.nar").await,
+            http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_endpoints_unique() {
+        // a second entry for a pattern would answer OPTIONS with only the
+        // methods of the first
+        let mut patterns = std::collections::HashSet::new();
+        for (pattern, handlers) in endpoints() {
+            assert!(!handlers.is_empty(), "{}", pattern);
+            assert!(
+                patterns.insert(pattern.clone()),
+                "{} is listed twice",
+                pattern
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_options() -> Result<()> {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .configure(|cfg| configure(cfg, "/cache")),
+        )
+        .await;
+        let narhash = "1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh";
+        for (uri, allow) in [
+            (
+                "/cache/26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo".to_owned(),
+                "GET, HEAD, PUT",
+            ),
+            (format!("/cache/nar/{}.nar.zst", narhash), "GET, HEAD, PUT"),
+            (format!("/cache/nar/{}.nar.bz2", narhash), "PUT"),
+            ("/cache/admin/maintenance".to_owned(), "POST"),
+            ("/cache/nix-cache-info".to_owned(), "GET"),
+            ("/cache".to_owned(), "GET"),
+        ] {
+            let req = TestRequest::default()
+                .method(http::Method::OPTIONS)
+                .uri(&uri)
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), http::StatusCode::NO_CONTENT, "{}", uri);
+            assert_eq!(res.headers().get(http::header::ALLOW).unwrap(), allow);
+        }
+        let req = TestRequest::default()
+            .method(http::Method::OPTIONS)
+            .uri("/cache/unknown")
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            http::StatusCode::NOT_FOUND
+        );
+        Ok(())
+    }
+
+    /// Serves a path added to a temporary store.
+    #[actix_web::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_narinfo_and_nar() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file("harmonia-test.txt", b"hello harmonia")?;
+        let hash = &store_path["/nix/store/".len()..][..32];
+
+        let config = web::Data::new(Config {
+            store: temp_store.store(),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(config)
+                .configure(|cfg| configure(cfg, "")),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}.narinfo", hash))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let narinfo = String::from_utf8(read_body(res).await.to_vec())?;
+        assert!(narinfo.contains(&format!("StorePath: {}\n", store_path)));
+        let url = narinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("URL: "))
+            .context("narinfo has no URL")?;
+
+        let req = TestRequest::get().uri(&format!("/{}", url)).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let nar = read_body(res).await;
+        let dump = temp_store.nix_store(&["--dump".as_ref(), store_path.as_ref()])?;
+        assert_eq!(nar, dump);
+        Ok(())
+    }
+
+    /// Resolves hash parts spanning several pipelined batches, each to its own
+    /// store path.
+    #[actix_web::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_resolve() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let mut expected = std::collections::HashMap::new();
+        for i in 0..3 {
+            let store_path = temp_store.add_file(
+                &format!("harmonia-test-{}.txt", i),
+                format!("hello harmonia {}", i).as_bytes(),
+            )?;
+            let hash = store_path["/nix/store/".len()..][..32].to_owned();
+            expected.insert(hash, Some(store_path));
+        }
+        let mut hashes = expected.keys().cloned().collect::<Vec<_>>();
+        for i in 0..150 {
+            let missing = format!("{:032}", i);
+            expected.insert(missing.clone(), None);
+            // interleave the valid paths with the missing ones
+            hashes.insert(i % hashes.len(), missing);
+        }
+
+        let config = web::Data::new(Config {
+            store: temp_store.store(),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(config)
+                .configure(|cfg| configure(cfg, "")),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/resolve")
+            .set_json(&hashes)
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let resolved: std::collections::HashMap<String, Option<String>> =
+            serde_json::from_slice(&read_body(res).await)?;
+        assert_eq!(resolved, expected);
+        Ok(())
+    }
+
+    /// Probes answer like the request that follows them when unsigned paths
+    /// are refused.
+    #[actix_web::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_head_unsigned_narinfo() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file("harmonia-test.txt", b"hello harmonia")?;
+        let hash = &store_path["/nix/store/".len()..][..32];
+
+        let config = web::Data::new(Config {
+            store: temp_store.store(),
+            require_signatures: true,
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(config)
+                .configure(|cfg| configure(cfg, "")),
+        )
+        .await;
+        let uri = format!("/{}.narinfo", hash);
+        let head = call_service(
+            &app,
+            TestRequest::default()
+                .method(http::Method::HEAD)
+                .uri(&uri)
+                .to_request(),
+        )
+        .await
+        .status();
+        let get = call_service(&app, TestRequest::get().uri(&uri).to_request())
+            .await
+            .status();
+        assert_eq!(get, http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(head, get);
+        Ok(())
+    }
+
+    /// Joins the `partial` body of a download with the response `res` to the
+    /// request resuming it, like a client would: a 206 continues where its
+    /// Content-Range starts, anything else starts over.
+    async fn join_resumed(partial: &[u8], res: actix_web::dev::ServiceResponse) -> Result<Vec<u8>> {
+        if res.status() != http::StatusCode::PARTIAL_CONTENT {
+            assert_eq!(res.status(), http::StatusCode::OK);
+            return Ok(read_body(res).await.to_vec());
+        }
+        let content_range = res
+            .headers()
+            .get(http::header::CONTENT_RANGE)
+            .context("206 without Content-Range")?
+            .to_str()?
+            .to_owned();
+        let start = content_range
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split('-').next())
+            .context("invalid Content-Range")?
+            .parse::<usize>()?;
+        let mut body = partial[..start].to_vec();
+        body.extend_from_slice(&read_body(res).await);
+        Ok(body)
+    }
+
+    /// Resumes NAR downloads halfway, compressed or not.
+    #[actix_web::test]
+    #[ignore = "needs nix-daemon and nix-store in PATH"]
+    async fn test_resume_nar() -> Result<()> {
+        let temp_store = TempStore::start()?;
+        let store_path = temp_store.add_file(
+            "harmonia-test.bin",
+            &(0..3 * 1024 * 1024)
+                .map(|i| (i * 7 % 251) as u8)
+                .collect::<Vec<_>>(),
+        )?;
+        let hash = &store_path["/nix/store/".len()..][..32];
+
+        for compression in [vec![], vec![Compression::Zstd]] {
+            let config = web::Data::new(Config {
+                store: temp_store.store(),
+                compression,
+                ..Default::default()
+            });
+            let app = init_service(
+                App::new()
+                    .app_data(config)
+                    .configure(|cfg| configure(cfg, "")),
+            )
+            .await;
+            let req = TestRequest::get()
+                .uri(&format!("/{}.narinfo", hash))
+                .insert_header((http::header::ACCEPT_ENCODING, "zstd"))
+                .to_request();
+            let narinfo =
+                String::from_utf8(read_body(call_service(&app, req).await).await.to_vec())?;
+            let uri = format!(
+                "/{}",
+                narinfo
+                    .lines()
+                    .find_map(|line| line.strip_prefix("URL: "))
+                    .context("narinfo has no URL")?
+            );
+
+            let full =
+                read_body(call_service(&app, TestRequest::get().uri(&uri).to_request()).await)
+                    .await;
+            let partial = &full[..full.len() / 2];
+            let req = TestRequest::get()
+                .uri(&uri)
+                .insert_header((http::header::RANGE, format!("bytes={}-", partial.len())))
+                .to_request();
+            let resumed = join_resumed(partial, call_service(&app, req).await).await?;
+            assert!(resumed == full, "resuming {} corrupted it", uri);
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
 
 use actix_files::NamedFile;
-use actix_web::Responder;
-use actix_web::{web, HttpRequest, HttpResponse};
-use anyhow::Context;
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use anyhow::{Context, Result};
 use askama_escape::{escape as escape_html_entity, Html};
 use percent_encoding::{utf8_percent_encode, CONTROLS};
+use serde::Deserialize;
 use std::fmt::Write;
+use tokio_stream::StreamExt;
 
 use crate::{
-    config::Config, nixhash, some_or_404, ServerResult, BOOTSTRAP_SOURCE, CARGO_NAME, CARGO_VERSION,
+    assets, config::Config, nixhash, root, some_or_404, ServerResult, CARGO_NAME, CARGO_VERSION,
 };
 
 /// Returns percent encoded file URL path.
@@ -48,52 +54,135 @@ fn file_size(bytes: u64) -> String {
     }
 }
 
+/// Rows per page of directory listings, unless requested otherwise.
+const DEFAULT_PER_PAGE: usize = 1000;
+/// Upper bound of rows per page, to keep listings of huge directories small.
+const MAX_PER_PAGE: usize = 10000;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListingParams {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+/// Renders the table row of a directory entry, `None` if it vanished.
+fn listing_row(entry: &fs::DirEntry, fs_path: &Path, url_prefix: &Path) -> Option<String> {
+    let p = url_prefix
+        .join(entry.path().strip_prefix(fs_path).ok()?)
+        .to_string_lossy()
+        .into_owned();
+    let metadata = entry.metadata().ok()?;
+    // if file is a directory, add '/' to the end of the name
+    Some(if metadata.is_dir() {
+        format!(
+            "<tr><td><a href=\"{}\">{}/</a></td><td>-</td></tr>\n",
+            encode_file_url!(p),
+            encode_file_name!(entry),
+        )
+    } else {
+        format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td></tr>\n",
+            encode_file_url!(p),
+            encode_file_name!(entry),
+            file_size(metadata.len()),
+        )
+    })
+}
+
+/// Whether the client's copy, per its `If-Modified-Since`, is still current
+/// for something last modified at `modified`.
+fn not_modified(req: &HttpRequest, modified: SystemTime) -> bool {
+    // without an ETag, If-None-Match can't match, and it takes precedence
+    if req.headers().contains_key(http::header::IF_NONE_MATCH) {
+        return false;
+    }
+    let Some(http::header::IfModifiedSince(since)) = req.get_header() else {
+        return false;
+    };
+    // HTTP dates have whole seconds
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    };
+    secs(since.into()) >= secs(modified)
+}
+
+/// Lists a page of the entries of `fs_path`.
+///
+/// The listing carries the modification time of the directory as
+/// `Last-Modified`, which changes when entries are added or removed, so that
+/// clients can revalidate it cheaply. Store paths all have the same one, but
+/// they don't change either.
+///
+/// Only the names of all entries are held in memory, for sorting. The rows of
+/// the page are rendered while the response is streamed, so the metadata of
+/// the entries is read while earlier rows are already being sent.
 pub(crate) fn directory_listing(
+    req: &HttpRequest,
     url_prefix: &Path,
     fs_path: &Path,
     real_store: &Path,
+    params: &ListingParams,
+    head: &str,
 ) -> ServerResult {
+    let modified = fs_path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if let Some(modified) = modified {
+        if not_modified(req, modified) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(http::header::LastModified(modified.into()))
+                .finish());
+        }
+    }
     let path_without_store = fs_path.strip_prefix(real_store).unwrap_or(fs_path);
     let index_of = format!(
         "Index of {}",
         escape_html_entity(&path_without_store.to_string_lossy(), Html)
     );
-    let mut rows = String::new();
+    let per_page = params
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let page = params.page.unwrap_or(1).max(1);
 
-    for entry in fs_path
+    // sorted, so that pages are stable
+    let mut entries = fs_path
         .read_dir()
-        .with_context(|| format!("cannot read directory: {}", fs_path.display()))?
-    {
-        let entry = entry.unwrap();
-        let p = match entry.path().strip_prefix(fs_path) {
-            Ok(p) => url_prefix.join(p).to_string_lossy().into_owned(),
-            Err(_) => continue,
-        };
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("cannot read directory: {}", fs_path.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let total = entries.len();
+    let pages = total.div_ceil(per_page).max(1);
+    let entries = entries
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect::<Vec<_>>();
 
-        // if file is a directory, add '/' to the end of the name
-        if let Ok(metadata) = entry.metadata() {
-            if metadata.is_dir() {
-                let _ = writeln!(
-                    rows,
-                    "<tr><td><a href=\"{}\">{}/</a></td><td>-</td></tr>",
-                    encode_file_url!(p),
-                    encode_file_name!(entry),
-                );
-            } else {
-                let size = file_size(metadata.len());
-                let _ = writeln!(
-                    rows,
-                    "<tr><td><a href=\"{}\">{}</a></td><td>{size}</td></tr>",
-                    encode_file_url!(p),
-                    encode_file_name!(entry),
-                );
-            }
-        } else {
-            continue;
+    let mut pagination = String::new();
+    if pages > 1 {
+        let link = |page: usize, label: &str| {
+            format!(
+                "<a class=\"btn btn-outline-secondary\" href=\"?page={}&amp;per_page={}\">{}</a>",
+                page, per_page, label
+            )
+        };
+        if page > 1 {
+            pagination.push_str(&link(page - 1, "Previous"));
+        }
+        let _ = write!(
+            pagination,
+            " Page {} of {} ({} entries) ",
+            page, pages, total
+        );
+        if page < pages {
+            pagination.push_str(&link(page + 1, "Next"));
         }
     }
 
-    let html = format!(
+    let header = format!(
         r#"
 <!DOCTYPE html>
 <html lang="en">
@@ -102,7 +191,7 @@ pub(crate) fn directory_listing(
     <meta charset="utf-8">
     <meta name="viewport" content="width=device-width, initial-scale=1, shrink-to-fit=no">
     <title>Nix binary cache ({CARGO_NAME} {CARGO_VERSION})</title>
-    {BOOTSTRAP_SOURCE}
+    {head}
 </head>
 <body>
     <div class="container mt-4">
@@ -116,63 +205,373 @@ pub(crate) fn directory_listing(
                 </tr>
             </thead>
             <tbody>
-                {rows}
-            </tbody>
+"#,
+    );
+    let footer = format!(
+        r#"            </tbody>
         </table>
+        {pagination}
     </div>
 </body>"#,
     );
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(html))
+
+    let fs_path = fs_path.to_owned();
+    let url_prefix = url_prefix.to_owned();
+    let rows = tokio_stream::iter(entries)
+        .filter_map(move |entry| listing_row(&entry, &fs_path, &url_prefix));
+    let body = tokio_stream::once(header)
+        .chain(rows)
+        .chain(tokio_stream::once(footer))
+        .map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+    let mut res = HttpResponse::Ok();
+    if let Some(modified) = modified {
+        res.insert_header(http::header::LastModified(modified.into()));
+    }
+    Ok(res.content_type("text/html; charset=utf-8").streaming(body))
+}
+
+/// Returns the content type configured for `path`, if any. `guessed` is the
+/// content type guessed from the extension.
+fn configured_content_type(
+    path: &Path,
+    guessed: &mime::Mime,
+    mime_types: &HashMap<String, String>,
+    default_mime_type: Option<&str>,
+) -> Option<mime::Mime> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    let configured = match mime_types.get(&ext) {
+        Some(mime_type) => mime_type.as_str(),
+        None if *guessed == mime::APPLICATION_OCTET_STREAM => default_mime_type?,
+        None => return None,
+    };
+    // validated when loading the config
+    configured.parse().ok()
+}
+
+/// Opens a file to serve, with the content type overrides of the config applied.
+async fn open_file(path: &Path, settings: &Config) -> Result<NamedFile> {
+    let file = NamedFile::open_async(path)
+        .await
+        .with_context(|| format!("cannot open file: {}", path.display()))?;
+    Ok(
+        match configured_content_type(
+            path,
+            file.content_type(),
+            &settings.mime_types,
+            settings.default_mime_type.as_deref(),
+        ) {
+            Some(content_type) => file.set_content_type(content_type),
+            None => file,
+        },
+    )
+}
+
+/// Resolves symlinks in `path` and returns it together with the name of the
+/// store path it ends up in, which may differ from the one it started in.
+/// Returns `None` for paths outside of a store path, including the store itself.
+fn resolve_in_store(path: &Path, real_store: &Path) -> Result<Option<(PathBuf, String)>> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("cannot resolve nix store path: {}", path.display()))?;
+    // the store itself may be behind a symlink, too
+    let real_store = real_store.canonicalize().unwrap_or(real_store.to_owned());
+    let store_path = match path.strip_prefix(&real_store) {
+        Ok(rest) => match rest.components().next() {
+            Some(Component::Normal(name)) => name.to_string_lossy().into_owned(),
+            _ => return Ok(None),
+        },
+        Err(_) => return Ok(None),
+    };
+    Ok(Some((path, store_path)))
 }
 
 pub(crate) async fn get(
     path: web::Path<(String, PathBuf)>,
+    params: web::Query<ListingParams>,
     req: HttpRequest,
     settings: web::Data<Config>,
 ) -> ServerResult {
     let (hash, dir) = path.into_inner();
-    let dir = dir.strip_prefix("/").unwrap_or(&dir);
+    match serve(&hash, &dir, &params, &req, &settings).await {
+        Ok(res) if res.status() == http::StatusCode::NOT_FOUND => {
+            Ok(root::not_found_page(&settings))
+        }
+        Err(e) if e.status() == http::StatusCode::NOT_FOUND => {
+            log::debug!("{}", e);
+            Ok(root::not_found_page(&settings))
+        }
+        res => res,
+    }
+}
+
+/// Serves `dir` within the store path with `hash`.
+async fn serve(
+    hash: &str,
+    dir: &Path,
+    params: &ListingParams,
+    req: &HttpRequest,
+    settings: &web::Data<Config>,
+) -> ServerResult {
+    let dir = dir.strip_prefix("/").unwrap_or(dir);
+    // resolving deep paths takes a syscall per component
+    if dir.components().count() > settings.max_serve_depth {
+        return Ok(HttpResponse::BadRequest()
+            .insert_header(crate::cache_control_no_store())
+            .body("path too deep"));
+    }
 
-    let store_path = settings.store.get_real_path(&PathBuf::from(&some_or_404!(
-        nixhash(&settings, &hash).await
-    )));
+    let virtual_store_path = some_or_404!(nixhash(settings, hash).await);
+    let store_path = settings
+        .store
+        .get_real_path(&PathBuf::from(&virtual_store_path));
     let full_path = if dir == Path::new("") {
         store_path.clone()
     } else {
         store_path.join(dir)
     };
-    let full_path = full_path
-        .canonicalize()
-        .with_context(|| format!("cannot resolve nix store path: {}", full_path.display()))?;
+    let (full_path, target) =
+        some_or_404!(resolve_in_store(&full_path, settings.store.real_store())?);
 
-    if !full_path.starts_with(settings.store.real_store()) {
-        return Ok(HttpResponse::NotFound().finish());
+    // symlinks to other store paths are followed, as long as those may be served
+    if !virtual_store_path.ends_with(&format!("/{}", target)) {
+        let target_hash = some_or_404!(target.get(..32));
+        let target_path = some_or_404!(nixhash(settings, target_hash).await);
+        if !target_path.ends_with(&format!("/{}", target)) {
+            return Ok(HttpResponse::NotFound().finish());
+        }
     }
 
     if full_path.is_dir() {
         let index_file = full_path.join("index.html");
         if let Ok(stat) = index_file.metadata() {
             if stat.is_file() {
-                return Ok(NamedFile::open_async(&index_file)
-                    .await
-                    .with_context(|| format!("cannot open {}", index_file.display()))?
-                    .respond_to(&req));
+                return Ok(open_file(&index_file, settings).await?.respond_to(req));
             }
         }
 
-        let url_prefix = PathBuf::from("/serve").join(&hash);
+        let url_prefix = PathBuf::from(format!("{}/serve", settings.path_prefix)).join(hash);
         let url_prefix = if dir == Path::new("") {
             url_prefix
         } else {
             url_prefix.join(dir)
         };
-        directory_listing(&url_prefix, &full_path, settings.store.real_store())
+        directory_listing(
+            req,
+            &url_prefix,
+            &full_path,
+            settings.store.real_store(),
+            params,
+            &assets::head(settings),
+        )
     } else {
-        Ok(NamedFile::open_async(&full_path)
+        Ok(open_file(&full_path, settings).await?.respond_to(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::header::HttpDate;
+    use actix_web::test::TestRequest;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_resolve_in_store() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let store = temp_dir.path().join("store");
+        let hello = store.join("26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1");
+        let glibc = store.join("sl141d1g77wvhr050ah87lcyz2czdxa3-glibc-2.40-36");
+        fs::create_dir_all(hello.join("share"))?;
+        fs::create_dir_all(glibc.join("lib"))?;
+        fs::write(glibc.join("lib/libc.so"), b"")?;
+        symlink(glibc.join("lib"), hello.join("lib"))?;
+        symlink(temp_dir.path(), hello.join("outside"))?;
+        symlink(&store, hello.join("store"))?;
+
+        assert_eq!(
+            resolve_in_store(&hello.join("share"), &store)?,
+            Some((
+                hello.canonicalize()?.join("share"),
+                "26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1".into()
+            ))
+        );
+        // into another store path
+        assert_eq!(
+            resolve_in_store(&hello.join("lib/libc.so"), &store)?,
+            Some((
+                glibc.canonicalize()?.join("lib/libc.so"),
+                "sl141d1g77wvhr050ah87lcyz2czdxa3-glibc-2.40-36".into()
+            ))
+        );
+        assert_eq!(resolve_in_store(&hello.join("outside"), &store)?, None);
+        // the store itself must not be listed
+        assert_eq!(resolve_in_store(&hello.join("store"), &store)?, None);
+        assert_eq!(resolve_in_store(&hello.join(".."), &store)?, None);
+
+        // the store may be a symlink itself
+        let linked_store = temp_dir.path().join("linked-store");
+        symlink(&store, &linked_store)?;
+        assert!(resolve_in_store(
+            &linked_store.join("26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1/share"),
+            &linked_store
+        )?
+        .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_content_type() {
+        let mime_types = HashMap::from([("map".to_owned(), "application/json".to_owned())]);
+        let content_type = |path: &str, guessed: &mime::Mime, default| {
+            configured_content_type(Path::new(path), guessed, &mime_types, default)
+        };
+        assert_eq!(
+            content_type("/x/app.js.MAP", &mime::APPLICATION_OCTET_STREAM, None),
+            Some(mime::APPLICATION_JSON)
+        );
+        assert_eq!(content_type("/x/index.html", &mime::TEXT_HTML, None), None);
+        assert_eq!(
+            content_type("/x/README.unknown", &mime::APPLICATION_OCTET_STREAM, None),
+            None
+        );
+        assert_eq!(
+            content_type(
+                "/x/README.unknown",
+                &mime::APPLICATION_OCTET_STREAM,
+                Some("text/plain")
+            ),
+            Some(mime::TEXT_PLAIN)
+        );
+        assert_eq!(
+            content_type("/x/index.html", &mime::TEXT_HTML, Some("text/plain")),
+            None
+        );
+    }
+
+    async fn listing(dir: &Path, page: Option<usize>, per_page: Option<usize>) -> Result<String> {
+        let params = ListingParams { page, per_page };
+        let res = directory_listing(
+            &TestRequest::default().to_http_request(),
+            Path::new("/serve/x"),
+            dir,
+            Path::new("/nix/store"),
+            &params,
+            "",
+        )
+        .map_err(|e| e.err)?;
+        let body = actix_web::body::to_bytes(res.into_body())
             .await
-            .with_context(|| format!("cannot open file: {}", full_path.display()))?
-            .respond_to(&req))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    #[actix_web::test]
+    async fn test_not_modified() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let file = temp_dir.path().join("README.md");
+        fs::write(&file, b"# hello")?;
+        let file_modified = fs::metadata(&file)?.modified()?;
+        let dir_modified = temp_dir.path().metadata()?.modified()?;
+        let unchanged = file_modified.max(dir_modified);
+        let minute = std::time::Duration::from_secs(60);
+
+        let req = |since: SystemTime| {
+            TestRequest::default()
+                .insert_header(http::header::IfModifiedSince(since.into()))
+                .to_http_request()
+        };
+        let settings = Config::default();
+        let params = ListingParams {
+            page: None,
+            per_page: None,
+        };
+        for (since, status) in [
+            (unchanged, http::StatusCode::NOT_MODIFIED),
+            (unchanged + minute, http::StatusCode::NOT_MODIFIED),
+            (unchanged - minute, http::StatusCode::OK),
+        ] {
+            let res = open_file(&file, &settings).await?.respond_to(&req(since));
+            assert_eq!(res.status(), status);
+
+            let res = directory_listing(
+                &req(since),
+                Path::new("/serve/x"),
+                temp_dir.path(),
+                Path::new("/nix/store"),
+                &params,
+                "",
+            )
+            .map_err(|e| e.err)?;
+            assert_eq!(res.status(), status);
+            assert_eq!(
+                res.headers().get(http::header::LAST_MODIFIED).unwrap(),
+                &HttpDate::from(dir_modified).to_string()
+            );
+        }
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_not_found_page() -> Result<()> {
+        let res = get(
+            web::Path::from(("not-a-hash".to_owned(), PathBuf::from("/README.md"))),
+            web::Query::from_query("").map_err(|e| anyhow::anyhow!("{}", e))?,
+            TestRequest::default().to_http_request(),
+            web::Data::new(Config {
+                max_serve_depth: 256,
+                ..Default::default()
+            }),
+        )
+        .await
+        .map_err(|e| e.err)?;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_max_serve_depth() -> Result<()> {
+        let settings = web::Data::new(Config {
+            max_serve_depth: 2,
+            ..Default::default()
+        });
+        let res = get(
+            web::Path::from((
+                "26xbg1ndr7hbcncrlf9nhx5is2b25d13".to_owned(),
+                PathBuf::from("/a/b/c"),
+            )),
+            web::Query::from_query("").map_err(|e| anyhow::anyhow!("{}", e))?,
+            TestRequest::default().to_http_request(),
+            settings,
+        )
+        .await
+        .map_err(|e| e.err)?;
+        assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_directory_listing_pagination() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        for name in ["a", "b", "c", "d", "e"] {
+            fs::write(temp_dir.path().join(name), b"")?;
+        }
+        let html = listing(temp_dir.path(), Some(2), Some(2)).await?;
+        assert!(!html.contains("/serve/x/b"));
+        assert!(html.contains("/serve/x/c"));
+        assert!(html.contains("/serve/x/d"));
+        assert!(!html.contains("/serve/x/e"));
+        assert!(html.contains("?page=1&amp;per_page=2"));
+        assert!(html.contains("?page=3&amp;per_page=2"));
+        assert!(html.contains("Page 2 of 3 (5 entries)"));
+
+        let html = listing(temp_dir.path(), None, None).await?;
+        assert!(html.contains("/serve/x/a") && html.contains("/serve/x/e"));
+        assert!(!html.contains("?page="));
+        Ok(())
     }
 }
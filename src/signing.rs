@@ -3,7 +3,7 @@ use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine};
 use std::path::Path;
 
-use crate::config::SigningKey;
+use crate::config::{PublicKey, SigningKey};
 
 // this is from the nix32 crate
 
@@ -19,6 +19,12 @@ extern "C" {
         msg_len: usize,
         sk: *const u8,
     ) -> i32;
+    fn crypto_sign_verify_detached(
+        sig: *const u8,
+        msg: *const u8,
+        msg_len: u64,
+        pk: *const u8,
+    ) -> i32;
 }
 
 /// Converts the given byte slice to a nix-compatible base32 encoded String.
@@ -45,6 +51,36 @@ fn to_nix_base32(bytes: &[u8]) -> String {
         .collect()
 }
 
+/// Decodes a nix-compatible base32 String into bytes.
+fn from_nix_base32(s: &str) -> Result<Vec<u8>> {
+    let s = s.as_bytes();
+    let size = s.len() * 5 / 8;
+    let mut bytes = vec![0u8; size];
+
+    for (n, c) in s.iter().rev().enumerate() {
+        let digit = match BASE32_CHARS.iter().position(|b| b == c) {
+            Some(digit) => digit as u16,
+            None => bail!("invalid base32 character: {}", *c as char),
+        };
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let v = digit << j;
+        bytes[i] |= v as u8;
+        let carry = (v >> 8) as u8;
+        if i + 1 < size {
+            bytes[i + 1] |= carry;
+        } else if carry != 0 {
+            bail!("invalid base32 string: excess bits");
+        }
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 fn val(c: u8, idx: usize) -> Result<u8> {
     match c {
         b'A'..=b'F' => Ok(c - b'A' + 10),
@@ -72,9 +108,21 @@ pub(crate) fn convert_base16_to_nix32(hash_str: &str) -> Result<String> {
     Ok(to_nix_base32(&bytes))
 }
 
+pub(crate) fn convert_nix32_to_base16(hash_str: &str) -> Result<String> {
+    let bytes = from_nix_base32(hash_str)
+        .with_context(|| format!("Failed to convert hash: {}", hash_str))?;
+    Ok(to_hex(&bytes))
+}
+
 pub(crate) fn parse_secret_key(path: &Path) -> Result<SigningKey> {
     let sign_key = std::fs::read_to_string(path).context("Couldn't read sign_key file")?;
+    parse_secret_key_str(&sign_key)
+}
+
+/// Parses a secret key in the `name:base64` format written by `nix key generate-secret`.
+pub(crate) fn parse_secret_key_str(sign_key: &str) -> Result<SigningKey> {
     let (sign_name, sign_key64) = sign_key
+        .trim()
         .split_once(':')
         .context("Sign key does not contain a ':'")?;
     let sign_keyno64 = general_purpose::STANDARD
@@ -93,6 +141,83 @@ pub(crate) fn parse_secret_key(path: &Path) -> Result<SigningKey> {
     ))
 }
 
+pub(crate) fn parse_public_key(s: &str) -> Result<PublicKey> {
+    let (name, key64) = s
+        .split_once(':')
+        .context("Public key does not contain a ':'")?;
+    let key = general_purpose::STANDARD
+        .decode(key64.trim())
+        .context("Couldn't base64::decode public key")?;
+    if key.len() != 32 {
+        bail!("Invalid public key. Expected 32 bytes, got {}", key.len());
+    }
+    Ok(PublicKey {
+        name: name.to_string(),
+        key,
+    })
+}
+
+/// Returns true if any of `sigs` is a valid signature of `msg` by one of `keys`.
+pub(crate) fn verify_signatures(keys: &[PublicKey], sigs: &[String], msg: &str) -> bool {
+    sigs.iter().any(|sig| {
+        let Some((name, sig64)) = sig.split_once(':') else {
+            return false;
+        };
+        let Some(key) = keys.iter().find(|k| k.name == name) else {
+            return false;
+        };
+        let sig = match general_purpose::STANDARD.decode(sig64) {
+            Ok(sig) if sig.len() == 64 => sig,
+            _ => return false,
+        };
+        let msg = msg.as_bytes();
+        unsafe {
+            crypto_sign_verify_detached(
+                sig.as_ptr(),
+                msg.as_ptr(),
+                msg.len() as u64,
+                key.key.as_ptr(),
+            ) == 0
+        }
+    })
+}
+
+/// Returns the public half embedded in a 64 byte secret key.
+pub(crate) fn public_key(sign_key: &SigningKey) -> PublicKey {
+    PublicKey {
+        name: sign_key.name.clone(),
+        key: sign_key.key[32..].to_vec(),
+    }
+}
+
+/// Returns the public key belonging to a secret key, in the format of `trusted-public-keys`.
+pub(crate) fn public_key_string(sign_key: &SigningKey) -> String {
+    format!(
+        "{}:{}",
+        sign_key.name,
+        general_purpose::STANDARD.encode(public_key(sign_key).key)
+    )
+}
+
+/// Signs a fixed message and verifies it with the public half embedded in the
+/// secret key, so truncated or corrupt keys are caught before they are used.
+pub(crate) fn self_test_secret_key(sign_key: &SigningKey) -> Result<()> {
+    const MSG: &str = "harmonia signing key self-test";
+    if sign_key.key.len() != 64 {
+        bail!(
+            "Invalid signing key. Expected 64 bytes, got {}",
+            sign_key.key.len()
+        );
+    }
+    if !verify_signatures(&[public_key(sign_key)], &[sign_string(sign_key, MSG)], MSG) {
+        bail!(
+            "Signing key '{}' failed the self-test, its public half does not match",
+            sign_key.name
+        );
+    }
+    Ok(())
+}
+
 pub(crate) fn fingerprint_path(
     virtual_nix_store: &str,
     store_path: &str,
@@ -180,6 +305,49 @@ mod test {
         )?;
         let signature = sign_string(&key, &finger_print.unwrap());
         assert_eq!(signature, "cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==");
+
+        let key_str = parse_secret_key_str(&std::fs::read_to_string(&sign_key)?)?;
+        assert_eq!(key_str.name, key.name);
+        assert_eq!(key_str.key, key.key);
+        assert!(parse_secret_key_str("cache.example.com-1").is_err());
+
+        self_test_secret_key(&key)?;
+        assert_eq!(
+            public_key_string(&key),
+            std::fs::read_to_string(test_assets_path().join("cache.pk"))?.trim()
+        );
+        let mut corrupt = key_str;
+        corrupt.key[63] ^= 1;
+        assert!(self_test_secret_key(&corrupt).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_signatures() -> Result<()> {
+        let public_key =
+            parse_public_key(std::fs::read_to_string(test_assets_path().join("cache.pk"))?.trim())?;
+        let fingerprint = "1;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1;sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh;226560;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1,/nix/store/sl141d1g77wvhr050ah87lcyz2czdxa3-glibc-2.40-36";
+        let sig = String::from("cache.example.com-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==");
+        assert!(verify_signatures(
+            std::slice::from_ref(&public_key),
+            std::slice::from_ref(&sig),
+            fingerprint
+        ));
+        assert!(!verify_signatures(
+            std::slice::from_ref(&public_key),
+            &[sig],
+            &fingerprint.replace("226560", "226561")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nix32_roundtrip() -> Result<()> {
+        let base16 = "d3e5a3f2a1e4c4b8b5c0a0e6b0c9f0e1d2c3b4a5968778695a4b3c2d1e0f1a2b";
+        let nix32 = convert_base16_to_nix32(base16)?;
+        assert_eq!(nix32.len(), 52);
+        assert_eq!(convert_nix32_to_base16(&nix32)?, base16);
+        assert!(convert_nix32_to_base16("eeee").is_err());
         Ok(())
     }
 }
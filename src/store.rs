@@ -1,7 +1,11 @@
-use crate::daemon::DaemonConnection;
+use crate::daemon::{DaemonConnection, RetryPolicy, ValidPathInfo};
+use crate::derivation::DrvHashes;
+use crate::path_info_cache::PathInfoCache;
+use anyhow::Result;
 use core::str;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 #[derive(Default, Debug)]
@@ -9,16 +13,75 @@ pub struct Store {
     virtual_store: String,
     real_store: Option<String>,
     pub daemon: Mutex<DaemonConnection>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    daemon_socket: Option<PathBuf>,
+    path_info_cache: Option<PathInfoCache>,
+    pub(crate) drv_hashes: DrvHashes,
 }
 
 impl Store {
-    pub fn new(virtual_store: String, real_store: Option<String>) -> Self {
+    pub fn new(
+        virtual_store: String,
+        real_store: Option<String>,
+        retry: RetryPolicy,
+        timeout: Option<Duration>,
+    ) -> Self {
         Self {
             virtual_store,
             real_store,
-            daemon: Default::default(),
+            daemon: Mutex::new(DaemonConnection::new(retry, timeout)),
+            retry,
+            timeout,
+            daemon_socket: None,
+            path_info_cache: None,
+            drv_hashes: Default::default(),
         }
     }
+    /// Caches the path infos of up to `max_entries` paths for `ttl`.
+    pub fn with_path_info_cache(self, ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            path_info_cache: Some(PathInfoCache::new(ttl, max_entries)),
+            ..self
+        }
+    }
+    /// Talks to the daemon listening on `socket_path` instead of the default one.
+    pub fn with_daemon_socket(self, socket_path: PathBuf) -> Self {
+        Self {
+            daemon: Mutex::new(
+                self.daemon
+                    .into_inner()
+                    .with_socket_path(socket_path.clone()),
+            ),
+            daemon_socket: Some(socket_path),
+            ..self
+        }
+    }
+
+    /// Opens a dedicated connection to the daemon of this store, with the same
+    /// socket, retries and timeout as the shared one, for long running
+    /// operations that shouldn't block other requests.
+    pub(crate) fn dedicated_daemon(&self) -> DaemonConnection {
+        let daemon = DaemonConnection::new(self.retry, self.timeout);
+        match &self.daemon_socket {
+            Some(socket_path) => daemon.with_socket_path(socket_path.clone()),
+            None => daemon,
+        }
+    }
+
+    /// Queries the path info of `path`, from the cache if enabled. Only valid
+    /// paths are cached, since invalid ones may become valid at any time.
+    pub(crate) async fn query_path_info(&self, path: &str) -> Result<Option<ValidPathInfo>> {
+        if let Some(info) = self.path_info_cache.as_ref().and_then(|c| c.get(path)) {
+            return Ok(Some(info));
+        }
+        let info = self.daemon.lock().await.query_path_info(path).await?.path;
+        if let (Some(cache), Some(info)) = (&self.path_info_cache, &info) {
+            cache.insert(path, info);
+        }
+        Ok(info)
+    }
+
     pub fn get_real_path(&self, virtual_path: &Path) -> PathBuf {
         if self.real_store.is_some() && virtual_path.starts_with(&self.virtual_store) {
             return self
@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::Config;
+use crate::daemon::{DaemonConnection, ValidPathInfo};
+use crate::signing::{convert_base16_to_nix32, fingerprint_path};
+
+/// A store path selected by [`SelectedPaths`].
+pub(crate) struct SelectedPath {
+    pub(crate) path: String,
+    pub(crate) info: ValidPathInfo,
+    /// What signatures of the path sign.
+    pub(crate) fingerprint: String,
+}
+
+/// The store paths the `resign`, `verify` and `check` commands work on: the
+/// given ones, or all valid paths if none are given, skipping the ones that
+/// are not allowed to be served.
+pub(crate) struct SelectedPaths {
+    paths: std::vec::IntoIter<String>,
+}
+
+impl SelectedPaths {
+    pub(crate) async fn new(daemon: &mut DaemonConnection, paths: &[String]) -> Result<Self> {
+        let paths = if paths.is_empty() {
+            daemon
+                .query_all_valid_paths()
+                .await
+                .context("Failed to query valid paths")?
+        } else {
+            paths.to_vec()
+        };
+        Ok(Self {
+            paths: paths.into_iter(),
+        })
+    }
+
+    /// Returns the next path with its path info, `None` once all are done.
+    pub(crate) async fn next(
+        &mut self,
+        settings: &Config,
+        daemon: &mut DaemonConnection,
+    ) -> Result<Option<SelectedPath>> {
+        for path in self.paths.by_ref() {
+            let hash = Path::new(&path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.get(0..32))
+                .with_context(|| format!("'{}' is not a store path", path))?;
+            if !settings.path_filter.is_allowed(hash) {
+                continue;
+            }
+            let info = match daemon.query_path_info(&path).await?.path {
+                Some(info) => info,
+                None => bail!("'{}' is not a valid store path", path),
+            };
+            let nar_hash = format!(
+                "sha256:{}",
+                convert_base16_to_nix32(&info.hash).context("failed to convert path info hash")?
+            );
+            let fingerprint = fingerprint_path(
+                settings.store.virtual_store(),
+                &path,
+                &nar_hash,
+                info.nar_size,
+                &info.references,
+            )?
+            .with_context(|| format!("Failed to compute fingerprint of {}", path))?;
+            return Ok(Some(SelectedPath {
+                path,
+                info,
+                fingerprint,
+            }));
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,99 @@
+//! Stores for tests that talk to a daemon.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::daemon::RetryPolicy;
+use crate::store::Store;
+
+/// Store whose daemon socket doesn't exist, failing requests right away.
+pub(crate) fn unreachable_store(dir: &Path) -> Store {
+    let retry = RetryPolicy {
+        max_retries: 0,
+        ..Default::default()
+    };
+    Store::new("/nix/store".into(), None, retry, None).with_daemon_socket(dir.join("socket"))
+}
+
+/// A nix-daemon serving a chroot store in a temporary directory.
+pub(crate) struct TempStore {
+    pub(crate) dir: tempfile::TempDir,
+    daemon: Child,
+}
+
+impl TempStore {
+    pub(crate) fn start() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let daemon = Command::new("nix-daemon")
+            .arg("--store")
+            .arg(format!("local?root={}", dir.path().join("root").display()))
+            // resigning and importing unsigned paths need a trusted client
+            .args(["--option", "trusted-users", "*"])
+            .env("NIX_DAEMON_SOCKET_PATH", dir.path().join("socket"))
+            .spawn()
+            .context("Failed to start nix-daemon")?;
+        let store = Self { dir, daemon };
+        for _ in 0..100 {
+            if store.socket().exists() {
+                return Ok(store);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        bail!("nix-daemon didn't create {}", store.socket().display())
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.path().join("socket")
+    }
+
+    /// Runs `nix-store` against the daemon, returning its output.
+    pub(crate) fn nix_store(&self, args: &[&OsStr]) -> Result<Vec<u8>> {
+        let output = Command::new("nix-store")
+            .arg("--store")
+            .arg(format!("unix://{}", self.socket().display()))
+            .args(args)
+            .output()
+            .context("Failed to run nix-store")?;
+        ensure!(output.status.success(), "nix-store {:?} failed", args);
+        Ok(output.stdout)
+    }
+
+    /// Adds a file named `name` with `contents` to the store, returning its
+    /// store path.
+    pub(crate) fn add_file(&self, name: &str, contents: &[u8]) -> Result<String> {
+        let file = self.dir.path().join(name);
+        std::fs::write(&file, contents)?;
+        let output = self.nix_store(&["--add".as_ref(), file.as_os_str()])?;
+        Ok(std::str::from_utf8(&output)?.trim().to_owned())
+    }
+
+    /// Where the store keeps `store_path` on disk.
+    pub(crate) fn real_path(&self, store_path: &str) -> PathBuf {
+        self.dir
+            .path()
+            .join("root")
+            .join(store_path.trim_start_matches('/'))
+    }
+
+    pub(crate) fn store(&self) -> Store {
+        let real_store = self.real_path("/nix/store");
+        Store::new(
+            "/nix/store".into(),
+            Some(real_store.to_string_lossy().into_owned()),
+            Default::default(),
+            None,
+        )
+        .with_daemon_socket(self.socket())
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use base64::{engine::general_purpose, Engine};
+use serde::Deserialize;
+use tempfile::TempPath;
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+
+use crate::config::Config;
+use crate::daemon::ValidPathInfo;
+use crate::narinfo::parse_narinfo_txt;
+use crate::routes::NIXBASE32_ALPHABET;
+use crate::signing::{
+    convert_base16_to_nix32, convert_nix32_to_base16, fingerprint_path, verify_signatures,
+};
+use crate::{cache_control_no_store, ServerResult};
+
+/// NARs that have been uploaded but whose narinfo has not been received yet,
+/// keyed by their URL relative to the cache root.
+///
+/// Uploads whose narinfo doesn't follow within the TTL are dropped, which
+/// removes their temporary file, whenever an upload is added or taken.
+#[derive(Debug)]
+pub(crate) struct PendingUploads {
+    uploads: Mutex<HashMap<String, (Instant, TempPath)>>,
+    ttl: Duration,
+}
+
+impl PendingUploads {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            uploads: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    fn expire(&self, uploads: &mut HashMap<String, (Instant, TempPath)>) {
+        uploads.retain(|url, (uploaded, _)| {
+            let keep = uploaded.elapsed() < self.ttl;
+            if !keep {
+                log::info!("dropping upload of {}, its narinfo never arrived", url);
+            }
+            keep
+        });
+    }
+
+    async fn insert(&self, url: String, nar: TempPath) {
+        let mut uploads = self.uploads.lock().await;
+        self.expire(&mut uploads);
+        uploads.insert(url, (Instant::now(), nar));
+    }
+
+    async fn remove(&self, url: &str) -> Option<TempPath> {
+        let mut uploads = self.uploads.lock().await;
+        self.expire(&mut uploads);
+        uploads.remove(url).map(|(_, nar)| nar)
+    }
+}
+
+impl Default for PendingUploads {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60 * 60))
+    }
+}
+
+/// Represents the parsed parts in a NAR upload URL.
+#[derive(Debug, Deserialize)]
+pub struct PathParams {
+    narhash: String,
+    ext: Option<String>,
+}
+
+/// Constant time comparison, so the token can't be guessed byte by byte.
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization` header against `token`.
+///
+/// Both `Bearer <token>` and basic auth with the token as password (as sent
+/// by nix when the credentials are stored in a netrc file) are accepted.
+fn is_authorized(req: &HttpRequest, token: &str) -> bool {
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            if let Some(bearer) = value.strip_prefix("Bearer ") {
+                token_eq(bearer.trim().as_bytes(), token.as_bytes())
+            } else if let Some(basic) = value.strip_prefix("Basic ") {
+                general_purpose::STANDARD
+                    .decode(basic.trim())
+                    .ok()
+                    .and_then(|credentials| {
+                        let pos = credentials.iter().position(|b| *b == b':')?;
+                        Some(token_eq(&credentials[pos + 1..], token.as_bytes()))
+                    })
+                    .unwrap_or(false)
+            } else {
+                false
+            }
+        })
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header(cache_control_no_store())
+        .insert_header((http::header::WWW_AUTHENTICATE, "Basic realm=\"harmonia\""))
+        .finish()
+}
+
+/// Checks the `Authorization` header against the configured admin token.
+pub(crate) fn check_admin_auth(req: &HttpRequest, settings: &Config) -> Option<HttpResponse> {
+    let Some(token) = &settings.admin_token else {
+        return Some(
+            HttpResponse::NotFound()
+                .insert_header(cache_control_no_store())
+                .body("admin endpoints are disabled"),
+        );
+    };
+    if is_authorized(req, token) {
+        None
+    } else {
+        Some(unauthorized())
+    }
+}
+
+/// Checks the `Authorization` header against the configured upload token.
+fn check_auth(req: &HttpRequest, settings: &Config) -> Option<HttpResponse> {
+    let token = match &settings.upload_token {
+        Some(token) => token,
+        None => {
+            return Some(
+                HttpResponse::MethodNotAllowed()
+                    .insert_header(cache_control_no_store())
+                    .body("uploads are disabled"),
+            )
+        }
+    };
+    if is_authorized(req, token) {
+        None
+    } else {
+        Some(unauthorized())
+    }
+}
+
+/// Rejects uploads compressed with bzip2, which NARs can't be served with.
+fn unsupported_compression(compression: &str) -> HttpResponse {
+    HttpResponse::UnsupportedMediaType()
+        .insert_header(cache_control_no_store())
+        .body(format!(
+            "unsupported compression: {}, use zstd, xz, gzip, br or none",
+            compression
+        ))
+}
+
+fn decompress(compression: &str, file: tokio::fs::File) -> Result<Box<dyn AsyncRead + Unpin>> {
+    let reader = BufReader::new(file);
+    Ok(match compression {
+        "none" => Box::new(reader),
+        "xz" => Box::new(XzDecoder::new(reader)),
+        "zstd" => Box::new(ZstdDecoder::new(reader)),
+        "gzip" => Box::new(GzipDecoder::new(reader)),
+        "br" => Box::new(BrotliDecoder::new(reader)),
+        _ => bail!("unsupported compression: {}", compression),
+    })
+}
+
+/// Converts a narinfo `NarHash` to the base16 representation used by the daemon.
+pub(crate) fn nar_hash_to_base16(nar_hash: &str) -> Result<String> {
+    let hash = nar_hash
+        .strip_prefix("sha256:")
+        .with_context(|| format!("unsupported NarHash: {}", nar_hash))?;
+    match hash.len() {
+        52 => convert_nix32_to_base16(hash),
+        64 => Ok(hash.to_ascii_lowercase()),
+        _ => bail!("invalid NarHash: {}", nar_hash),
+    }
+}
+
+fn is_store_path_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.chars().all(|c| NIXBASE32_ALPHABET.contains(c))
+}
+
+fn url_without_query(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+pub(crate) async fn put_nar(
+    path: web::Path<PathParams>,
+    req: HttpRequest,
+    mut payload: web::Payload,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    if let Some(res) = check_auth(&req, &settings) {
+        return Ok(res);
+    }
+    if path.ext.as_deref() == Some("bz2") {
+        return Ok(unsupported_compression("bzip2"));
+    }
+    let url = match &path.ext {
+        Some(ext) => format!("nar/{}.nar.{}", path.narhash, ext),
+        None => format!("nar/{}.nar", path.narhash),
+    };
+
+    let temp_path = tempfile::NamedTempFile::new()
+        .context("Failed to create temporary file for upload")?
+        .into_temp_path();
+    let mut file = tokio::fs::File::create(&temp_path)
+        .await
+        .with_context(|| format!("Failed to open {}", temp_path.display()))?;
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| anyhow::anyhow!("Failed to receive upload: {}", e))?;
+        size += chunk.len();
+        if let Some(max_payload_size) = settings.max_payload_size {
+            if size > max_payload_size {
+                return Ok(HttpResponse::PayloadTooLarge()
+                    .insert_header(cache_control_no_store())
+                    .body(format!(
+                        "upload exceeds the limit of {} bytes",
+                        max_payload_size
+                    )));
+            }
+        }
+        file.write_all(&chunk)
+            .await
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    }
+    file.flush().await.context("Failed to flush upload")?;
+
+    settings.uploads.insert(url, temp_path).await;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub(crate) async fn put_narinfo(
+    hash: web::Path<String>,
+    req: HttpRequest,
+    body: String,
+    settings: web::Data<Config>,
+) -> ServerResult {
+    if let Some(res) = check_auth(&req, &settings) {
+        return Ok(res);
+    }
+    let bad_request = |msg: String| {
+        Ok(HttpResponse::BadRequest()
+            .insert_header(cache_control_no_store())
+            .body(msg))
+    };
+    if !is_store_path_hash(&hash) {
+        return bad_request(format!("invalid store path hash {}", hash));
+    }
+
+    let narinfo = match parse_narinfo_txt(&body) {
+        Ok(narinfo) => narinfo,
+        Err(e) => return bad_request(format!("invalid narinfo: {:#}", e)),
+    };
+    if narinfo.compression == "bzip2" {
+        return Ok(unsupported_compression(&narinfo.compression));
+    }
+    let store = settings.store_for(&req);
+    let store_dir = store.virtual_store();
+    let store_path = Path::new(&narinfo.store_path);
+    if store_path.parent() != Some(Path::new(store_dir))
+        || !narinfo.store_path[store_dir.len() + 1..].starts_with(&format!("{}-", hash))
+    {
+        return bad_request(format!(
+            "store path {} does not match {}",
+            narinfo.store_path, hash
+        ));
+    }
+    let nar_hash = match nar_hash_to_base16(&narinfo.nar_hash) {
+        Ok(nar_hash) => nar_hash,
+        Err(e) => return bad_request(format!("{:#}", e)),
+    };
+
+    let references = narinfo
+        .references
+        .iter()
+        .map(|r| format!("{}/{}", store_dir, r))
+        .collect::<Vec<_>>();
+
+    if !settings.public_keys.is_empty() {
+        let fingerprint = fingerprint_path(
+            store_dir,
+            &narinfo.store_path,
+            &format!("sha256:{}", convert_base16_to_nix32(&nar_hash)?),
+            narinfo.nar_size,
+            &references,
+        );
+        let trusted = match fingerprint {
+            Ok(Some(fingerprint)) => {
+                verify_signatures(&settings.public_keys, &narinfo.sigs, &fingerprint)
+            }
+            _ => false,
+        };
+        if !trusted {
+            // drop the staged NAR, it won't be imported
+            settings
+                .uploads
+                .remove(url_without_query(&narinfo.url))
+                .await;
+            return Ok(HttpResponse::Forbidden()
+                .insert_header(cache_control_no_store())
+                .body(format!(
+                    "{} has no signature by a trusted key",
+                    narinfo.store_path
+                )));
+        }
+    }
+
+    let url = url_without_query(&narinfo.url);
+    let nar_file = match settings.uploads.remove(url).await {
+        Some(nar_file) => nar_file,
+        None => return bad_request(format!("{} has not been uploaded", url)),
+    };
+    let file = tokio::fs::File::open(&nar_file)
+        .await
+        .with_context(|| format!("Failed to open {}", nar_file.display()))?;
+    let nar = match decompress(&narinfo.compression, file) {
+        Ok(nar) => nar,
+        Err(e) => return bad_request(format!("{:#}", e)),
+    };
+
+    let info = ValidPathInfo {
+        deriver: narinfo
+            .deriver
+            .map(|d| format!("{}/{}", store_dir, d))
+            .unwrap_or_default(),
+        hash: nar_hash,
+        references,
+        registration_time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        nar_size: narinfo.nar_size,
+        ultimate: false,
+        sigs: narinfo.sigs,
+        content_address: narinfo.ca,
+    };
+
+    // Use a dedicated connection, so that other requests are not blocked
+    // while the NAR is imported.
+    let mut daemon = store.dedicated_daemon();
+    daemon
+        .add_to_store_nar(&narinfo.store_path, &info, nar, false, false)
+        .await
+        .with_context(|| format!("Failed to import {}", narinfo.store_path))?;
+    log::info!("imported {}", narinfo.store_path);
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nar_hash_to_base16() -> Result<()> {
+        let base16 = "d3e5a3f2a1e4c4b8b5c0a0e6b0c9f0e1d2c3b4a5968778695a4b3c2d1e0f1a2b";
+        let nix32 = crate::signing::convert_base16_to_nix32(base16)?;
+        assert_eq!(nar_hash_to_base16(&format!("sha256:{}", nix32))?, base16);
+        assert_eq!(nar_hash_to_base16(&format!("sha256:{}", base16))?, base16);
+        assert!(nar_hash_to_base16(&nix32).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pending_uploads() -> Result<()> {
+        let uploads = PendingUploads::new(Duration::from_secs(60));
+        let nar = tempfile::NamedTempFile::new()?.into_temp_path();
+        uploads.insert("nar/a.nar".into(), nar).await;
+        assert!(uploads.remove("nar/a.nar").await.is_some());
+        assert!(uploads.remove("nar/a.nar").await.is_none());
+
+        // expired uploads are dropped along with their file
+        let uploads = PendingUploads::new(Duration::ZERO);
+        let nar = tempfile::NamedTempFile::new()?.into_temp_path();
+        let nar_path = nar.to_path_buf();
+        uploads.insert("nar/a.nar".into(), nar).await;
+        assert!(uploads.remove("nar/a.nar").await.is_none());
+        assert!(!nar_path.exists());
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_reject_bzip2() -> Result<()> {
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        let app = init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(Config {
+                    upload_token: Some("secret".into()),
+                    ..Default::default()
+                }))
+                .configure(|cfg| crate::routes::configure(cfg, "")),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar.bz2")
+            .insert_header((http::header::AUTHORIZATION, "Bearer secret"))
+            .set_payload("BZh9")
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        let req = TestRequest::put()
+            .uri("/26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo")
+            .insert_header((http::header::AUTHORIZATION, "Bearer secret"))
+            .set_payload(
+                "StorePath: /nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1\n\
+                 URL: nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar.bz2\n\
+                 Compression: bzip2\n\
+                 NarHash: sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh\n\
+                 NarSize: 4\n",
+            )
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_narinfo_hash_mismatch() -> Result<()> {
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        let app = init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(Config {
+                    upload_token: Some("secret".into()),
+                    ..Default::default()
+                }))
+                .configure(|cfg| crate::routes::configure(cfg, "")),
+        )
+        .await;
+        let narinfo = "StorePath: /nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1\n\
+                       URL: nar/1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh.nar\n\
+                       Compression: none\n\
+                       NarHash: sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh\n\
+                       NarSize: 4\n";
+        // a prefix of the hash, a different hash and a hash that isn't nixbase32
+        for hash in [
+            "2",
+            "26xbg1ndr7hbcncrlf9nhx5is2b25d1",
+            "36xbg1ndr7hbcncrlf9nhx5is2b25d13",
+            "26xbg1ndr7hbcncrlf9nhx5is2b25d1e",
+        ] {
+            let req = TestRequest::put()
+                .uri(&format!("/{}.narinfo", hash))
+                .insert_header((http::header::AUTHORIZATION, "Bearer secret"))
+                .set_payload(narinfo)
+                .to_request();
+            assert_eq!(
+                call_service(&app, req).await.status(),
+                http::StatusCode::BAD_REQUEST,
+                "{}",
+                hash
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_eq() {
+        assert!(token_eq(b"secret", b"secret"));
+        assert!(!token_eq(b"secret", b"secreT"));
+        assert!(!token_eq(b"secret", b"secret2"));
+    }
+}
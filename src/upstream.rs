@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use actix_web::body::SizedStream;
+use actix_web::web::Bytes;
+use actix_web::{http, HttpRequest, HttpResponse, HttpResponseBuilder};
+use anyhow::{anyhow, bail, Context, Result};
+use url::Url;
+
+use crate::cache_control_max_age;
+
+thread_local! {
+    // awc clients can't be shared between threads, so every worker gets its own
+    static CLIENT: awc::Client = awc::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .finish();
+}
+
+/// Another binary cache that requests for paths missing in the local store are
+/// proxied to, e.g. `https://cache.nixos.org`.
+#[derive(Debug)]
+pub(crate) struct Upstream {
+    url: Url,
+}
+
+impl Upstream {
+    pub(crate) fn new(url: &str) -> Result<Self> {
+        let mut url = Url::parse(url).with_context(|| format!("Invalid upstream URL '{}'", url))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Upstream URL '{}' is neither http nor https", url);
+        }
+        // so that joining keeps the path of the base URL
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(Self { url })
+    }
+
+    fn join(&self, path: &str) -> Result<Url> {
+        self.url
+            .join(path.trim_start_matches('/'))
+            .with_context(|| format!("Invalid upstream path '{}'", path))
+    }
+
+    /// Fetches the narinfo of `hash`, if upstream has it.
+    pub(crate) async fn narinfo(&self, hash: &str) -> Result<Option<Bytes>> {
+        let url = self.join(&format!("{}.narinfo", hash))?;
+        let mut res = CLIENT
+            .with(|client| client.get(url.as_str()))
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to query upstream {}: {}", url, e))?;
+        match res.status() {
+            http::StatusCode::OK => {}
+            http::StatusCode::NOT_FOUND | http::StatusCode::FORBIDDEN => return Ok(None),
+            status => bail!("Upstream {} returned {}", url, status),
+        }
+        let body = res
+            .body()
+            .limit(1024 * 1024)
+            .await
+            .map_err(|e| anyhow!("Failed to read narinfo from upstream {}: {}", url, e))?;
+        Ok(Some(body))
+    }
+
+    /// Requests the file at the same path as `req` from upstream.
+    pub(crate) async fn send(&self, req: &HttpRequest) -> Result<awc::ClientResponse> {
+        let url = self.join(req.path())?;
+        let mut upstream_req =
+            CLIENT.with(|client| client.request(req.method().clone(), url.as_str()));
+        if let Some(range) = req.headers().get(http::header::RANGE) {
+            upstream_req = upstream_req.insert_header((http::header::RANGE, range.clone()));
+        }
+        upstream_req
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to query upstream {}: {}", url, e))
+    }
+
+    /// Streams the file at the same path from upstream, e.g. a NAR that is
+    /// referenced by a proxied narinfo.
+    pub(crate) async fn proxy(&self, req: &HttpRequest, max_age: u32) -> Result<HttpResponse> {
+        let upstream_res = self.send(req).await?;
+        let mut res = response_builder(&upstream_res, max_age);
+        // stream the body instead of buffering whole NARs
+        Ok(match content_length(&upstream_res) {
+            Some(len) => res.body(SizedStream::new(len, upstream_res)),
+            None => res.streaming(upstream_res),
+        })
+    }
+}
+
+/// Starts a response with the status and relevant headers of `upstream_res`.
+pub(crate) fn response_builder(
+    upstream_res: &awc::ClientResponse,
+    max_age: u32,
+) -> HttpResponseBuilder {
+    let status = upstream_res.status();
+    let mut res = HttpResponse::build(status);
+    for name in [
+        http::header::CONTENT_TYPE,
+        http::header::CONTENT_RANGE,
+        http::header::ACCEPT_RANGES,
+    ] {
+        if let Some(value) = upstream_res.headers().get(&name) {
+            res.insert_header((name, value.clone()));
+        }
+    }
+    // passed through as-is, keep the compression middleware away
+    res.insert_header((
+        http::header::CONTENT_ENCODING,
+        http::header::HeaderValue::from_static("identity"),
+    ));
+    if status.is_success() {
+        res.insert_header(cache_control_max_age(max_age));
+    } else {
+        res.insert_header(crate::cache_control_no_store());
+    }
+    res
+}
+
+pub(crate) fn content_length(upstream_res: &awc::ClientResponse) -> Option<u64> {
+    upstream_res
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_join() -> Result<()> {
+        let upstream = Upstream::new("https://cache.example.com/prefix")?;
+        assert_eq!(
+            upstream.join("/nar/foo.nar.xz")?.as_str(),
+            "https://cache.example.com/prefix/nar/foo.nar.xz"
+        );
+        let upstream = Upstream::new("https://cache.nixos.org")?;
+        assert_eq!(
+            upstream
+                .join("26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo")?
+                .as_str(),
+            "https://cache.nixos.org/26xbg1ndr7hbcncrlf9nhx5is2b25d13.narinfo"
+        );
+        assert!(Upstream::new("file:///tmp/cache").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_files::NamedFile;
+use actix_web::body::SizedStream;
+use actix_web::web::Bytes;
+use actix_web::{http, HttpRequest, HttpResponse, Responder};
+use anyhow::{bail, Context, Result};
+use openssl::sha::Sha256;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::StreamExt;
+
+use crate::cache_control_max_age;
+use crate::config::PublicKey;
+use crate::nar::nar_hash_matches;
+use crate::narinfo::parse_narinfo_txt;
+use crate::signing::{fingerprint_path, verify_signatures};
+use crate::upload::nar_hash_to_base16;
+use crate::upstream::{content_length, response_builder, Upstream};
+
+/// Prefix of files that are still being downloaded.
+const TMP_PREFIX: &str = ".tmp";
+
+/// Keeps narinfos and NARs proxied from upstream on disk, so that they are
+/// only fetched once. Entries are keyed by the hash of their store path and
+/// the least recently used NARs are evicted once `max_size` is exceeded.
+#[derive(Debug, Clone)]
+pub(crate) struct UpstreamCache {
+    dir: PathBuf,
+    max_size: u64,
+    evicting: Arc<Mutex<()>>,
+}
+
+impl UpstreamCache {
+    pub(crate) fn new(dir: &Path, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache_dir '{}'", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            max_size,
+            evicting: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Removes the temporary files left behind by interrupted downloads.
+    ///
+    /// Only safe to call before the cache is in use, which is why this isn't
+    /// part of [`Self::new`]: the configuration is loaded again on reload.
+    pub(crate) fn remove_stale_tmp(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read cache_dir '{}'", self.dir.display()))?
+        {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                std::fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to remove '{}'", entry.path().display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn narinfo_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.narinfo", hash))
+    }
+
+    fn nar_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.nar", hash))
+    }
+
+    /// Returns the cached narinfo of `hash`.
+    pub(crate) async fn narinfo(&self, hash: &str) -> Option<String> {
+        tokio::fs::read_to_string(self.narinfo_path(hash))
+            .await
+            .ok()
+    }
+
+    /// Caches the narinfo of `hash` if it is signed by one of `keys`. Returns the
+    /// narinfo to serve, which references the NAR with the store hash, so that
+    /// NAR requests can be matched with the cached narinfo.
+    pub(crate) async fn insert_narinfo(
+        &self,
+        hash: &str,
+        narinfo: &str,
+        store_dir: &str,
+        keys: &[PublicKey],
+    ) -> Result<Option<String>> {
+        if !is_trusted(narinfo, hash, store_dir, keys) {
+            return Ok(None);
+        }
+        let narinfo = with_hash_in_url(narinfo, hash);
+        let tmp = tempfile::Builder::new()
+            .prefix(TMP_PREFIX)
+            .tempfile_in(&self.dir)
+            .context("Failed to create temporary file")?;
+        tokio::fs::write(tmp.path(), &narinfo)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp.path().display()))?;
+        tmp.persist(self.narinfo_path(hash))
+            .context("Failed to cache narinfo")?;
+        Ok(Some(narinfo))
+    }
+
+    /// Serves the NAR of `hash` from disk, fetching it from upstream first if needed.
+    pub(crate) async fn nar(
+        &self,
+        upstream: &Upstream,
+        hash: &str,
+        req: &HttpRequest,
+        max_age: u32,
+    ) -> Result<HttpResponse> {
+        let nar_path = self.nar_path(hash);
+        if let Ok(file) = std::fs::File::options().write(true).open(&nar_path) {
+            // the modification time tracks the last use for eviction
+            file.set_modified(SystemTime::now())
+                .with_context(|| format!("Failed to touch {}", nar_path.display()))?;
+            let nar = NamedFile::open_async(&nar_path)
+                .await
+                .with_context(|| format!("Failed to open {}", nar_path.display()))?
+                .disable_content_disposition()
+                .set_content_type("application/x-nix-archive".parse()?)
+                .customize()
+                .insert_header(cache_control_max_age(max_age))
+                .insert_header((
+                    http::header::CONTENT_ENCODING,
+                    http::header::HeaderValue::from_static("identity"),
+                ));
+            return Ok(nar.respond_to(req).map_into_boxed_body());
+        }
+
+        // only NARs of verified narinfos are cached, and only in full
+        let file_hash = self
+            .narinfo(hash)
+            .await
+            .and_then(|narinfo| narinfo_field(&narinfo, "FileHash").map(nar_hash_to_base16));
+        let file_hash = match file_hash {
+            Some(Ok(file_hash))
+                if req.method() == http::Method::GET
+                    && !req.headers().contains_key(http::header::RANGE) =>
+            {
+                file_hash
+            }
+            _ => return upstream.proxy(req, max_age).await,
+        };
+
+        let upstream_res = upstream.send(req).await?;
+        let mut res = response_builder(&upstream_res, max_age);
+        if upstream_res.status() != http::StatusCode::OK {
+            return Ok(res.streaming(upstream_res));
+        }
+        let len = content_length(&upstream_res);
+        let (tx, rx) = mpsc::channel(16);
+        let cache = self.clone();
+        let hash = hash.to_owned();
+        // the upstream response can't be sent to other threads
+        actix_web::rt::spawn(async move {
+            if let Err(e) = cache.download(&hash, upstream_res, &file_hash, tx).await {
+                log::error!("Failed to cache NAR of {}: {:#}", hash, e);
+            }
+        });
+        let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+        Ok(match len {
+            Some(len) => res.body(SizedStream::new(len, rx)),
+            None => res.streaming(rx),
+        })
+    }
+
+    /// Forwards the NAR to `tx` while writing it to the cache.
+    ///
+    /// Like with `verify_nar_hash`, the last chunk is held back until the
+    /// FileHash has been verified, so that clients don't get a corrupt NAR.
+    async fn download(
+        &self,
+        hash: &str,
+        mut upstream_res: awc::ClientResponse,
+        file_hash: &str,
+        tx: mpsc::Sender<Result<Bytes, std::io::Error>>,
+    ) -> Result<()> {
+        let tmp = tempfile::Builder::new()
+            .prefix(TMP_PREFIX)
+            .tempfile_in(&self.dir)
+            .context("Failed to create temporary file")?;
+        let mut file = tokio::fs::File::from_std(tmp.as_file().try_clone()?);
+        let mut hasher = Sha256::new();
+        let mut held_back: Option<Bytes> = None;
+        // keep downloading after the client went away, the NAR will be cached nonetheless
+        let mut client_connected = true;
+
+        while let Some(chunk) = upstream_res.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+                    bail!("Failed to download NAR: {}", e);
+                }
+            };
+            hasher.update(&chunk);
+            file.write_all(&chunk)
+                .await
+                .with_context(|| format!("Failed to write {}", tmp.path().display()))?;
+            if let Some(previous) = held_back.replace(chunk) {
+                client_connected = client_connected && tx.send(Ok(previous)).await.is_ok();
+            }
+        }
+        file.flush().await?;
+
+        if !nar_hash_matches(&hasher.finish(), file_hash) {
+            // dropping tx ends the response without the last chunk
+            bail!("NAR does not match its FileHash");
+        }
+        if let Some(last) = held_back {
+            if client_connected {
+                let _ = tx.send(Ok(last)).await;
+            }
+        }
+        drop(tx);
+
+        tmp.persist(self.nar_path(hash))
+            .context("Failed to cache NAR")?;
+        self.evict().await
+    }
+
+    /// Removes all cached narinfos and NARs, e.g. after upstream was changed.
+    /// Downloads that are still running are cached when they finish.
+    pub(crate) async fn flush(&self) -> Result<Flushed> {
+        let _guard = self.evicting.lock().await;
+        let mut flushed = Flushed::default();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let counter = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("narinfo") => &mut flushed.narinfos,
+                Some("nar") => &mut flushed.nars,
+                _ => continue,
+            };
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("Failed to remove '{}'", path.display()))?;
+            *counter += 1;
+        }
+        Ok(flushed)
+    }
+
+    /// Removes the least recently used NARs and their narinfos until the
+    /// cache fits into `max_size`.
+    async fn evict(&self) -> Result<()> {
+        let _guard = self.evicting.lock().await;
+        let mut nars = vec![];
+        let mut total = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "nar") {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            total += metadata.len();
+            nars.push((metadata.modified()?, metadata.len(), path));
+        }
+        nars.sort();
+
+        for (_, len, path) in nars {
+            if total <= self.max_size {
+                break;
+            }
+            log::info!("evicting {} from the upstream cache", path.display());
+            tokio::fs::remove_file(&path).await?;
+            let _ = tokio::fs::remove_file(path.with_extension("narinfo")).await;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Number of entries removed by [`UpstreamCache::flush`].
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Flushed {
+    pub(crate) narinfos: usize,
+    pub(crate) nars: usize,
+}
+
+/// Returns the value of the first `key` field of a narinfo.
+fn narinfo_field<'a>(narinfo: &'a str, key: &str) -> Option<&'a str> {
+    narinfo.lines().find_map(|line| {
+        line.split_once(": ")
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    })
+}
+
+/// Checks that a narinfo from upstream belongs to `hash` and is signed by one of `keys`.
+fn is_trusted(narinfo: &str, hash: &str, store_dir: &str, keys: &[PublicKey]) -> bool {
+    let Ok(narinfo) = parse_narinfo_txt(narinfo) else {
+        return false;
+    };
+    let name = narinfo
+        .store_path
+        .strip_prefix(store_dir)
+        .and_then(|p| p.strip_prefix('/'));
+    if !name.is_some_and(|name| name.starts_with(&format!("{}-", hash))) {
+        return false;
+    }
+    let references = narinfo
+        .references
+        .iter()
+        .map(|r| format!("{}/{}", store_dir, r))
+        .collect::<Vec<_>>();
+    match fingerprint_path(
+        store_dir,
+        &narinfo.store_path,
+        &narinfo.nar_hash,
+        narinfo.nar_size,
+        &references,
+    ) {
+        Ok(Some(fingerprint)) => verify_signatures(keys, &narinfo.sigs, &fingerprint),
+        _ => false,
+    }
+}
+
+/// Appends the store hash to the NAR URL of a narinfo, as in our own narinfos.
+fn with_hash_in_url(narinfo: &str, hash: &str) -> String {
+    narinfo
+        .lines()
+        .map(|line| match line.strip_prefix("URL: ") {
+            Some(url) if !url.contains('?') => format!("URL: {}?hash={}\n", url, hash),
+            _ => format!("{}\n", line),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::{parse_public_key, parse_secret_key, sign_string};
+
+    const NARINFO: &str = "StorePath: /nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1
+URL: nar/0ib8lnp6pijbx7pyfvbsjvikkbzqbh8k0ga2jb0kdn0y9ljk0ssl.nar.xz
+Compression: xz
+FileHash: sha256:0ib8lnp6pijbx7pyfvbsjvikkbzqbh8k0ga2jb0kdn0y9ljk0ssl
+FileSize: 50088
+NarHash: sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh
+NarSize: 226560
+References: 26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1
+";
+
+    #[test]
+    fn test_narinfo_fields() {
+        assert_eq!(
+            narinfo_field(NARINFO, "FileHash"),
+            Some("sha256:0ib8lnp6pijbx7pyfvbsjvikkbzqbh8k0ga2jb0kdn0y9ljk0ssl")
+        );
+        assert_eq!(narinfo_field(NARINFO, "Sig"), None);
+        let narinfo = with_hash_in_url(NARINFO, "26xbg1ndr7hbcncrlf9nhx5is2b25d13");
+        assert_eq!(
+            narinfo_field(&narinfo, "URL"),
+            Some("nar/0ib8lnp6pijbx7pyfvbsjvikkbzqbh8k0ga2jb0kdn0y9ljk0ssl.nar.xz?hash=26xbg1ndr7hbcncrlf9nhx5is2b25d13")
+        );
+    }
+
+    #[test]
+    fn test_is_trusted() -> Result<()> {
+        let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests");
+        let secret_key = parse_secret_key(&tests.join("cache.sk"))?;
+        let keys = [parse_public_key(
+            std::fs::read_to_string(tests.join("cache.pk"))?.trim(),
+        )?];
+        let hash = "26xbg1ndr7hbcncrlf9nhx5is2b25d13";
+        let fingerprint = "1;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1;sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh;226560;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1";
+        let signed = format!(
+            "{}Sig: {}\n",
+            NARINFO,
+            sign_string(&secret_key, fingerprint)
+        );
+
+        assert!(is_trusted(&signed, hash, "/nix/store", &keys));
+        assert!(!is_trusted(NARINFO, hash, "/nix/store", &keys));
+        assert!(!is_trusted(
+            &signed.replace("NarSize: 226560", "NarSize: 1"),
+            hash,
+            "/nix/store",
+            &keys
+        ));
+        // served for another hash
+        assert!(!is_trusted(
+            &signed,
+            "sl141d1g77wvhr050ah87lcyz2czdxa3",
+            "/nix/store",
+            &keys
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let cache = UpstreamCache::new(temp_dir.path(), 10)?;
+        let now = SystemTime::now();
+        for (i, hash) in ["a", "b", "c"].iter().enumerate() {
+            let nar = std::fs::File::create(cache.nar_path(hash))?;
+            nar.set_len(4)?;
+            nar.set_modified(now - std::time::Duration::from_secs(10 - i as u64))?;
+            std::fs::write(cache.narinfo_path(hash), "")?;
+        }
+        cache.evict().await?;
+        assert!(!cache.nar_path("a").exists());
+        assert!(!cache.narinfo_path("a").exists());
+        assert!(cache.nar_path("b").exists());
+        assert!(cache.nar_path("c").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_stale_tmp() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let tmp = temp_dir.path().join(format!("{}download", TMP_PREFIX));
+        std::fs::write(&tmp, "")?;
+        std::fs::write(temp_dir.path().join("a.narinfo"), "")?;
+        // reloading the configuration creates the cache again
+        let cache = UpstreamCache::new(temp_dir.path(), 10)?;
+        assert!(tmp.exists());
+        cache.remove_stale_tmp()?;
+        assert!(!tmp.exists());
+        assert!(cache.narinfo_path("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush() -> Result<()> {
+        let temp_dir = tempfile::tempdir().context("Failed to create temp dir")?;
+        let cache = UpstreamCache::new(temp_dir.path(), 10)?;
+        for hash in ["a", "b"] {
+            std::fs::write(cache.nar_path(hash), "")?;
+            std::fs::write(cache.narinfo_path(hash), "")?;
+        }
+        std::fs::write(cache.narinfo_path("c"), "")?;
+        let flushed = cache.flush().await?;
+        assert_eq!((flushed.narinfos, flushed.nars), (3, 2));
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+        Ok(())
+    }
+}
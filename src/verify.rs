@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+
+use crate::config::{Config, PublicKey};
+use crate::signing::{public_key, verify_signatures};
+use crate::store_paths::{SelectedPath, SelectedPaths};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Valid,
+    /// No signature by any of the keys.
+    Unsigned,
+    /// Signatures by the keys exist, but none of them is valid.
+    Bad,
+}
+
+fn check_signatures(keys: &[PublicKey], sigs: &[String], fingerprint: &str) -> Status {
+    let known = sigs
+        .iter()
+        .filter(|sig| {
+            sig.split_once(':')
+                .is_some_and(|(name, _)| keys.iter().any(|k| k.name == name))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if known.is_empty() {
+        Status::Unsigned
+    } else if verify_signatures(keys, &known, fingerprint) {
+        Status::Valid
+    } else {
+        Status::Bad
+    }
+}
+
+/// Reports store paths lacking a valid signature by the configured signing
+/// keys or trusted public keys, to audit the signing coverage of a cache.
+///
+/// Checks all valid paths that are allowed to be served if `paths` is empty.
+pub(crate) async fn run(settings: &Config, paths: &[String]) -> Result<()> {
+    let keys = settings
+        .secret_keys
+        .load()
+        .iter()
+        .map(public_key)
+        .chain(settings.public_keys.iter().cloned())
+        .collect::<Vec<_>>();
+    if keys.is_empty() {
+        bail!("Neither signing keys nor trusted public keys configured, nothing to verify against");
+    }
+    let mut daemon = settings.store.daemon.lock().await;
+    let mut paths = SelectedPaths::new(&mut daemon, paths).await?;
+
+    let mut unsigned = 0;
+    let mut bad = 0;
+    let mut checked = 0;
+    while let Some(SelectedPath {
+        path,
+        info,
+        fingerprint,
+    }) = paths.next(settings, &mut daemon).await?
+    {
+        checked += 1;
+        match check_signatures(&keys, &info.sigs, &fingerprint) {
+            Status::Valid => {}
+            Status::Unsigned => {
+                println!("unsigned: {}", path);
+                unsigned += 1;
+            }
+            Status::Bad => {
+                println!("bad signature: {}", path);
+                bad += 1;
+            }
+        }
+    }
+    if unsigned > 0 || bad > 0 {
+        bail!(
+            "{} unsigned and {} badly signed of {} store paths",
+            unsigned,
+            bad,
+            checked
+        );
+    }
+    log::info!("all {} store paths are signed", checked);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signing::parse_secret_key;
+    use crate::signing::{parse_public_key, sign_string};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_check_signatures() -> Result<()> {
+        let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests");
+        let secret_key = parse_secret_key(&tests.join("cache.sk"))?;
+        let keys = [parse_public_key(
+            std::fs::read_to_string(tests.join("cache.pk"))?.trim(),
+        )?];
+        let fingerprint = "1;/nix/store/26xbg1ndr7hbcncrlf9nhx5is2b25d13-hello-2.12.1;sha256:1mkvday29m2qxg1fnbv8xh9s6151bh8a2xzhh0k86j7lqhyfwibh;226560;";
+        let sig = sign_string(&secret_key, fingerprint);
+        let other = "other-cache-1:6wzr1QlOPHG+knFuJIaw+85Z5ivwbdI512JikexG+nQ7JDSZM2hw8zzlcLrguzoLEpCA9VzaEEQflZEHVwy9AA==".to_owned();
+
+        assert_eq!(
+            check_signatures(&keys, &[other.clone(), sig.clone()], fingerprint),
+            Status::Valid
+        );
+        assert_eq!(
+            check_signatures(&keys, std::slice::from_ref(&other), fingerprint),
+            Status::Unsigned
+        );
+        assert_eq!(check_signatures(&keys, &[], fingerprint), Status::Unsigned);
+        assert_eq!(
+            check_signatures(&keys, &[sig], &fingerprint.replace("226560", "1")),
+            Status::Bad
+        );
+        Ok(())
+    }
+}
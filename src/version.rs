@@ -1,11 +1,91 @@
 use std::error::Error;
+use std::time::Instant;
 
-use actix_web::HttpResponse;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
 
-pub(crate) async fn get() -> Result<HttpResponse, Box<dyn Error>> {
-    Ok(HttpResponse::Ok().body(format!(
-        "{} {}",
-        env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION")
-    )))
+use crate::config::Config;
+use crate::daemon::DaemonInfo;
+
+/// Optional functionality built into this binary.
+const FEATURES: &[&str] = &["tls", "zstd", "xz", "gzip", "brotli"];
+
+/// When harmonia was started, for the uptime.
+#[derive(Debug)]
+pub(crate) struct StartTime(Instant);
+
+impl Default for StartTime {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Version {
+    name: &'static str,
+    version: &'static str,
+    /// Commit harmonia was built from, if known at build time.
+    git_revision: Option<&'static str>,
+    features: &'static [&'static str],
+    uptime_seconds: u64,
+    /// `null` if the daemon could not be reached.
+    daemon: Option<DaemonInfo>,
+}
+
+pub(crate) async fn get(settings: web::Data<Config>) -> Result<HttpResponse, Box<dyn Error>> {
+    let daemon = match settings.store.daemon.lock().await.info().await {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log::warn!("Failed to query daemon version: {:#}", e);
+            None
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(crate::cache_control_no_store())
+        .json(Version {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_revision: option_env!("HARMONIA_GIT_REV"),
+            features: FEATURES,
+            uptime_seconds: settings.started.0.elapsed().as_secs(),
+            daemon,
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_version_json() -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_value(Version {
+            name: "harmonia",
+            version: "1.0.0",
+            git_revision: Some("4fce081"),
+            features: FEATURES,
+            uptime_seconds: 42,
+            daemon: Some(DaemonInfo {
+                protocol_version: "1.38".into(),
+                version: "2.24.9".into(),
+                trusted: true,
+            }),
+        })?;
+        assert_eq!(json["daemon"]["protocol_version"], "1.38");
+        assert_eq!(json["daemon"]["version"], "2.24.9");
+        assert_eq!(json["daemon"]["trusted"], true);
+        assert_eq!(json["git_revision"], "4fce081");
+        assert_eq!(json["uptime_seconds"], 42);
+        assert_eq!(json["features"][0], "tls");
+
+        let json = serde_json::to_value(Version {
+            name: "harmonia",
+            version: "1.0.0",
+            git_revision: None,
+            features: FEATURES,
+            uptime_seconds: 0,
+            daemon: None,
+        })?;
+        assert!(json["daemon"].is_null());
+        Ok(())
+    }
 }